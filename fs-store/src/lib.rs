@@ -0,0 +1,527 @@
+//! A [`SessionStore`] that keeps every session in its own file.
+//!
+//! Sessions can be shared by several servers through a network filesystem such as NFS, by
+//! creating the stores of every server on the same directory. The directory should only be used
+//! by the store.
+//!
+//! A session file is named after the id of the session in hex, and holds the record serialized
+//! as JSON with [`serde`], along with the expiry of the session. The expiry date is kept in the
+//! file rather than derived from its modification time, as the clocks of the clients of a
+//! network filesystem may disagree with the clock of the server. Files are written next to
+//! their final path and then renamed, so that a reader never sees a partially written session.
+//!
+//! Expired sessions are never returned, and are removed by [`FsStore::delete_expired`], or
+//! periodically by a [`CleanupTask`].
+//!
+//! # Examples
+//!
+//! ```rust
+//! use serde::{Deserialize, Serialize};
+//! use tower_sesh_core::Expires;
+//! use tower_sesh_fs_store::FsStore;
+//!
+//! #[derive(Clone, Serialize, Deserialize)]
+//! struct User {
+//!     name: String,
+//! }
+//!
+//! impl Expires for User {}
+//!
+//! # fn main() -> Result<(), tower_sesh_fs_store::Error> {
+//! # let temporary = tempfile::tempdir()?;
+//! # let directory = temporary.path();
+//! // Spread the session files over 256 directories.
+//! let store: FsStore<User> = FsStore::new(directory)?.with_shard_levels(1);
+//! # Ok(())
+//! # }
+//! ```
+use std::{
+    error::Error as StdError,
+    fmt::{self, Debug, Display},
+    fs::{self, File},
+    io::{self, Write},
+    marker::PhantomData,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use serde::{
+    de::{DeserializeOwned, IgnoredAny},
+    Deserialize, Serialize,
+};
+use serde_json::value::RawValue;
+use time::OffsetDateTime;
+use tower_sesh_core::{
+    clock::{Clock, SystemClock},
+    session_store::ExpiryDate,
+    task::{blocking, Cancelled},
+    Expires, Expiry, Id, SessionStore,
+};
+
+pub use tower_sesh_core::task::CleanupTask;
+
+/// The largest number of directory levels, one per byte of the ids.
+const MAX_SHARD_LEVELS: usize = 16;
+
+/// The age at which a temporary file is considered left behind by an interrupted write.
+const STALE_TEMPORARY_FILE_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// A session store keeping every session in its own file.
+///
+/// The files are accessed on tokio's blocking threads, so the store must be used within a
+/// tokio runtime.
+///
+/// Operations on the same session from several servers are not serialized: when a session is
+/// saved on one server while it is deleted on another, the last operation wins.
+#[derive(Debug)]
+pub struct FsStore<R> {
+    layout: Arc<Layout>,
+    clock: Arc<dyn Clock>,
+    _record: PhantomData<fn() -> R>,
+}
+
+impl<R> FsStore<R> {
+    /// Create a new `FsStore` keeping the sessions in `directory`, which is created if it does
+    /// not exist.
+    pub fn new(directory: impl Into<PathBuf>) -> Result<Self, Error> {
+        let directory = directory.into();
+        fs::create_dir_all(&directory)?;
+        Ok(FsStore {
+            layout: Arc::new(Layout {
+                directory,
+                shard_levels: 0,
+            }),
+            clock: Arc::new(SystemClock),
+            _record: PhantomData,
+        })
+    }
+
+    /// Spread the session files over `levels` levels of directories, each named after the next
+    /// byte of the ids in hex.
+    ///
+    /// Every level divides the number of files per directory by 256, which keeps lookups fast
+    /// when there are many sessions. By default, every file is in the store's directory.
+    ///
+    /// Every store sharing a directory must use the same number of levels, and changing it
+    /// hides the existing sessions, although [`delete_expired`](FsStore::delete_expired) still
+    /// removes them once they expire.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `levels` is greater than 16, the number of bytes of an id.
+    pub fn with_shard_levels(mut self, levels: usize) -> Self {
+        assert!(
+            levels <= MAX_SHARD_LEVELS,
+            "there can be at most {MAX_SHARD_LEVELS} shard levels"
+        );
+        Arc::make_mut(&mut self.layout).shard_levels = levels;
+        self
+    }
+
+    /// Use `clock` to compute the expiry of the sessions.
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Remove every expired session file, returning how many were removed.
+    ///
+    /// Files that cannot be read as sessions are logged and left in place. The temporary files
+    /// left behind by writes that were interrupted, e.g. by a crash, are also removed once they
+    /// are an hour old.
+    pub async fn delete_expired(&self) -> Result<usize, Error> {
+        let layout = self.layout.clone();
+        let now = self.clock.now();
+        blocking(move || delete_expired(&layout.directory, now)).await
+    }
+
+    /// Spawn a [`CleanupTask`] on the current tokio runtime that removes the expired sessions
+    /// with [`delete_expired`](FsStore::delete_expired) every `interval`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside of a tokio runtime, or if `interval` is zero.
+    pub fn spawn_cleanup(&self, interval: Duration) -> CleanupTask {
+        let clock = self.clock.clone();
+        CleanupTask::spawn(&self.layout, interval, move |layout| {
+            let now = clock.now();
+            blocking(move || delete_expired(&layout.directory, now))
+        })
+    }
+}
+
+impl<R> Clone for FsStore<R> {
+    fn clone(&self) -> Self {
+        FsStore {
+            layout: self.layout.clone(),
+            clock: self.clock.clone(),
+            _record: PhantomData,
+        }
+    }
+}
+
+/// An error of an [`FsStore`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// A session file could not be read or written.
+    Io(io::Error),
+    /// A session file could not be serialized or deserialized.
+    Serde(serde_json::Error),
+    /// The operation was cancelled before it ran, because the runtime is shutting down.
+    Cancelled,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(_) => f.write_str("failed to access a session file"),
+            Error::Serde(_) => f.write_str("failed to serialize or deserialize a session file"),
+            Error::Cancelled => f.write_str("the session operation was cancelled"),
+        }
+    }
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            Error::Serde(err) => Some(err),
+            Error::Cancelled => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        Error::Serde(err)
+    }
+}
+
+impl From<Cancelled> for Error {
+    fn from(Cancelled: Cancelled) -> Self {
+        Error::Cancelled
+    }
+}
+
+/// Where the session files are.
+#[derive(Debug, Clone)]
+struct Layout {
+    directory: PathBuf,
+    shard_levels: usize,
+}
+
+impl Layout {
+    /// The path of the file of session `id`.
+    fn path(&self, id: &Id) -> PathBuf {
+        let name = format!("{:032x}", id.0);
+        let mut path = self.directory.clone();
+        for level in 0..self.shard_levels {
+            path.push(&name[2 * level..2 * level + 2]);
+        }
+        path.push(name);
+        path.set_extension("json");
+        path
+    }
+}
+
+/// The contents of a session file.
+#[derive(Serialize, Deserialize)]
+struct Stored<T> {
+    expiry_date: Option<OffsetDateTime>,
+    expiry: Expiry,
+    record: T,
+}
+
+impl<'a, R: Expires> Stored<&'a R> {
+    fn new(record: &'a R, now: OffsetDateTime) -> Self {
+        let expiry = record.expires();
+        Stored {
+            expiry_date: expiry.deadline(now),
+            expiry,
+            record,
+        }
+    }
+}
+
+impl<T> Stored<T> {
+    fn is_expired(&self, now: OffsetDateTime) -> bool {
+        self.expiry_date
+            .is_some_and(|expiry_date| expiry_date <= now)
+    }
+
+    /// Renew the expiry of the session, as if it was saved at `now`.
+    fn refresh(&mut self, now: OffsetDateTime) {
+        self.expiry_date = self.expiry.deadline(now);
+    }
+}
+
+impl<R> SessionStore<R> for FsStore<R>
+where
+    R: Expires + Serialize + DeserializeOwned + Send + Sync,
+{
+    type Error = Error;
+
+    async fn create(&mut self, record: &R) -> Result<Id, Self::Error> {
+        let now = self.clock.now();
+        let stored = Stored::new(record, now);
+        // A record that is already expired is not written, as it could never be loaded.
+        if stored.is_expired(now) {
+            return Ok(random_id());
+        }
+        let contents = serde_json::to_vec(&stored)?;
+        let layout = self.layout.clone();
+        blocking(move || loop {
+            let id = random_id();
+            let path = layout.path(&id);
+            if read_live::<IgnoredAny>(&path, now)?.is_none() {
+                write(&path, &contents)?;
+                return Ok(id);
+            }
+        })
+        .await
+    }
+
+    async fn save(&mut self, id: &Id, record: &R) -> Result<bool, Self::Error> {
+        let now = self.clock.now();
+        let stored = Stored::new(record, now);
+        let (contents, expired) = (serde_json::to_vec(&stored)?, stored.is_expired(now));
+        let path = self.layout.path(id);
+        blocking(move || {
+            if read_live::<IgnoredAny>(&path, now)?.is_none() {
+                return Ok(false);
+            }
+            if expired {
+                remove(&path)?;
+            } else {
+                write(&path, &contents)?;
+            }
+            Ok(true)
+        })
+        .await
+    }
+
+    async fn save_or_create(&mut self, id: &Id, record: &R) -> Result<(), Self::Error> {
+        let now = self.clock.now();
+        let stored = Stored::new(record, now);
+        let (contents, expired) = (serde_json::to_vec(&stored)?, stored.is_expired(now));
+        let path = self.layout.path(id);
+        blocking(move || {
+            if expired {
+                remove(&path)?;
+            } else {
+                write(&path, &contents)?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn load(&mut self, id: &Id) -> Result<Option<R>, Self::Error> {
+        let session = self.load_with_expiry_date(id).await?;
+        Ok(session.map(|(record, _)| record))
+    }
+
+    async fn delete(&mut self, id: &Id) -> Result<bool, Self::Error> {
+        let now = self.clock.now();
+        let path = self.layout.path(id);
+        blocking(move || {
+            if read_live::<IgnoredAny>(&path, now)?.is_none() {
+                return Ok(false);
+            }
+            remove(&path)
+        })
+        .await
+    }
+
+    async fn cycle_id(&mut self, old_id: &Id) -> Result<Option<Id>, Self::Error> {
+        let now = self.clock.now();
+        let layout = self.layout.clone();
+        let old_path = layout.path(old_id);
+        blocking(move || {
+            // The record is kept as is, without deserializing it.
+            let Some(mut stored) = read_live::<Box<RawValue>>(&old_path, now)? else {
+                return Ok(None);
+            };
+            // Cycling the id is an activity, like touching the session.
+            stored.refresh(now);
+            let contents = serde_json::to_vec(&stored)?;
+            let new_id = loop {
+                let id = random_id();
+                if read_live::<IgnoredAny>(&layout.path(&id), now)?.is_none() {
+                    break id;
+                }
+            };
+            // The new file is written first, so that the session is kept if the process stops
+            // in between.
+            write(&layout.path(&new_id), &contents)?;
+            remove(&old_path)?;
+            Ok(Some(new_id))
+        })
+        .await
+    }
+
+    async fn touch(&mut self, id: &Id) -> Result<bool, Self::Error> {
+        let now = self.clock.now();
+        let path = self.layout.path(id);
+        blocking(move || {
+            // The record is kept as is, without deserializing it.
+            let Some(mut stored) = read_live::<Box<RawValue>>(&path, now)? else {
+                return Ok(false);
+            };
+            stored.refresh(now);
+            write(&path, &serde_json::to_vec(&stored)?)?;
+            Ok(true)
+        })
+        .await
+    }
+
+    async fn load_with_expiry_date(
+        &mut self,
+        id: &Id,
+    ) -> Result<Option<(R, ExpiryDate)>, Self::Error> {
+        let now = self.clock.now();
+        let path = self.layout.path(id);
+        let Some(contents) = blocking(move || Ok::<_, Error>(read(&path)?)).await? else {
+            return Ok(None);
+        };
+        // The record is deserialized outside of the blocking thread.
+        let stored: Stored<R> = serde_json::from_slice(&contents)?;
+        if stored.is_expired(now) {
+            return Ok(None);
+        }
+        let expiry_date = stored.expiry_date.map_or(ExpiryDate::Never, ExpiryDate::At);
+        Ok(Some((stored.record, expiry_date)))
+    }
+}
+
+/// The contents of the file at `path`, if it exists.
+fn read(path: &Path) -> io::Result<Option<Vec<u8>>> {
+    match fs::read(path) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// The session file at `path`, if it exists.
+fn read_stored<T: DeserializeOwned>(path: &Path) -> Result<Option<Stored<T>>, Error> {
+    let Some(contents) = read(path)? else {
+        return Ok(None);
+    };
+    Ok(Some(serde_json::from_slice(&contents)?))
+}
+
+/// The session file at `path`, if it exists and had not expired at `now`.
+fn read_live<T: DeserializeOwned>(
+    path: &Path,
+    now: OffsetDateTime,
+) -> Result<Option<Stored<T>>, Error> {
+    Ok(read_stored(path)?.filter(|stored: &Stored<T>| !stored.is_expired(now)))
+}
+
+/// Replace the file at `path` with `contents`.
+///
+/// The contents are written to a temporary file in the same directory, which is then renamed,
+/// so that the file at `path` is replaced atomically. The directory is synced after the rename,
+/// so that the new file survives a crash of the machine, or of the server of a network
+/// filesystem.
+fn write(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let directory = path.parent().expect("session files are in a directory");
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(format!(".{:016x}.tmp", rand::random::<u64>()));
+    let temporary = PathBuf::from(temporary);
+
+    let mut file = match File::create(&temporary) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            // The shard directory of the session does not exist yet.
+            fs::create_dir_all(directory)?;
+            File::create(&temporary)?
+        }
+        Err(err) => return Err(err),
+    };
+    let result = file
+        .write_all(contents)
+        .and_then(|()| file.sync_all())
+        .and_then(|()| fs::rename(&temporary, path))
+        .and_then(|()| sync_directory(directory));
+    if result.is_err() {
+        let _ = fs::remove_file(&temporary);
+    }
+    result
+}
+
+/// Make the renames of files in `directory` durable.
+///
+/// Syncing a file does not sync its entry in the directory. Directories can't be synced on
+/// Windows, where renames are made durable by the filesystem.
+fn sync_directory(directory: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    File::open(directory)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = directory;
+    Ok(())
+}
+
+/// Remove the file at `path`, returning whether it existed.
+fn remove(path: &Path) -> Result<bool, Error> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(true),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Remove the session files in `directory` and its subdirectories that expired at `now`,
+/// returning how many were removed.
+fn delete_expired(directory: &Path, now: OffsetDateTime) -> Result<usize, Error> {
+    let mut deleted = 0;
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            deleted += delete_expired(&path, now)?;
+            continue;
+        }
+        if path.extension().is_some_and(|extension| extension == "tmp") {
+            // The age of the file is given by the clock of the system rather than the clock of
+            // the store, since it is compared with its modification time.
+            let modified = entry.metadata()?.modified()?;
+            if modified
+                .elapsed()
+                .is_ok_and(|age| age >= STALE_TEMPORARY_FILE_AGE)
+            {
+                remove(&path)?;
+            }
+            continue;
+        }
+        if path.extension().is_none_or(|extension| extension != "json") {
+            continue;
+        }
+        match read_stored::<IgnoredAny>(&path) {
+            Ok(Some(stored)) if stored.is_expired(now) => {
+                if remove(&path)? {
+                    deleted += 1;
+                }
+            }
+            Ok(_) => {}
+            Err(err) => {
+                tracing::error!(err = %err, path = %path.display(), "failed to read a session file");
+            }
+        }
+    }
+    Ok(deleted)
+}
+
+fn random_id() -> Id {
+    use rand::prelude::*;
+    Id(rand::thread_rng().gen())
+}
@@ -0,0 +1,110 @@
+//! The `FsStore`, tested against temporary directories.
+use std::{
+    fs::{self, File},
+    time::{Duration as StdDuration, SystemTime},
+};
+
+use tempfile::TempDir;
+use time::Duration;
+use tower_sesh_core::{
+    clock::ManualClock,
+    test_util::{self, record, Record, START},
+    Expiry, Id, SessionStore,
+};
+use tower_sesh_fs_store::FsStore;
+
+/// A store in a new temporary directory, removed when the directory is dropped.
+fn store() -> (FsStore<Record>, ManualClock, TempDir) {
+    let directory = TempDir::new().unwrap();
+    let clock = ManualClock::new(START);
+    let store = FsStore::new(directory.path())
+        .unwrap()
+        .with_clock(clock.clone());
+    (store, clock, directory)
+}
+
+#[tokio::test]
+async fn session_store() {
+    let directory = TempDir::new().unwrap();
+    let mut stores = 0;
+    test_util::check_store(|clock| {
+        stores += 1;
+        FsStore::new(directory.path().join(stores.to_string()))
+            .unwrap()
+            .with_clock(clock)
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn delete_expired() {
+    let directory = TempDir::new().unwrap();
+    test_util::check_delete_expired(
+        |clock| FsStore::new(directory.path()).unwrap().with_clock(clock),
+        |store| async move { store.delete_expired().await },
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn shard_levels() {
+    let temporary = TempDir::new().unwrap();
+    let directory = temporary.path();
+    let mut store: FsStore<Record> = FsStore::new(directory).unwrap().with_shard_levels(2);
+    let session = record(Expiry::OnSessionEnd);
+    let id = store.create(&session).await.unwrap();
+
+    let name = format!("{:032x}", id.0);
+    let path = directory
+        .join(&name[..2])
+        .join(&name[2..4])
+        .join(format!("{name}.json"));
+    assert!(path.is_file());
+
+    // Another store on the same directory, e.g. on another server, sees the session.
+    let mut other: FsStore<Record> = FsStore::new(directory).unwrap().with_shard_levels(2);
+    assert_eq!(other.load(&id).await.unwrap(), Some(session));
+    let new_id = other.cycle_id(&id).await.unwrap().unwrap();
+    assert!(!path.exists());
+    assert!(store.load(&new_id).await.unwrap().is_some());
+}
+
+#[tokio::test]
+async fn unreadable_files_are_kept() {
+    let (mut store, clock, directory) = store();
+    let id = store
+        .create(&record(Expiry::OnInactivity(Duration::minutes(1))))
+        .await
+        .unwrap();
+    let corrupt = directory.path().join(format!("{:032x}.json", u128::MAX));
+    fs::write(&corrupt, "not a session").unwrap();
+
+    assert!(store.load(&Id(u128::MAX)).await.is_err());
+    clock.advance(Duration::minutes(1));
+    assert_eq!(store.delete_expired().await.unwrap(), 1);
+    assert!(corrupt.is_file());
+    assert!(store.load(&id).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn stale_temporary_files_are_deleted() {
+    let (store, _, directory) = store();
+    let name = format!("{:032x}.json", 1);
+    let stale = directory
+        .path()
+        .join(format!("{name}.0000000000000001.tmp"));
+    let recent = directory
+        .path()
+        .join(format!("{name}.0000000000000002.tmp"));
+    File::create(&stale)
+        .unwrap()
+        .set_modified(SystemTime::now() - StdDuration::from_secs(2 * 60 * 60))
+        .unwrap();
+    File::create(&recent).unwrap();
+
+    // A temporary file is not a session, so it is not counted.
+    assert_eq!(store.delete_expired().await.unwrap(), 0);
+    assert!(!stale.exists());
+    // A recent temporary file may still be written.
+    assert!(recent.is_file());
+}
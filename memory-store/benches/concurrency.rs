@@ -0,0 +1,96 @@
+//! Throughput of the memory stores under many concurrent tokio tasks.
+//!
+//! Every task creates a session, then loads, saves and touches it in a loop, like a request
+//! handler would. A single-shard `MemoryStore` puts every session behind one lock, like the store
+//! did before it was sharded, to compare with the default 16 shards.
+//!
+//! Run with `cargo bench -p tower-sesh-memory-store`.
+use std::{
+    convert::Infallible,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tower_sesh_core::{Expires, SessionStore};
+use tower_sesh_memory_store::{LruStore, MemoryStore};
+
+/// The number of load, save and touch rounds of every task.
+const ROUNDS: usize = 2_000;
+
+#[derive(Debug, Clone)]
+struct Record {
+    /// Makes the record costly to clone, like a session holding real data.
+    #[allow(dead_code)]
+    data: Vec<u8>,
+}
+
+impl Expires for Record {}
+
+fn record() -> Record {
+    Record {
+        data: vec![0; 1024],
+    }
+}
+
+async fn run<R, S>(store: S, record: R, tasks: usize) -> Duration
+where
+    R: Clone + Send + Sync + 'static,
+    S: SessionStore<R, Error = Infallible> + Clone + Send + 'static,
+{
+    let start = Instant::now();
+    let handles: Vec<_> = (0..tasks)
+        .map(|_| {
+            let mut store = store.clone();
+            let record = record.clone();
+            tokio::spawn(async move {
+                let id = store.create(&record).await.unwrap();
+                for _ in 0..ROUNDS {
+                    let loaded = store.load(&id).await.unwrap().unwrap();
+                    store.save(&id, &loaded).await.unwrap();
+                    store.touch(&id).await.unwrap();
+                    tokio::task::yield_now().await;
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.await.unwrap();
+    }
+    start.elapsed()
+}
+
+fn report(name: &str, tasks: usize, elapsed: Duration) {
+    let operations = (tasks * ROUNDS * 3) as f64;
+    println!(
+        "{name:<24} {tasks:>5} tasks {:>12.0} ops/s",
+        operations / elapsed.as_secs_f64()
+    );
+}
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .build()
+        .expect("the runtime should build");
+
+    for tasks in [1, 8, 64, 512] {
+        let store: MemoryStore<Record> = MemoryStore::default().with_shards(1);
+        let elapsed = runtime.block_on(run(store, record(), tasks));
+        report("MemoryStore, 1 shard", tasks, elapsed);
+
+        let store: MemoryStore<Record> = MemoryStore::default();
+        let elapsed = runtime.block_on(run(store, record(), tasks));
+        report("MemoryStore, 16 shards", tasks, elapsed);
+
+        let store: MemoryStore<Arc<Record>> = MemoryStore::default();
+        let elapsed = runtime.block_on(run(store, Arc::new(record()), tasks));
+        report("MemoryStore<Arc<Record>>", tasks, elapsed);
+
+        let store: LruStore<Record> = LruStore::new(tasks).with_shards(1);
+        let elapsed = runtime.block_on(run(store, record(), tasks));
+        report("LruStore, 1 shard", tasks, elapsed);
+
+        let store: LruStore<Record> = LruStore::new(tasks * 16);
+        let elapsed = runtime.block_on(run(store, record(), tasks));
+        report("LruStore, 16 shards", tasks, elapsed);
+    }
+}
@@ -1,16 +1,39 @@
-use std::sync::Mutex;
-use std::{collections::HashMap, convert::Infallible, sync::Arc};
+use std::sync::{Mutex, MutexGuard};
+use std::collections::hash_map::{Entry, HashMap};
+use std::{convert::Infallible, sync::Arc};
 
 use std::fmt::Debug;
 use time::OffsetDateTime;
-use tower_sesh_core::{expires::Expires, Expiry, Id, SessionStore};
+#[cfg(feature = "reaper")]
+use tower_sesh_core::task::PeriodicTask;
+use tower_sesh_core::{
+    clock::{Clock, SystemClock},
+    expires::Expires,
+    session_store::ExpiryDate,
+    Id, SessionStore,
+};
+
+pub use self::lru::{LruMetrics, LruStore};
+#[cfg(feature = "snapshot")]
+pub use self::snapshot::SnapshotTask;
+
+mod lru;
+#[cfg(feature = "snapshot")]
+mod snapshot;
 
 /// A session store that lives only in memory.
 ///
 /// This is useful for testing but not recommended for real applications.
 ///
-/// The store manages the expiry of the sessions with respect to UTC time. No cleanup is done for
-/// the expired sessions untile the are loaded.
+/// The store manages the expiry of the sessions with respect to UTC time, as given by its
+/// [`Clock`]. Expired sessions are removed when they are loaded, when
+/// [`purge_expired`](MemoryStore::purge_expired) is called, or periodically by a [`Reaper`] with
+/// the `reaper` feature.
+///
+/// The sessions are split into shards, each with its own lock, so that concurrent requests
+/// rarely wait for each other. The records are stored in an [`Arc`], and only cloned once the
+/// lock is released. Records that are expensive to clone can be wrapped in an `Arc` as well, i.e.
+/// with a `MemoryStore<Arc<R>>`, so that loading a session never clones the record itself.
 ///
 /// # Examples
 ///
@@ -25,38 +48,218 @@ use tower_sesh_core::{expires::Expires, Expiry, Id, SessionStore};
 /// let store: MemoryStore<User> = MemoryStore::default();
 /// ```
 #[derive(Debug)]
-pub struct MemoryStore<R>(Arc<Mutex<HashMap<Id, Value<R>>>>);
+pub struct MemoryStore<R> {
+    sessions: Sessions<R>,
+    clock: Arc<dyn Clock>,
+}
+
+/// The shards of the sessions of a [`MemoryStore`].
+type Sessions<R> = Arc<[Mutex<HashMap<Id, Value<R>>>]>;
+
+/// The number of shards used by default.
+const DEFAULT_SHARDS: usize = 16;
+
+impl<R> MemoryStore<R> {
+    /// Create a new `MemoryStore` that uses `clock` to compute the expiry of the sessions.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use tower_sesh_core::clock::ManualClock;
+    /// use tower_sesh_memory_store::MemoryStore;
+    ///
+    /// let clock = ManualClock::default();
+    /// let store: MemoryStore<()> = MemoryStore::with_clock(clock.clone());
+    /// ```
+    pub fn with_clock(clock: impl Clock + 'static) -> Self {
+        MemoryStore {
+            sessions: (0..DEFAULT_SHARDS).map(|_| Default::default()).collect(),
+            clock: Arc::new(clock),
+        }
+    }
+
+    /// Split the sessions into `shards` shards, instead of 16.
+    ///
+    /// A single shard puts every session behind the same lock, so that concurrent requests
+    /// wait for each other.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `shards` is zero.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use tower_sesh_memory_store::MemoryStore;
+    ///
+    /// let store: MemoryStore<()> = MemoryStore::default().with_shards(4);
+    /// ```
+    pub fn with_shards(mut self, shards: usize) -> Self {
+        assert!(shards > 0, "the number of shards must not be zero");
+        self.sessions = (0..shards).map(|_| Default::default()).collect();
+        self
+    }
+
+    /// The number of sessions held by the store, including the expired ones that were not
+    /// removed yet.
+    pub fn len(&self) -> usize {
+        self.sessions
+            .iter()
+            .map(|shard| shard.lock().unwrap().len())
+            .sum()
+    }
+
+    /// Whether the store holds no session.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn shard(&self, id: &Id) -> MutexGuard<'_, HashMap<Id, Value<R>>> {
+        self.sessions[shard_index(id, self.sessions.len())]
+            .lock()
+            .unwrap()
+    }
+
+    /// The session `id` if it had not expired at `now`. Expired sessions are removed.
+    fn get_live(&self, id: &Id, now: OffsetDateTime) -> Option<Value<R>> {
+        let mut shard = self.shard(id);
+        let value = shard.get(id)?;
+        if value.is_expired(now) {
+            shard.remove(id);
+            return None;
+        }
+        Some(Value {
+            data: Arc::clone(&value.data),
+            expiry_date: value.expiry_date,
+        })
+    }
+
+    /// Remove every expired session from the store, returning how many were removed.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use tower_sesh_memory_store::MemoryStore;
+    ///
+    /// let store: MemoryStore<()> = MemoryStore::default();
+    /// assert_eq!(store.purge_expired(), 0);
+    /// ```
+    pub fn purge_expired(&self) -> usize {
+        purge_expired(&self.sessions, self.clock.now())
+    }
+
+    /// Spawn a task on the current tokio runtime that removes the expired sessions every
+    /// `interval`.
+    ///
+    /// Requires the `reaper` feature.
+    ///
+    /// The task stops when the returned [`Reaper`] is shut down or dropped, or when every clone
+    /// of the store has been dropped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside of a tokio runtime, or if `interval` is zero.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use std::time::Duration;
+    ///
+    /// use tower_sesh_memory_store::MemoryStore;
+    ///
+    /// # #[tokio::main(flavor = "current_thread")]
+    /// # async fn main() {
+    /// let store: MemoryStore<()> = MemoryStore::default();
+    /// let reaper = store.spawn_reaper(Duration::from_secs(60));
+    /// // ...
+    /// reaper.shutdown().await;
+    /// # }
+    /// ```
+    #[cfg(feature = "reaper")]
+    pub fn spawn_reaper(&self, interval: std::time::Duration) -> Reaper
+    where
+        R: Send + Sync + 'static,
+    {
+        let clock = self.clock.clone();
+        Reaper(PeriodicTask::spawn(
+            &self.sessions,
+            interval,
+            move |sessions| {
+                purge_expired(&sessions, clock.now());
+                std::future::ready(())
+            },
+        ))
+    }
+}
+
+/// A task removing the expired sessions of a [`MemoryStore`] periodically.
+///
+/// Created with [`MemoryStore::spawn_reaper`]. Dropping the reaper stops the task without
+/// waiting for it.
+#[cfg(feature = "reaper")]
+#[derive(Debug)]
+pub struct Reaper(PeriodicTask);
+
+#[cfg(feature = "reaper")]
+impl Reaper {
+    /// Stop the task, waiting for a sweep in progress to finish.
+    pub async fn shutdown(self) {
+        self.0.shutdown().await;
+    }
+}
 
 impl<R> Default for MemoryStore<R> {
     fn default() -> Self {
-        MemoryStore(Default::default())
+        MemoryStore::with_clock(SystemClock)
     }
 }
 
 impl<R> Clone for MemoryStore<R> {
     fn clone(&self) -> Self {
-        MemoryStore(self.0.clone())
+        MemoryStore {
+            sessions: self.sessions.clone(),
+            clock: self.clock.clone(),
+        }
     }
 }
 
 #[derive(Debug, Clone)]
 struct Value<R> {
-    data: R,
+    data: Arc<R>,
     // Needed because if the expiry date is set to `OnInactivity`, we need to know whether the
     // session is active or not.
     expiry_date: Option<OffsetDateTime>,
 }
 
 impl<R: Expires> Value<R> {
-    /// Create a new `MemoryStore`.
-    pub fn new(data: R) -> Self {
-        let expiry_date = match data.expires() {
-            Expiry::OnSessionEnd => None,
-            Expiry::OnInactivity(duration) => Some(OffsetDateTime::now_utc() + duration),
-            Expiry::AtDateTime(offset_date_time) => Some(offset_date_time),
-        };
+    /// Create a new value, saved at `now`.
+    pub fn new(data: R, now: OffsetDateTime) -> Self {
+        let expiry_date = expiry_date(&data, now);
+        Value {
+            data: Arc::new(data),
+            expiry_date,
+        }
+    }
+}
+
+impl<R> Value<R> {
+    /// Whether the session expired at `now`.
+    fn is_expired(&self, now: OffsetDateTime) -> bool {
+        self.expiry_date
+            .is_some_and(|expiry_date| expiry_date <= now)
+    }
 
-        Value { data, expiry_date }
+    /// Expire the session no later than `expiry_date`.
+    fn expire_by(&mut self, expiry_date: OffsetDateTime) {
+        self.expiry_date = Some(
+            self.expiry_date
+                .map_or(expiry_date, |date| date.min(expiry_date)),
+        );
+    }
+
+    /// The expiry date of the session, as returned by `load_with_expiry_date`.
+    fn reported_expiry_date(&self) -> ExpiryDate {
+        self.expiry_date.map_or(ExpiryDate::Never, ExpiryDate::At)
     }
 }
 
@@ -67,73 +270,147 @@ where
     type Error = Infallible;
 
     async fn create(&mut self, record: &R) -> Result<Id, Self::Error> {
-        let mut id = random_id();
-        let mut store = self.0.lock().unwrap();
-        while store.contains_key(&id) {
-            // If the ID already exists, generate a new one
-            id = random_id();
+        let now = self.clock.now();
+        let value = Value::new(record.clone(), now);
+        loop {
+            let id = random_id();
+            if let Entry::Vacant(entry) = self.shard(&id).entry(id) {
+                // A record that is already expired is not inserted, as it could never be loaded.
+                if !value.is_expired(now) {
+                    entry.insert(value);
+                }
+                return Ok(id);
+            }
         }
-
-        let value = Value::new(record.clone());
-
-        store.insert(id, value);
-        Ok(id)
     }
 
     async fn save(&mut self, id: &Id, record: &R) -> Result<bool, Self::Error> {
-        let mut store = self.0.lock().unwrap();
-        if store.contains_key(id) {
-            let value = Value::new(record.clone());
-            store.insert(*id, value);
-            Ok(true)
-        } else {
-            Ok(false)
+        let now = self.clock.now();
+        let value = Value::new(record.clone(), now);
+        let mut shard = self.shard(id);
+        if remove_live(&mut shard, id, now).is_none() {
+            return Ok(false);
+        }
+        if !value.is_expired(now) {
+            shard.insert(*id, value);
         }
+        Ok(true)
     }
 
     async fn save_or_create(&mut self, id: &Id, record: &R) -> Result<(), Self::Error> {
-        let mut store = self.0.lock().unwrap();
-        let value = Value::new(record.clone());
-        store.insert(*id, value);
+        let now = self.clock.now();
+        let value = Value::new(record.clone(), now);
+        let mut shard = self.shard(id);
+        if value.is_expired(now) {
+            shard.remove(id);
+        } else {
+            shard.insert(*id, value);
+        }
         Ok(())
     }
 
     async fn load(&mut self, id: &Id) -> Result<Option<R>, Self::Error> {
-        let mut store = self.0.lock().unwrap();
+        let now = self.clock.now();
+        let value = self.get_live(id, now);
+        Ok(value.map(|value| R::clone(&value.data)))
+    }
 
-        let Some(value) = store.get(id) else {
+    async fn delete(&mut self, id: &Id) -> Result<bool, Self::Error> {
+        let now = self.clock.now();
+        Ok(remove_live(&mut self.shard(id), id, now).is_some())
+    }
+
+    async fn cycle_id(&mut self, old_id: &Id) -> Result<Option<Id>, Self::Error> {
+        let now = self.clock.now();
+        let Some(mut value) = remove_live(&mut self.shard(old_id), old_id, now) else {
             return Ok(None);
         };
-        Ok(match value.expiry_date {
-            Some(expiry_date) if expiry_date > OffsetDateTime::now_utc() => {
-                store.remove(id);
-                None
+        // Cycling the id is an activity, like touching the session.
+        value.expiry_date = expiry_date(&*value.data, now);
+        loop {
+            let new_id = random_id();
+            if let Entry::Vacant(entry) = self.shard(&new_id).entry(new_id) {
+                entry.insert(value);
+                return Ok(Some(new_id));
             }
-            _ => Some(value.data.clone()),
-        })
+        }
     }
 
-    async fn delete(&mut self, id: &Id) -> Result<bool, Self::Error> {
-        let mut store = self.0.lock().unwrap();
-        Ok(store.remove(id).is_some())
+    async fn touch(&mut self, id: &Id) -> Result<bool, Self::Error> {
+        let now = self.clock.now();
+        let mut shard = self.shard(id);
+        let Some(value) = shard.get_mut(id) else {
+            return Ok(false);
+        };
+        if value.is_expired(now) {
+            shard.remove(id);
+            return Ok(false);
+        }
+        value.expiry_date = expiry_date(&*value.data, now);
+        Ok(true)
     }
 
-    async fn cycle_id(&mut self, old_id: &Id) -> Result<Option<Id>, Self::Error> {
-        let mut store = self.0.lock().unwrap();
-        if let Some(record) = store.remove(old_id) {
-            let mut new_id = random_id();
-            while store.contains_key(&new_id) {
-                // If the ID already exists, generate a new one
-                new_id = random_id();
-            }
-            store.insert(new_id, record);
-            Ok(Some(new_id))
-        } else {
-            Ok(None)
+    async fn load_with_expiry_date(
+        &mut self,
+        id: &Id,
+    ) -> Result<Option<(R, ExpiryDate)>, Self::Error> {
+        let now = self.clock.now();
+        let value = self.get_live(id, now);
+        Ok(value.map(|value| (R::clone(&value.data), value.reported_expiry_date())))
+    }
+
+    async fn save_or_create_until(
+        &mut self,
+        id: &Id,
+        record: &R,
+        expiry_date: OffsetDateTime,
+    ) -> Result<bool, Self::Error> {
+        let now = self.clock.now();
+        let mut value = Value::new(record.clone(), now);
+        value.expire_by(expiry_date);
+        let mut shard = self.shard(id);
+        if value.is_expired(now) {
+            shard.remove(id);
+            return Ok(false);
         }
+        shard.insert(*id, value);
+        Ok(true)
     }
 }
 
+/// Remove the session `id` from the store, returning it only if it had not expired at `now`.
+fn remove_live<R>(
+    store: &mut HashMap<Id, Value<R>>,
+    id: &Id,
+    now: OffsetDateTime,
+) -> Option<Value<R>> {
+    store.remove(id).filter(|value| !value.is_expired(now))
+}
+
+/// Remove the sessions that expired at `now`, returning how many were removed.
+fn purge_expired<R>(sessions: &[Mutex<HashMap<Id, Value<R>>>], now: OffsetDateTime) -> usize {
+    sessions
+        .iter()
+        .map(|shard| {
+            let mut shard = shard.lock().unwrap();
+            let before = shard.len();
+            shard.retain(|_, value| !value.is_expired(now));
+            before - shard.len()
+        })
+        .sum()
+}
+
+/// The shard holding the session `id`, out of `count` shards.
+fn shard_index(id: &Id, count: usize) -> usize {
+    // Ids are random, so their low bits are evenly distributed.
+    (id.0 % count as u128) as usize
+}
+
+/// The date at which `data` expires, if it is saved at `now`.
+fn expiry_date<R: Expires>(data: &R, now: OffsetDateTime) -> Option<OffsetDateTime> {
+    data.expires().deadline(now)
+}
+
 fn random_id() -> Id {
     use rand::prelude::*;
     let id_val = rand::thread_rng().gen();
@@ -143,7 +420,7 @@ fn random_id() -> Id {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use tower_sesh_core::SessionStore;
+    use tower_sesh_core::{clock::ManualClock, Expiry, SessionStore};
 
     
     #[derive(Debug, Clone)]
@@ -153,6 +430,15 @@ mod tests {
 
     impl Expires for SimpleUser {}
 
+    #[derive(Debug, Clone)]
+    struct Expiring(Expiry);
+
+    impl Expires for Expiring {
+        fn expires(&self) -> Expiry {
+            self.0
+        }
+    }
+
     #[tokio::test]
     async fn round_trip() {
         let mut store: MemoryStore<SimpleUser> = MemoryStore::default();
@@ -180,4 +466,133 @@ mod tests {
         assert!(store.delete(&new_id).await.unwrap());
         assert!(store.load(&new_id).await.unwrap().is_none());
     }
+
+    #[tokio::test]
+    async fn shared_records() {
+        let mut store: MemoryStore<Arc<SimpleUser>> = MemoryStore::default();
+
+        let user = Arc::new(SimpleUser { age: 20 });
+        let id = store.create(&user).await.unwrap();
+        let loaded = store.load(&id).await.unwrap().unwrap();
+        assert!(Arc::ptr_eq(&user, &loaded));
+    }
+
+    #[tokio::test]
+    async fn touch() {
+        let mut store: MemoryStore<Expiring> = MemoryStore::default();
+
+        let id = store
+            .create(&Expiring(Expiry::OnInactivity(time::Duration::minutes(5))))
+            .await
+            .unwrap();
+        let before = store.shard(&id)[&id].expiry_date.unwrap();
+        assert!(store.touch(&id).await.unwrap());
+        assert!(store.shard(&id)[&id].expiry_date.unwrap() >= before);
+
+        let expired = store
+            .create(&Expiring(Expiry::AtDateTime(
+                OffsetDateTime::now_utc() - time::Duration::minutes(5),
+            )))
+            .await
+            .unwrap();
+        assert!(!store.touch(&expired).await.unwrap());
+        assert!(!store.shard(&expired).contains_key(&expired));
+
+        assert!(!store.touch(&random_id()).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn manual_clock() {
+        let clock = ManualClock::new(OffsetDateTime::UNIX_EPOCH);
+        let mut store: MemoryStore<Expiring> = MemoryStore::with_clock(clock.clone());
+
+        let id = store
+            .create(&Expiring(Expiry::OnInactivity(time::Duration::minutes(5))))
+            .await
+            .unwrap();
+        clock.advance(time::Duration::minutes(4));
+        assert!(store.touch(&id).await.unwrap());
+        assert_eq!(
+            store.shard(&id)[&id].expiry_date,
+            Some(OffsetDateTime::UNIX_EPOCH + time::Duration::minutes(9))
+        );
+
+        clock.advance(time::Duration::minutes(5));
+        assert!(!store.touch(&id).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn purge_expired() {
+        let clock = ManualClock::new(OffsetDateTime::UNIX_EPOCH);
+        let mut store: MemoryStore<Expiring> = MemoryStore::with_clock(clock.clone());
+
+        for minutes in [1, 2, 3] {
+            store
+                .create(&Expiring(Expiry::OnInactivity(time::Duration::minutes(
+                    minutes,
+                ))))
+                .await
+                .unwrap();
+        }
+        let forever = store.create(&Expiring(Expiry::OnSessionEnd)).await.unwrap();
+        assert_eq!(store.purge_expired(), 0);
+
+        clock.advance(time::Duration::minutes(2));
+        assert_eq!(store.purge_expired(), 2);
+        clock.advance(time::Duration::days(1));
+        assert_eq!(store.purge_expired(), 1);
+
+        assert_eq!(store.len(), 1);
+        assert!(store.shard(&forever).contains_key(&forever));
+    }
+
+    #[cfg(feature = "reaper")]
+    #[tokio::test]
+    async fn reaper() {
+        use std::time::Duration;
+
+        let clock = ManualClock::new(OffsetDateTime::UNIX_EPOCH);
+        let mut store: MemoryStore<Expiring> = MemoryStore::with_clock(clock.clone());
+        store
+            .create(&Expiring(Expiry::OnInactivity(time::Duration::minutes(1))))
+            .await
+            .unwrap();
+
+        let reaper = store.spawn_reaper(Duration::from_millis(10));
+        clock.advance(time::Duration::minutes(1));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(store.is_empty());
+        reaper.shutdown().await;
+
+        // The reaper does not keep the store alive.
+        let reaper = store.spawn_reaper(Duration::from_millis(10));
+        drop(store);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(reaper.0.is_finished());
+    }
+
+    #[tokio::test]
+    async fn idle_and_absolute_expiry() {
+        let mut store: MemoryStore<Expiring> = MemoryStore::default();
+
+        let deadline = OffsetDateTime::now_utc() + time::Duration::minutes(5);
+        let id = store
+            .create(&Expiring(Expiry::OnInactivityUntil(
+                time::Duration::hours(1),
+                deadline,
+            )))
+            .await
+            .unwrap();
+        assert!(store.touch(&id).await.unwrap());
+        assert_eq!(store.shard(&id)[&id].expiry_date, Some(deadline));
+
+        let idle = store
+            .create(&Expiring(Expiry::OnInactivityUntil(
+                time::Duration::minutes(1),
+                deadline,
+            )))
+            .await
+            .unwrap();
+        assert!(store.shard(&idle)[&idle].expiry_date < Some(deadline));
+    }
 }
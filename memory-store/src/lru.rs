@@ -0,0 +1,619 @@
+use std::collections::{BTreeMap, HashMap};
+use std::convert::Infallible;
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use time::OffsetDateTime;
+use tower_sesh_core::{
+    clock::{Clock, SystemClock},
+    expires::Expires,
+    session_store::ExpiryDate,
+    Id, SessionStore,
+};
+
+use crate::{expiry_date, random_id, shard_index, Value, DEFAULT_SHARDS};
+
+/// A session store that lives in memory and holds at most a fixed number of sessions.
+///
+/// When the store is full, the least recently used session is evicted to make room for a new
+/// one. This makes it suitable as the cache of a [`CachingSessionStore`], in front of a store
+/// that persists the sessions: evicted sessions are loaded from the persistent store again when
+/// they are needed.
+///
+/// The sessions are split into shards, each with its own lock, so that concurrent requests
+/// rarely wait for each other. The least recently used session is evicted per shard.
+///
+/// Like the [`MemoryStore`](crate::MemoryStore), expired sessions are never returned. A
+/// [time-to-live](LruStore::with_ttl) can also be set, so that the cached sessions are loaded
+/// from the persistent store again after a while even if they are used.
+///
+/// # Examples
+///
+/// ```rust
+/// use tower_sesh_core::{session_store::CachingSessionStore, Expires};
+/// use tower_sesh_memory_store::{LruStore, MemoryStore};
+///
+/// #[derive(Clone)]
+/// struct User {
+///     name: String,
+/// }
+///
+/// impl Expires for User {}
+///
+/// let cache: LruStore<User> = LruStore::new(10_000).with_ttl(time::Duration::minutes(5));
+/// // The persistent store would be a database in a real application.
+/// let backend: MemoryStore<User> = MemoryStore::default();
+/// let store = CachingSessionStore::new(cache.clone(), backend);
+///
+/// let metrics = cache.metrics();
+/// assert_eq!(metrics.hits, 0);
+/// ```
+///
+/// [`CachingSessionStore`]: tower_sesh_core::session_store::CachingSessionStore
+#[derive(Debug)]
+pub struct LruStore<R> {
+    /// The capacity requested, which is split between the shards.
+    requested: usize,
+    shards: Arc<[Mutex<Shard<R>>]>,
+    ttl: Option<time::Duration>,
+    clock: Arc<dyn Clock>,
+    counters: Arc<Counters>,
+}
+
+impl<R> LruStore<R> {
+    /// Create a new `LruStore` holding at most `capacity` sessions.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is zero.
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "the capacity must not be zero");
+        let shards = DEFAULT_SHARDS.min(capacity);
+        LruStore {
+            requested: capacity,
+            shards: Shard::split(capacity, shards),
+            ttl: None,
+            clock: Arc::new(SystemClock),
+            counters: Default::default(),
+        }
+    }
+
+    /// Split the sessions into `shards` shards, instead of 16.
+    ///
+    /// Each shard holds an equal part of the capacity, rounded up. Using a single shard makes
+    /// the eviction order exact.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `shards` is zero, or greater than the capacity.
+    pub fn with_shards(mut self, shards: usize) -> Self {
+        assert!(
+            (1..=self.requested).contains(&shards),
+            "the number of shards must be between 1 and the capacity"
+        );
+        self.shards = Shard::split(self.requested, shards);
+        self
+    }
+
+    /// Expire every session `ttl` after it was last saved, unless its record expires sooner.
+    pub fn with_ttl(mut self, ttl: time::Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Use `clock` to compute the expiry of the sessions.
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// The maximum number of sessions held by the store.
+    ///
+    /// This is the capacity given to [`LruStore::new`], rounded up to a multiple of the number of
+    /// shards.
+    pub fn capacity(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().capacity)
+            .sum()
+    }
+
+    /// The number of sessions currently held by the store, including the expired ones that
+    /// were not removed yet.
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().entries.len())
+            .sum()
+    }
+
+    /// Whether the store holds no session.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The number of hits, misses and evictions since the store was created.
+    ///
+    /// The metrics are shared by the clones of the store.
+    pub fn metrics(&self) -> LruMetrics {
+        LruMetrics {
+            hits: self.counters.hits.load(Ordering::Relaxed),
+            misses: self.counters.misses.load(Ordering::Relaxed),
+            evictions: self.counters.evictions.load(Ordering::Relaxed),
+        }
+    }
+
+    fn shard(&self, id: &Id) -> MutexGuard<'_, Shard<R>> {
+        self.shards[shard_index(id, self.shards.len())]
+            .lock()
+            .unwrap()
+    }
+}
+
+impl<R> Clone for LruStore<R> {
+    fn clone(&self) -> Self {
+        LruStore {
+            requested: self.requested,
+            shards: self.shards.clone(),
+            ttl: self.ttl,
+            clock: self.clock.clone(),
+            counters: self.counters.clone(),
+        }
+    }
+}
+
+/// Metrics of an [`LruStore`], as returned by [`LruStore::metrics`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct LruMetrics {
+    /// The number of sessions found by `load`.
+    pub hits: u64,
+    /// The number of sessions that `load` did not find, or found expired.
+    pub misses: u64,
+    /// The number of sessions evicted to make room for other sessions.
+    pub evictions: u64,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+/// A part of the sessions, with its own lock and capacity.
+#[derive(Debug)]
+struct Shard<R> {
+    capacity: usize,
+    entries: HashMap<Id, Entry<R>>,
+    /// The ids of the sessions by their last use, from the least to the most recently used.
+    recency: BTreeMap<u64, Id>,
+    /// Incremented on every use of a session.
+    tick: u64,
+}
+
+#[derive(Debug)]
+struct Entry<R> {
+    value: Value<R>,
+    /// The tick of the last use of the session.
+    used: u64,
+}
+
+impl<R> Shard<R> {
+    /// Split `capacity` between `count` new shards.
+    fn split(capacity: usize, count: usize) -> Arc<[Mutex<Self>]> {
+        let capacity = capacity.div_ceil(count);
+        (0..count)
+            .map(|_| {
+                Mutex::new(Shard {
+                    capacity,
+                    entries: HashMap::new(),
+                    recency: BTreeMap::new(),
+                    tick: 0,
+                })
+            })
+            .collect()
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    /// The session `id` if it had not expired at `now`, marked as the most recently used.
+    ///
+    /// Expired sessions are removed.
+    fn get_live(&mut self, id: &Id, now: OffsetDateTime) -> Option<&mut Value<R>> {
+        if self.entries.get(id)?.value.is_expired(now) {
+            self.remove(id);
+            return None;
+        }
+        let tick = self.next_tick();
+        let entry = self.entries.get_mut(id)?;
+        self.recency.remove(&entry.used);
+        self.recency.insert(tick, *id);
+        entry.used = tick;
+        Some(&mut entry.value)
+    }
+
+    /// Remove the session `id`, returning it only if it had not expired at `now`.
+    fn remove_live(&mut self, id: &Id, now: OffsetDateTime) -> Option<Value<R>> {
+        self.remove(id).filter(|value| !value.is_expired(now))
+    }
+
+    fn remove(&mut self, id: &Id) -> Option<Value<R>> {
+        let entry = self.entries.remove(id)?;
+        self.recency.remove(&entry.used);
+        Some(entry.value)
+    }
+
+    /// Insert the session `id` as the most recently used, returning how many sessions were
+    /// evicted to make room for it.
+    ///
+    /// Expired sessions are not inserted, and the previous session `id` is removed instead.
+    fn insert(&mut self, id: Id, value: Value<R>, now: OffsetDateTime) -> u64 {
+        self.remove(&id);
+        if value.is_expired(now) {
+            return 0;
+        }
+
+        let mut evictions = 0;
+        while self.entries.len() >= self.capacity {
+            let Some((_, lru)) = self.recency.pop_first() else {
+                break;
+            };
+            let evicted = self.entries.remove(&lru);
+            // Expired sessions would have been removed anyway, so they don't count as evictions.
+            if evicted.is_some_and(|entry| !entry.value.is_expired(now)) {
+                evictions += 1;
+            }
+        }
+
+        let used = self.next_tick();
+        self.recency.insert(used, id);
+        self.entries.insert(id, Entry { value, used });
+        evictions
+    }
+}
+
+impl<R: Expires> LruStore<R> {
+    /// Create a new value saved at `now`.
+    fn value(&self, record: &R, now: OffsetDateTime) -> Value<R>
+    where
+        R: Clone,
+    {
+        Value {
+            data: Arc::new(record.clone()),
+            expiry_date: self.expiry_date(record, now),
+        }
+    }
+
+    /// The date at which `data` expires if it is saved at `now`, at most `ttl` later.
+    fn expiry_date(&self, data: &R, now: OffsetDateTime) -> Option<OffsetDateTime> {
+        let expiry_date = expiry_date(data, now);
+        match self.ttl {
+            Some(ttl) => Some(expiry_date.map_or(now + ttl, |date| date.min(now + ttl))),
+            None => expiry_date,
+        }
+    }
+
+    fn insert(&self, shard: &mut Shard<R>, id: Id, value: Value<R>, now: OffsetDateTime) {
+        let evictions = shard.insert(id, value, now);
+        if evictions > 0 {
+            self.counters
+                .evictions
+                .fetch_add(evictions, Ordering::Relaxed);
+        }
+    }
+}
+
+impl<R> SessionStore<R> for LruStore<R>
+where
+    R: Expires + Send + Sync + Clone,
+{
+    type Error = Infallible;
+
+    async fn create(&mut self, record: &R) -> Result<Id, Self::Error> {
+        let now = self.clock.now();
+        let value = self.value(record, now);
+        loop {
+            let id = random_id();
+            let mut shard = self.shard(&id);
+            if !shard.entries.contains_key(&id) {
+                self.insert(&mut shard, id, value, now);
+                return Ok(id);
+            }
+        }
+    }
+
+    async fn save(&mut self, id: &Id, record: &R) -> Result<bool, Self::Error> {
+        let now = self.clock.now();
+        let mut shard = self.shard(id);
+        if shard.get_live(id, now).is_none() {
+            return Ok(false);
+        }
+        let value = self.value(record, now);
+        self.insert(&mut shard, *id, value, now);
+        Ok(true)
+    }
+
+    async fn save_or_create(&mut self, id: &Id, record: &R) -> Result<(), Self::Error> {
+        let now = self.clock.now();
+        let value = self.value(record, now);
+        self.insert(&mut self.shard(id), *id, value, now);
+        Ok(())
+    }
+
+    async fn load(&mut self, id: &Id) -> Result<Option<R>, Self::Error> {
+        let now = self.clock.now();
+        let record = self
+            .shard(id)
+            .get_live(id, now)
+            .map(|value| Arc::clone(&value.data));
+        let counter = match record {
+            Some(_) => &self.counters.hits,
+            None => &self.counters.misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        Ok(record.map(|data| R::clone(&data)))
+    }
+
+    async fn delete(&mut self, id: &Id) -> Result<bool, Self::Error> {
+        let now = self.clock.now();
+        Ok(self.shard(id).remove_live(id, now).is_some())
+    }
+
+    async fn cycle_id(&mut self, old_id: &Id) -> Result<Option<Id>, Self::Error> {
+        let now = self.clock.now();
+        let Some(mut value) = self.shard(old_id).remove_live(old_id, now) else {
+            return Ok(None);
+        };
+        // Cycling the id is an activity, like touching the session.
+        value.expiry_date = self.expiry_date(&value.data, now);
+        loop {
+            let new_id = random_id();
+            let mut shard = self.shard(&new_id);
+            if !shard.entries.contains_key(&new_id) {
+                self.insert(&mut shard, new_id, value, now);
+                return Ok(Some(new_id));
+            }
+        }
+    }
+
+    async fn touch(&mut self, id: &Id) -> Result<bool, Self::Error> {
+        let now = self.clock.now();
+        let mut shard = self.shard(id);
+        let Some(value) = shard.get_live(id, now) else {
+            return Ok(false);
+        };
+        value.expiry_date = self.expiry_date(&value.data, now);
+        Ok(true)
+    }
+
+    async fn load_with_expiry_date(
+        &mut self,
+        id: &Id,
+    ) -> Result<Option<(R, ExpiryDate)>, Self::Error> {
+        let now = self.clock.now();
+        let value = self
+            .shard(id)
+            .get_live(id, now)
+            .map(|value| (Arc::clone(&value.data), value.reported_expiry_date()));
+        let counter = match value {
+            Some(_) => &self.counters.hits,
+            None => &self.counters.misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        Ok(value.map(|(data, expiry_date)| (R::clone(&data), expiry_date)))
+    }
+
+    async fn save_or_create_until(
+        &mut self,
+        id: &Id,
+        record: &R,
+        expiry_date: OffsetDateTime,
+    ) -> Result<bool, Self::Error> {
+        let now = self.clock.now();
+        let mut value = self.value(record, now);
+        value.expire_by(expiry_date);
+        let saved = !value.is_expired(now);
+        self.insert(&mut self.shard(id), *id, value, now);
+        Ok(saved)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tower_sesh_core::{clock::ManualClock, test_util, Expiry};
+
+    use super::*;
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Record(u32);
+
+    impl Expires for Record {}
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Expiring(Expiry);
+
+    impl Expires for Expiring {
+        fn expires(&self) -> Expiry {
+            self.0
+        }
+    }
+
+    #[tokio::test]
+    async fn session_store() {
+        test_util::check_store(|clock| LruStore::new(100).with_clock(clock)).await;
+    }
+
+    #[tokio::test]
+    async fn evicts_least_recently_used() {
+        let mut store: LruStore<Record> = LruStore::new(3).with_shards(1);
+
+        let mut ids = Vec::new();
+        for i in 0..3 {
+            ids.push(store.create(&Record(i)).await.unwrap());
+        }
+        // The first session is used, so the second one is evicted.
+        assert!(store.load(&ids[0]).await.unwrap().is_some());
+        let newest = store.create(&Record(3)).await.unwrap();
+
+        assert_eq!(store.len(), 3);
+        assert_eq!(store.load(&ids[1]).await.unwrap(), None);
+        assert_eq!(store.load(&ids[0]).await.unwrap(), Some(Record(0)));
+        assert_eq!(store.load(&ids[2]).await.unwrap(), Some(Record(2)));
+        assert_eq!(store.load(&newest).await.unwrap(), Some(Record(3)));
+
+        assert_eq!(
+            store.metrics(),
+            LruMetrics {
+                hits: 4,
+                misses: 1,
+                evictions: 1,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn capacity_is_split_between_shards() {
+        let mut store: LruStore<Record> = LruStore::new(100).with_shards(8);
+        assert_eq!(store.capacity(), 104);
+
+        for i in 0..1000 {
+            store.create(&Record(i)).await.unwrap();
+        }
+        assert!(store.len() <= store.capacity());
+        assert_eq!(store.metrics().evictions, 1000 - store.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn ttl() {
+        let clock = ManualClock::new(OffsetDateTime::UNIX_EPOCH);
+        let mut store: LruStore<Record> = LruStore::new(10)
+            .with_ttl(time::Duration::minutes(5))
+            .with_clock(clock.clone());
+
+        let id = store.create(&Record(0)).await.unwrap();
+        clock.advance(time::Duration::minutes(4));
+        assert!(store.touch(&id).await.unwrap());
+        clock.advance(time::Duration::minutes(4));
+        assert!(store.load(&id).await.unwrap().is_some());
+        clock.advance(time::Duration::minutes(1));
+        assert!(store.load(&id).await.unwrap().is_none());
+        assert!(store.is_empty());
+    }
+
+    #[tokio::test]
+    async fn ttl_does_not_extend_expiry() {
+        let clock = ManualClock::new(OffsetDateTime::UNIX_EPOCH);
+        let mut store: LruStore<Expiring> = LruStore::new(10)
+            .with_ttl(time::Duration::hours(1))
+            .with_clock(clock.clone());
+
+        let id = store
+            .create(&Expiring(Expiry::OnInactivity(time::Duration::minutes(1))))
+            .await
+            .unwrap();
+        clock.advance(time::Duration::minutes(1));
+        assert!(store.load(&id).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn caching_frontend() {
+        use tower_sesh_core::session_store::CachingSessionStore;
+
+        use crate::MemoryStore;
+
+        let cache: LruStore<Record> = LruStore::new(1);
+        let mut store = CachingSessionStore::new(cache.clone(), MemoryStore::default());
+
+        let first = store.create(&Record(1)).await.unwrap();
+        let second = store.create(&Record(2)).await.unwrap();
+        assert_eq!(cache.metrics().evictions, 1);
+
+        // The evicted session is loaded from the backend, and cached again.
+        assert_eq!(store.load(&first).await.unwrap(), Some(Record(1)));
+        assert_eq!(store.load(&first).await.unwrap(), Some(Record(1)));
+        assert_eq!(
+            cache.metrics(),
+            LruMetrics {
+                hits: 1,
+                misses: 1,
+                evictions: 2,
+            }
+        );
+        assert_eq!(store.load(&second).await.unwrap(), Some(Record(2)));
+    }
+
+    #[tokio::test]
+    async fn cached_sessions_expire_with_the_backend() {
+        use tower_sesh_core::session_store::CachingSessionStore;
+
+        use crate::MemoryStore;
+
+        let clock = ManualClock::new(OffsetDateTime::UNIX_EPOCH);
+        let cache: LruStore<Expiring> = LruStore::new(10).with_clock(clock.clone());
+        let mut backend = MemoryStore::with_clock(clock.clone());
+        let mut store = CachingSessionStore::new(cache.clone(), backend.clone());
+
+        let record = Expiring(Expiry::OnInactivity(time::Duration::minutes(10)));
+        let id = backend.create(&record).await.unwrap();
+        clock.advance(time::Duration::minutes(9));
+        // Loading the session is not an activity, so the cache keeps the deadline of the backend.
+        assert_eq!(store.load(&id).await.unwrap(), Some(record.clone()));
+        assert_eq!(cache.len(), 1);
+        clock.advance(time::Duration::minutes(6));
+        assert_eq!(backend.load(&id).await.unwrap(), None);
+        assert_eq!(store.load(&id).await.unwrap(), None);
+        assert!(cache.is_empty());
+    }
+
+    #[tokio::test]
+    async fn backends_without_expiry_dates_are_cached() {
+        use tower_sesh_core::session_store::CachingSessionStore;
+
+        use crate::MemoryStore;
+
+        /// A backend using the default `load_with_expiry_date`.
+        #[derive(Debug, Clone, Default)]
+        struct Backend(MemoryStore<Record>);
+
+        impl SessionStore<Record> for Backend {
+            type Error = Infallible;
+
+            async fn create(&mut self, record: &Record) -> Result<Id, Self::Error> {
+                self.0.create(record).await
+            }
+
+            async fn save(&mut self, id: &Id, record: &Record) -> Result<bool, Self::Error> {
+                self.0.save(id, record).await
+            }
+
+            async fn save_or_create(
+                &mut self,
+                id: &Id,
+                record: &Record,
+            ) -> Result<(), Self::Error> {
+                self.0.save_or_create(id, record).await
+            }
+
+            async fn load(&mut self, id: &Id) -> Result<Option<Record>, Self::Error> {
+                self.0.load(id).await
+            }
+
+            async fn delete(&mut self, id: &Id) -> Result<bool, Self::Error> {
+                self.0.delete(id).await
+            }
+        }
+
+        let cache: LruStore<Record> = LruStore::new(10);
+        let mut backend = Backend::default();
+        let mut store = CachingSessionStore::new(cache.clone(), backend.clone());
+
+        let id = backend.create(&Record(1)).await.unwrap();
+        assert_eq!(store.load(&id).await.unwrap(), Some(Record(1)));
+        assert_eq!(cache.len(), 1);
+    }
+}
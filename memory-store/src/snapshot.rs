@@ -0,0 +1,365 @@
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use time::OffsetDateTime;
+use tower_sesh_core::{task::PeriodicTask, Id};
+
+use crate::{MemoryStore, Value};
+
+/// The sessions of a snapshot, as written.
+#[derive(Serialize)]
+struct SnapshotRef<'a, R> {
+    sessions: Vec<SessionRef<'a, R>>,
+}
+
+#[derive(Serialize)]
+struct SessionRef<'a, R> {
+    id: Id,
+    record: &'a R,
+    expiry_date: Option<OffsetDateTime>,
+}
+
+/// The sessions of a snapshot, as read.
+#[derive(Deserialize)]
+struct Snapshot<R> {
+    sessions: Vec<Session<R>>,
+}
+
+#[derive(Deserialize)]
+struct Session<R> {
+    id: Id,
+    record: R,
+    expiry_date: Option<OffsetDateTime>,
+}
+
+impl<R: Serialize> MemoryStore<R> {
+    /// Write the sessions that have not expired to `writer`, with their ids and expiry dates.
+    ///
+    /// The snapshot is written as JSON, and can be read back with
+    /// [`read_snapshot`](MemoryStore::read_snapshot). The records are only serialized once every
+    /// lock is released.
+    pub fn write_snapshot(&self, writer: impl Write) -> io::Result<()> {
+        let now = self.clock.now();
+        let sessions: Vec<(Id, Arc<R>, Option<OffsetDateTime>)> = self
+            .sessions
+            .iter()
+            .flat_map(|shard| {
+                let shard = shard.lock().unwrap();
+                shard
+                    .iter()
+                    .filter(|(_, value)| !value.is_expired(now))
+                    .map(|(id, value)| (*id, Arc::clone(&value.data), value.expiry_date))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+
+        let snapshot = SnapshotRef {
+            sessions: sessions
+                .iter()
+                .map(|(id, record, expiry_date)| SessionRef {
+                    id: *id,
+                    record: &**record,
+                    expiry_date: *expiry_date,
+                })
+                .collect(),
+        };
+        serde_json::to_writer(writer, &snapshot)?;
+        Ok(())
+    }
+
+    /// Write a snapshot of the sessions to the file at `path`.
+    ///
+    /// The snapshot is first written to a file of a unique name next to `path`, then renamed, so
+    /// that the file at `path` always holds a complete snapshot, even if the process stops while
+    /// writing or another snapshot is written to `path` at the same time.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// use tower_sesh_memory_store::MemoryStore;
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let store: MemoryStore<String> = MemoryStore::default();
+    /// store.restore_snapshot("sessions.json")?;
+    /// // ...
+    /// store.save_snapshot("sessions.json")?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn save_snapshot(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let mut partial = OsString::from(path);
+        partial.push(format!(".{:016x}.partial", rand::random::<u64>()));
+
+        let file = File::create(&partial)?;
+        let mut writer = BufWriter::new(file);
+        let result = self
+            .write_snapshot(&mut writer)
+            .and_then(|()| writer.into_inner().map_err(io::IntoInnerError::into_error))
+            .and_then(|file| file.sync_all())
+            .and_then(|()| fs::rename(&partial, path));
+        if result.is_err() {
+            let _ = fs::remove_file(&partial);
+        }
+        result
+    }
+
+    /// Spawn a task on the current tokio runtime that writes a snapshot of the sessions to the
+    /// file at `path` every `interval`.
+    ///
+    /// A last snapshot is written when the task is stopped with [`SnapshotTask::shutdown`], so
+    /// that no session is lost when the application shuts down gracefully. Failures to write
+    /// the periodic snapshots are logged.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside of a tokio runtime, or if `interval` is zero.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// use std::time::Duration;
+    ///
+    /// use tower_sesh_memory_store::MemoryStore;
+    ///
+    /// # #[tokio::main(flavor = "current_thread")]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let store: MemoryStore<String> = MemoryStore::default();
+    /// store.restore_snapshot("sessions.json")?;
+    /// let snapshots = store.spawn_snapshots("sessions.json", Duration::from_secs(60));
+    /// // ...
+    /// snapshots.shutdown().await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn spawn_snapshots(&self, path: impl Into<PathBuf>, interval: Duration) -> SnapshotTask
+    where
+        R: Send + Sync + 'static,
+    {
+        let path = path.into();
+        let clock = self.clock.clone();
+        let task = PeriodicTask::spawn_with_last_tick(
+            &self.sessions,
+            interval,
+            move |sessions, last| {
+                let store = MemoryStore {
+                    sessions,
+                    clock: clock.clone(),
+                };
+                let path = path.clone();
+                async move {
+                    let result = tokio::task::spawn_blocking(move || store.save_snapshot(path))
+                        .await
+                        .expect("writing a snapshot should not panic");
+                    // The error of the last snapshot is returned by `SnapshotTask::shutdown`.
+                    match result {
+                        Err(err) if !last => {
+                            tracing::error!(err = %err, "failed to write a snapshot of the sessions");
+                            Ok(())
+                        }
+                        result => result,
+                    }
+                }
+            },
+        );
+        SnapshotTask(task)
+    }
+}
+
+impl<R: DeserializeOwned> MemoryStore<R> {
+    /// Add the sessions of a snapshot written by [`write_snapshot`](MemoryStore::write_snapshot)
+    /// to the store, returning how many were added.
+    ///
+    /// The sessions keep their ids and expiry dates. Sessions that expired since the snapshot
+    /// was written are skipped, and sessions of the store with the same ids are replaced.
+    pub fn read_snapshot(&self, reader: impl Read) -> io::Result<usize> {
+        let snapshot: Snapshot<R> = serde_json::from_reader(reader)?;
+        let now = self.clock.now();
+
+        let mut restored = 0;
+        for Session {
+            id,
+            record,
+            expiry_date,
+        } in snapshot.sessions
+        {
+            let value = Value {
+                data: Arc::new(record),
+                expiry_date,
+            };
+            if !value.is_expired(now) {
+                self.shard(&id).insert(id, value);
+                restored += 1;
+            }
+        }
+        Ok(restored)
+    }
+
+    /// Add the sessions of the snapshot written by [`save_snapshot`](MemoryStore::save_snapshot)
+    /// at `path` to the store, returning how many were added.
+    ///
+    /// If there is no file at `path`, e.g. when the application starts for the first time, no
+    /// session is added.
+    pub fn restore_snapshot(&self, path: impl AsRef<Path>) -> io::Result<usize> {
+        match File::open(path) {
+            Ok(file) => self.read_snapshot(BufReader::new(file)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(0),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// A task writing snapshots of the sessions of a [`MemoryStore`] periodically.
+///
+/// Created with [`MemoryStore::spawn_snapshots`]. Dropping the task handle stops the task after
+/// it writes a last snapshot, without waiting for it.
+#[derive(Debug)]
+pub struct SnapshotTask(PeriodicTask<io::Result<()>>);
+
+impl SnapshotTask {
+    /// Stop the task, waiting for it to write a last snapshot.
+    ///
+    /// No snapshot is written if every clone of the store was already dropped.
+    pub async fn shutdown(self) -> io::Result<()> {
+        self.0.shutdown().await.unwrap_or(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tower_sesh_core::{clock::ManualClock, Expires, Expiry, SessionStore};
+
+    use super::*;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Record {
+        name: String,
+        expiry: Expiry,
+    }
+
+    impl Expires for Record {
+        fn expires(&self) -> Expiry {
+            self.expiry
+        }
+    }
+
+    fn record(name: &str, expiry: Expiry) -> Record {
+        Record {
+            name: name.to_owned(),
+            expiry,
+        }
+    }
+
+    /// A path in the temporary directory, unique to the test.
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "tower-sesh-snapshot-{}-{name}.json",
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let clock = ManualClock::new(OffsetDateTime::UNIX_EPOCH);
+        let mut store: MemoryStore<Record> = MemoryStore::with_clock(clock.clone());
+        let forever = record("forever", Expiry::OnSessionEnd);
+        let idle = record("idle", Expiry::OnInactivity(time::Duration::minutes(10)));
+        let short = record("short", Expiry::OnInactivity(time::Duration::minutes(1)));
+        let forever_id = store.create(&forever).await.unwrap();
+        let idle_id = store.create(&idle).await.unwrap();
+        store.create(&short).await.unwrap();
+
+        let mut snapshot = Vec::new();
+        store.write_snapshot(&mut snapshot).unwrap();
+
+        // The expiry dates are kept: only the expired sessions are skipped.
+        clock.advance(time::Duration::minutes(5));
+        let mut restored: MemoryStore<Record> = MemoryStore::with_clock(clock.clone());
+        assert_eq!(restored.read_snapshot(&snapshot[..]).unwrap(), 2);
+        assert_eq!(restored.load(&forever_id).await.unwrap(), Some(forever));
+        assert_eq!(restored.load(&idle_id).await.unwrap(), Some(idle));
+
+        clock.advance(time::Duration::minutes(5));
+        assert_eq!(restored.load(&idle_id).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn file() {
+        let path = temp_path("file");
+        let mut store: MemoryStore<Record> = MemoryStore::default();
+        assert_eq!(store.restore_snapshot(&path).unwrap(), 0);
+
+        let session = record("file", Expiry::OnSessionEnd);
+        let id = store.create(&session).await.unwrap();
+        store.save_snapshot(&path).unwrap();
+
+        let mut restored: MemoryStore<Record> = MemoryStore::default();
+        assert_eq!(restored.restore_snapshot(&path).unwrap(), 1);
+        assert_eq!(restored.load(&id).await.unwrap(), Some(session));
+
+        fs::write(&path, "not a snapshot").unwrap();
+        let err = restored.restore_snapshot(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn concurrent_snapshots() {
+        let path = temp_path("concurrent");
+        let store: MemoryStore<Record> = MemoryStore::default();
+
+        // Each snapshot is written to its own temporary file.
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..20 {
+                        store.save_snapshot(&path).unwrap();
+                    }
+                });
+            }
+        });
+        assert_eq!(store.restore_snapshot(&path).unwrap(), 0);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn periodic_snapshots() {
+        let path = temp_path("periodic");
+        let mut store: MemoryStore<Record> = MemoryStore::default();
+        let interval = Duration::from_secs(60);
+        let snapshots = store.spawn_snapshots(&path, interval);
+
+        let first = store
+            .create(&record("first", Expiry::OnSessionEnd))
+            .await
+            .unwrap();
+        // The time is paused, and advanced as the task waits for the next snapshot.
+        while !path.exists() {
+            tokio::time::sleep(interval).await;
+        }
+        let restored: MemoryStore<Record> = MemoryStore::default();
+        assert_eq!(restored.restore_snapshot(&path).unwrap(), 1);
+
+        // The last snapshot is written on shutdown.
+        let second = store
+            .create(&record("second", Expiry::OnSessionEnd))
+            .await
+            .unwrap();
+        snapshots.shutdown().await.unwrap();
+        let mut restored: MemoryStore<Record> = MemoryStore::default();
+        assert_eq!(restored.restore_snapshot(&path).unwrap(), 2);
+        assert!(restored.load(&first).await.unwrap().is_some());
+        assert!(restored.load(&second).await.unwrap().is_some());
+
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,79 @@
+//! The expiry semantics of the `MemoryStore`, and the removal of its expired sessions.
+use time::Duration;
+use tower_sesh_core::{
+    clock::ManualClock,
+    test_util::{self, expiring, record, Record, START},
+    Expiry, Id, SessionStore,
+};
+use tower_sesh_memory_store::MemoryStore;
+
+fn store() -> (MemoryStore<Record>, ManualClock) {
+    let clock = ManualClock::new(START);
+    (MemoryStore::with_clock(clock.clone()), clock)
+}
+
+/// A store with a single session of the given `expiry`, that has just expired.
+async fn expired(expiry: Expiry) -> (MemoryStore<Record>, Id) {
+    let (mut store, clock) = store();
+    let id = store.create(&record(expiry)).await.unwrap();
+    clock.advance(Duration::minutes(10));
+    (store, id)
+}
+
+#[tokio::test]
+async fn session_store() {
+    test_util::check_store(MemoryStore::with_clock).await;
+}
+
+#[tokio::test]
+async fn on_session_end_never_expires() {
+    let (mut store, clock) = store();
+    let id = store.create(&record(Expiry::OnSessionEnd)).await.unwrap();
+
+    clock.advance(Duration::days(10_000));
+    assert!(store.load(&id).await.unwrap().is_some());
+    assert!(store.touch(&id).await.unwrap());
+    assert_eq!(store.purge_expired(), 0);
+}
+
+#[tokio::test]
+async fn expired_sessions_are_purged() {
+    for expiry in expiring() {
+        let (store, _) = expired(expiry).await;
+        assert_eq!(store.purge_expired(), 1, "{expiry:?}");
+    }
+}
+
+#[tokio::test]
+async fn expired_sessions_are_removed_when_used() {
+    for expiry in expiring() {
+        let (mut store, id) = expired(expiry).await;
+        assert_eq!(store.load(&id).await.unwrap(), None, "{expiry:?}");
+        assert_eq!(store.purge_expired(), 0, "{expiry:?}");
+
+        let (mut store, id) = expired(expiry).await;
+        assert!(!store.delete(&id).await.unwrap(), "{expiry:?}");
+        assert_eq!(store.purge_expired(), 0, "{expiry:?}");
+
+        let (mut store, id) = expired(expiry).await;
+        assert_eq!(store.cycle_id(&id).await.unwrap(), None, "{expiry:?}");
+        assert_eq!(store.purge_expired(), 0, "{expiry:?}");
+
+        let (mut store, id) = expired(expiry).await;
+        assert!(!store.touch(&id).await.unwrap(), "{expiry:?}");
+        assert_eq!(store.purge_expired(), 0, "{expiry:?}");
+    }
+}
+
+#[tokio::test]
+async fn expired_sessions_are_reinstated() {
+    let (mut store, clock) = store();
+    let expiry = Expiry::OnInactivity(Duration::minutes(10));
+    let id = store.create(&record(expiry)).await.unwrap();
+    clock.advance(Duration::minutes(10));
+
+    // `save_or_create` replaces the expired session with the live record.
+    store.save_or_create(&id, &record(expiry)).await.unwrap();
+    assert_eq!(store.load(&id).await.unwrap(), Some(record(expiry)));
+    assert_eq!(store.purge_expired(), 0);
+}
@@ -0,0 +1,474 @@
+//! A [`SessionStore`] that persists the sessions in an embedded [`redb`] database.
+//!
+//! `redb` is written in pure Rust, which makes this store convenient for desktop applications
+//! and edge deployments, where no database server is available.
+//!
+//! The records are serialized as JSON with [`serde`]. Expired sessions are never returned. The
+//! sessions that expire are indexed by expiry date, so that [`RedbStore::delete_expired`], or
+//! periodically a [`CleanupTask`], removes them without reading the other sessions.
+//!
+//! Every operation runs in a single transaction, so that a crash can't leave the database in
+//! an intermediate state. In particular, [`cycle_id`](SessionStore::cycle_id) either moves the
+//! record to its new id, or leaves it untouched.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use serde::{Deserialize, Serialize};
+//! use tower_sesh_core::Expires;
+//! use tower_sesh_redb_store::RedbStore;
+//!
+//! #[derive(Clone, Serialize, Deserialize)]
+//! struct User {
+//!     name: String,
+//! }
+//!
+//! impl Expires for User {}
+//!
+//! # fn main() -> Result<(), tower_sesh_redb_store::Error> {
+//! // `RedbStore::open("sessions.redb")` persists the sessions to a file.
+//! let store: RedbStore<User> = RedbStore::in_memory()?;
+//! # Ok(())
+//! # }
+//! ```
+use std::{
+    error::Error as StdError,
+    fmt::{self, Debug, Display},
+    marker::PhantomData,
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+
+use redb::{
+    backends::InMemoryBackend, AccessGuard, Database, ReadableTable, Table, TableDefinition,
+    WriteTransaction,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use time::OffsetDateTime;
+use tower_sesh_core::{
+    clock::{Clock, SystemClock},
+    session_store::ExpiryDate,
+    task::{blocking, Cancelled},
+    Expires, Expiry, Id, SessionStore,
+};
+
+pub use tower_sesh_core::task::CleanupTask;
+
+/// The `expires_at` timestamp, serialized expiry and serialized record of a session.
+type Columns = (Option<i64>, &'static [u8], &'static [u8]);
+
+/// The sessions by id.
+const SESSIONS: TableDefinition<u128, Columns> = TableDefinition::new("tower_sesh_sessions");
+
+/// The ids of the sessions that expire, by `expires_at` timestamp.
+const EXPIRIES: TableDefinition<(i64, u128), ()> = TableDefinition::new("tower_sesh_expiries");
+
+/// A session store backed by a [`redb`] database.
+///
+/// The database is accessed on tokio's blocking threads, so the store must be used within a
+/// tokio runtime.
+#[derive(Debug)]
+pub struct RedbStore<R> {
+    database: Arc<Database>,
+    clock: Arc<dyn Clock>,
+    _record: PhantomData<fn() -> R>,
+}
+
+impl<R> RedbStore<R> {
+    /// Create a new `RedbStore` using `database`, creating the tables if needed.
+    pub fn new(database: Database) -> Result<Self, Error> {
+        let transaction = database.begin_write()?;
+        Tables::open(&transaction)?;
+        transaction.commit()?;
+        Ok(RedbStore {
+            database: Arc::new(database),
+            clock: Arc::new(SystemClock),
+            _record: PhantomData,
+        })
+    }
+
+    /// Create a new `RedbStore` using the database at `path`, which is created if it does not
+    /// exist.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Self::new(Database::create(path)?)
+    }
+
+    /// Create a new `RedbStore` using a new in-memory database, mostly useful for tests.
+    ///
+    /// The database is shared by the clones of the store, and dropped with the last one.
+    pub fn in_memory() -> Result<Self, Error> {
+        Self::new(Database::builder().create_with_backend(InMemoryBackend::new())?)
+    }
+
+    /// Use `clock` to compute the expiry of the sessions.
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Remove every expired session from the database, returning how many were removed.
+    pub async fn delete_expired(&self) -> Result<usize, Error> {
+        delete_expired(self.database.clone(), self.clock.now()).await
+    }
+
+    /// Spawn a [`CleanupTask`] on the current tokio runtime that removes the expired sessions
+    /// with [`delete_expired`](RedbStore::delete_expired) every `interval`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside of a tokio runtime, or if `interval` is zero.
+    pub fn spawn_cleanup(&self, interval: Duration) -> CleanupTask {
+        let clock = self.clock.clone();
+        CleanupTask::spawn(&self.database, interval, move |database| {
+            delete_expired(database, clock.now())
+        })
+    }
+}
+
+impl<R> Clone for RedbStore<R> {
+    fn clone(&self) -> Self {
+        RedbStore {
+            database: self.database.clone(),
+            clock: self.clock.clone(),
+            _record: PhantomData,
+        }
+    }
+}
+
+/// An error of a [`RedbStore`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// The database returned an error.
+    Redb(Box<redb::Error>),
+    /// A record could not be serialized or deserialized.
+    Serde(serde_json::Error),
+    /// The operation was cancelled before it ran, because the runtime is shutting down.
+    Cancelled,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Redb(_) => f.write_str("the session database failed"),
+            Error::Serde(_) => f.write_str("failed to serialize or deserialize a session record"),
+            Error::Cancelled => f.write_str("the session operation was cancelled"),
+        }
+    }
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            Error::Redb(err) => Some(&**err),
+            Error::Serde(err) => Some(err),
+            Error::Cancelled => None,
+        }
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        Error::Serde(err)
+    }
+}
+
+impl From<Cancelled> for Error {
+    fn from(Cancelled: Cancelled) -> Self {
+        Error::Cancelled
+    }
+}
+
+/// Convert the errors of the different `redb` operations to [`Error::Redb`].
+macro_rules! from_redb {
+    ($($err:ty),+) => {
+        $(
+            impl From<$err> for Error {
+                fn from(err: $err) -> Self {
+                    Error::Redb(Box::new(err.into()))
+                }
+            }
+        )+
+    };
+}
+from_redb!(
+    redb::Error,
+    redb::DatabaseError,
+    redb::TransactionError,
+    redb::TableError,
+    redb::StorageError,
+    redb::CommitError
+);
+
+/// A session as stored in the database.
+struct Entry {
+    /// The number of milliseconds between the Unix epoch and the expiry of the session.
+    expires_at: Option<i64>,
+    expiry: Vec<u8>,
+    record: Vec<u8>,
+}
+
+impl Entry {
+    fn new<R: Serialize + Expires>(record: &R, now: OffsetDateTime) -> Result<Self, Error> {
+        let expiry = record.expires();
+        Ok(Entry {
+            expires_at: expiry.deadline(now).map(timestamp),
+            expiry: serde_json::to_vec(&expiry)?,
+            record: serde_json::to_vec(record)?,
+        })
+    }
+
+    fn read(guard: AccessGuard<'_, Columns>) -> Self {
+        let (expires_at, expiry, record) = guard.value();
+        Entry {
+            expires_at,
+            expiry: expiry.to_vec(),
+            record: record.to_vec(),
+        }
+    }
+
+    /// Whether the session expired at `now`, a timestamp.
+    fn is_expired(&self, now: i64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    /// Renew the expiry of the session, as if it was saved at `now`.
+    fn refresh(&mut self, now: OffsetDateTime) -> Result<(), Error> {
+        let expiry: Expiry = serde_json::from_slice(&self.expiry)?;
+        self.expires_at = expiry.deadline(now).map(timestamp);
+        Ok(())
+    }
+}
+
+/// The tables of a write transaction.
+struct Tables<'txn> {
+    sessions: Table<'txn, u128, Columns>,
+    expiries: Table<'txn, (i64, u128), ()>,
+}
+
+impl<'txn> Tables<'txn> {
+    fn open(transaction: &'txn WriteTransaction) -> Result<Self, Error> {
+        Ok(Tables {
+            sessions: transaction.open_table(SESSIONS)?,
+            expiries: transaction.open_table(EXPIRIES)?,
+        })
+    }
+
+    /// The session `id`, if it had not expired at `now`.
+    fn get_live(&self, id: u128, now: i64) -> Result<Option<Entry>, Error> {
+        let entry = self.sessions.get(id)?.map(Entry::read);
+        Ok(entry.filter(|entry| !entry.is_expired(now)))
+    }
+
+    /// Insert the session `id`, replacing the previous one.
+    fn insert(&mut self, id: u128, entry: &Entry) -> Result<(), Error> {
+        self.remove(id)?;
+        self.sessions
+            .insert(id, (entry.expires_at, &*entry.expiry, &*entry.record))?;
+        if let Some(expires_at) = entry.expires_at {
+            self.expiries.insert((expires_at, id), ())?;
+        }
+        Ok(())
+    }
+
+    /// Remove the session `id`, returning it if it existed.
+    fn remove(&mut self, id: u128) -> Result<Option<Entry>, Error> {
+        let entry = self.sessions.remove(id)?.map(Entry::read);
+        if let Some(expires_at) = entry.as_ref().and_then(|entry| entry.expires_at) {
+            self.expiries.remove((expires_at, id))?;
+        }
+        Ok(entry)
+    }
+
+    /// A random id that is not used by a session that had not expired at `now`.
+    fn unused_id(&self, now: i64) -> Result<u128, Error> {
+        loop {
+            let id = random_id();
+            if self.get_live(id, now)?.is_none() {
+                return Ok(id);
+            }
+        }
+    }
+}
+
+impl<R> SessionStore<R> for RedbStore<R>
+where
+    R: Expires + Serialize + DeserializeOwned + Send + Sync,
+{
+    type Error = Error;
+
+    async fn create(&mut self, record: &R) -> Result<Id, Self::Error> {
+        let now = self.clock.now();
+        let entry = Entry::new(record, now)?;
+        let now = timestamp(now);
+        // A record that is already expired is not inserted, as it could never be loaded.
+        if entry.is_expired(now) {
+            return Ok(Id(random_id()));
+        }
+        write(self.database.clone(), move |tables| {
+            let id = tables.unused_id(now)?;
+            tables.insert(id, &entry)?;
+            Ok(Id(id))
+        })
+        .await
+    }
+
+    async fn save(&mut self, id: &Id, record: &R) -> Result<bool, Self::Error> {
+        let now = self.clock.now();
+        let entry = Entry::new(record, now)?;
+        let (id, now) = (id.0, timestamp(now));
+        write(self.database.clone(), move |tables| {
+            if tables.get_live(id, now)?.is_none() {
+                return Ok(false);
+            }
+            if entry.is_expired(now) {
+                tables.remove(id)?;
+            } else {
+                tables.insert(id, &entry)?;
+            }
+            Ok(true)
+        })
+        .await
+    }
+
+    async fn save_or_create(&mut self, id: &Id, record: &R) -> Result<(), Self::Error> {
+        let now = self.clock.now();
+        let entry = Entry::new(record, now)?;
+        let (id, now) = (id.0, timestamp(now));
+        write(self.database.clone(), move |tables| {
+            if entry.is_expired(now) {
+                tables.remove(id)?;
+            } else {
+                tables.insert(id, &entry)?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn load(&mut self, id: &Id) -> Result<Option<R>, Self::Error> {
+        let session = self.load_with_expiry_date(id).await?;
+        Ok(session.map(|(record, _)| record))
+    }
+
+    async fn delete(&mut self, id: &Id) -> Result<bool, Self::Error> {
+        let (id, now) = (id.0, timestamp(self.clock.now()));
+        write(self.database.clone(), move |tables| {
+            if tables.get_live(id, now)?.is_none() {
+                return Ok(false);
+            }
+            tables.remove(id)?;
+            Ok(true)
+        })
+        .await
+    }
+
+    async fn cycle_id(&mut self, old_id: &Id) -> Result<Option<Id>, Self::Error> {
+        let now = self.clock.now();
+        let old_id = old_id.0;
+        // Removing the record and inserting it with its new id are committed together.
+        write(self.database.clone(), move |tables| {
+            let Some(mut entry) = tables.get_live(old_id, timestamp(now))? else {
+                return Ok(None);
+            };
+            // Cycling the id is an activity, like touching the session.
+            entry.refresh(now)?;
+            tables.remove(old_id)?;
+            let new_id = tables.unused_id(timestamp(now))?;
+            tables.insert(new_id, &entry)?;
+            Ok(Some(Id(new_id)))
+        })
+        .await
+    }
+
+    async fn touch(&mut self, id: &Id) -> Result<bool, Self::Error> {
+        let now = self.clock.now();
+        let id = id.0;
+        write(self.database.clone(), move |tables| {
+            let Some(mut entry) = tables.get_live(id, timestamp(now))? else {
+                return Ok(false);
+            };
+            entry.refresh(now)?;
+            tables.insert(id, &entry)?;
+            Ok(true)
+        })
+        .await
+    }
+
+    async fn load_with_expiry_date(
+        &mut self,
+        id: &Id,
+    ) -> Result<Option<(R, ExpiryDate)>, Self::Error> {
+        let (id, now) = (id.0, timestamp(self.clock.now()));
+        let database = self.database.clone();
+        let entry = blocking(move || -> Result<_, Error> {
+            let transaction = database.begin_read()?;
+            let sessions = transaction.open_table(SESSIONS)?;
+            let entry = sessions.get(id)?.map(Entry::read);
+            Ok(entry.filter(|entry| !entry.is_expired(now)))
+        })
+        .await?;
+        let Some(entry) = entry else {
+            return Ok(None);
+        };
+        let expiry_date = match entry.expires_at {
+            Some(expires_at) => ExpiryDate::At(date(expires_at)?),
+            None => ExpiryDate::Never,
+        };
+        // The record is deserialized once the transaction is over.
+        Ok(Some((serde_json::from_slice(&entry.record)?, expiry_date)))
+    }
+}
+
+/// Run `f` in a write transaction on a blocking thread, and commit the transaction if `f`
+/// succeeds.
+async fn write<T, F>(database: Arc<Database>, f: F) -> Result<T, Error>
+where
+    T: Send + 'static,
+    F: FnOnce(&mut Tables<'_>) -> Result<T, Error> + Send + 'static,
+{
+    blocking(move || {
+        let transaction = database.begin_write()?;
+        let output = f(&mut Tables::open(&transaction)?)?;
+        transaction.commit()?;
+        Ok(output)
+    })
+    .await
+}
+
+/// Remove the sessions that expired at `now`, returning how many were removed.
+async fn delete_expired(database: Arc<Database>, now: OffsetDateTime) -> Result<usize, Error> {
+    let now = timestamp(now);
+    write(database, move |tables| {
+        let mut expired = Vec::new();
+        for key in tables.expiries.range(..=(now, u128::MAX))? {
+            let (key, _) = key?;
+            expired.push(key.value());
+        }
+        for &(expires_at, id) in &expired {
+            tables.expiries.remove((expires_at, id))?;
+            tables.sessions.remove(id)?;
+        }
+        Ok(expired.len())
+    })
+    .await
+}
+
+/// The number of milliseconds between the Unix epoch and `date`, which fits in an `i64` for
+/// every date supported by `time`.
+fn timestamp(date: OffsetDateTime) -> i64 {
+    (date.unix_timestamp_nanos() / 1_000_000) as i64
+}
+
+/// The date `timestamp` milliseconds after the Unix epoch.
+fn date(timestamp: i64) -> Result<OffsetDateTime, Error> {
+    OffsetDateTime::from_unix_timestamp_nanos(i128::from(timestamp) * 1_000_000).map_err(|_| {
+        redb::StorageError::Corrupted(format!("invalid expiry timestamp {timestamp}")).into()
+    })
+}
+
+fn random_id() -> u128 {
+    use rand::prelude::*;
+    rand::thread_rng().gen()
+}
@@ -0,0 +1,50 @@
+//! The `RedbStore`, tested against in-memory databases.
+use time::Duration;
+use tower_sesh_core::{
+    clock::ManualClock,
+    test_util::{self, record, Record, START},
+    Expiry, SessionStore,
+};
+use tower_sesh_redb_store::RedbStore;
+
+fn new_store(clock: ManualClock) -> RedbStore<Record> {
+    RedbStore::in_memory().unwrap().with_clock(clock)
+}
+
+#[tokio::test]
+async fn session_store() {
+    test_util::check_store(new_store).await;
+}
+
+#[tokio::test]
+async fn delete_expired() {
+    test_util::check_delete_expired(
+        new_store,
+        |store| async move { store.delete_expired().await },
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn persistence() {
+    let path =
+        std::env::temp_dir().join(format!("tower-sesh-redb-store-{}.redb", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+
+    let clock = ManualClock::new(START);
+    let mut store: RedbStore<Record> = RedbStore::open(&path).unwrap().with_clock(clock.clone());
+    let session = record(Expiry::OnInactivity(Duration::minutes(10)));
+    let id = store.create(&session).await.unwrap();
+    let new_id = store.cycle_id(&id).await.unwrap().unwrap();
+    drop(store);
+
+    // The new id is committed, along with the expiry index of the session.
+    let mut store: RedbStore<Record> = RedbStore::open(&path).unwrap().with_clock(clock.clone());
+    assert_eq!(store.load(&id).await.unwrap(), None);
+    assert_eq!(store.load(&new_id).await.unwrap(), Some(session));
+    clock.advance(Duration::minutes(10));
+    assert_eq!(store.delete_expired().await.unwrap(), 1);
+    drop(store);
+
+    std::fs::remove_file(&path).unwrap();
+}
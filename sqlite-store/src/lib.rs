@@ -0,0 +1,514 @@
+//! A [`SessionStore`] that persists the sessions in a SQLite database.
+//!
+//! The records are serialized as JSON with [`serde`], and the expiry of the sessions, as given
+//! by [`Expires`], is kept in an indexed `expires_at` column. Expired sessions are never
+//! returned, and are removed by [`SqliteStore::delete_expired`], or periodically by a
+//! [`CleanupTask`].
+//!
+//! The schema is created and migrated when the store is created. It uses a single table named
+//! `tower_sesh_sessions`, so the database can be shared with the rest of the application.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use serde::{Deserialize, Serialize};
+//! use tower_sesh_core::Expires;
+//! use tower_sesh_sqlite_store::SqliteStore;
+//!
+//! #[derive(Clone, Serialize, Deserialize)]
+//! struct User {
+//!     name: String,
+//! }
+//!
+//! impl Expires for User {}
+//!
+//! # fn main() -> Result<(), tower_sesh_sqlite_store::Error> {
+//! // `SqliteStore::open("sessions.db")` persists the sessions to a file.
+//! let store: SqliteStore<User> = SqliteStore::open_in_memory()?;
+//! # Ok(())
+//! # }
+//! ```
+use std::{
+    error::Error as StdError,
+    fmt::{self, Debug, Display},
+    marker::PhantomData,
+    path::Path,
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+
+use rusqlite::{params, Connection, ErrorCode, OptionalExtension};
+use serde::{de::DeserializeOwned, Serialize};
+use time::OffsetDateTime;
+use tower_sesh_core::{
+    clock::{Clock, SystemClock},
+    session_store::ExpiryDate,
+    task::{blocking, Cancelled},
+    Expires, Expiry, Id, SessionStore,
+};
+
+pub use tower_sesh_core::task::CleanupTask;
+
+/// The migrations of the schema, in order.
+///
+/// The `user_version` of the database is the number of migrations that were applied. Applied
+/// migrations must never be changed: add a new one instead.
+const MIGRATIONS: &[&str] = &["
+    CREATE TABLE tower_sesh_sessions (
+        id BLOB PRIMARY KEY NOT NULL,
+        record TEXT NOT NULL,
+        expiry TEXT NOT NULL,
+        expires_at INTEGER
+    ) WITHOUT ROWID;
+    CREATE INDEX tower_sesh_sessions_expires_at ON tower_sesh_sessions (expires_at);
+"];
+
+/// A session store backed by a SQLite database.
+///
+/// The database is accessed on tokio's blocking threads, so the store must be used within a
+/// tokio runtime. A single connection is used, which also allows in-memory databases.
+#[derive(Debug)]
+pub struct SqliteStore<R> {
+    connection: Arc<Mutex<Connection>>,
+    clock: Arc<dyn Clock>,
+    _record: PhantomData<fn() -> R>,
+}
+
+impl<R> SqliteStore<R> {
+    /// Create a new `SqliteStore` using `connection`, creating or migrating the schema if needed.
+    pub fn new(mut connection: Connection) -> Result<Self, Error> {
+        migrate(&mut connection)?;
+        Ok(SqliteStore {
+            connection: Arc::new(Mutex::new(connection)),
+            clock: Arc::new(SystemClock),
+            _record: PhantomData,
+        })
+    }
+
+    /// Create a new `SqliteStore` using the database at `path`, which is created if it does not
+    /// exist.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Self::new(Connection::open(path)?)
+    }
+
+    /// Create a new `SqliteStore` using a new in-memory database.
+    ///
+    /// The sessions are lost when the last clone of the store is dropped, which is mostly useful
+    /// for tests.
+    pub fn open_in_memory() -> Result<Self, Error> {
+        Self::new(Connection::open_in_memory()?)
+    }
+
+    /// Use `clock` to compute the expiry of the sessions.
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Remove every expired session from the database, returning how many were removed.
+    pub async fn delete_expired(&self) -> Result<usize, Error> {
+        delete_expired(self.connection.clone(), self.clock.now()).await
+    }
+
+    /// Spawn a [`CleanupTask`] on the current tokio runtime that removes the expired sessions
+    /// with [`delete_expired`](SqliteStore::delete_expired) every `interval`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside of a tokio runtime, or if `interval` is zero.
+    pub fn spawn_cleanup(&self, interval: Duration) -> CleanupTask {
+        let clock = self.clock.clone();
+        CleanupTask::spawn(&self.connection, interval, move |connection| {
+            delete_expired(connection, clock.now())
+        })
+    }
+}
+
+impl<R> Clone for SqliteStore<R> {
+    fn clone(&self) -> Self {
+        SqliteStore {
+            connection: self.connection.clone(),
+            clock: self.clock.clone(),
+            _record: PhantomData,
+        }
+    }
+}
+
+/// An error of a [`SqliteStore`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// The database returned an error.
+    Sqlite(rusqlite::Error),
+    /// A record could not be serialized or deserialized.
+    Serde(serde_json::Error),
+    /// The schema of the database is more recent than the one of this crate, i.e. the database
+    /// was migrated by a newer version of the store.
+    SchemaVersion(usize),
+    /// The operation was cancelled before it ran, because the runtime is shutting down.
+    Cancelled,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Sqlite(_) => f.write_str("the session database failed"),
+            Error::Serde(_) => f.write_str("failed to serialize or deserialize a session record"),
+            Error::SchemaVersion(version) => write!(
+                f,
+                "the session database has the unknown schema version {version}"
+            ),
+            Error::Cancelled => f.write_str("the session operation was cancelled"),
+        }
+    }
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            Error::Sqlite(err) => Some(err),
+            Error::Serde(err) => Some(err),
+            Error::SchemaVersion(_) | Error::Cancelled => None,
+        }
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(err: rusqlite::Error) -> Self {
+        Error::Sqlite(err)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        Error::Serde(err)
+    }
+}
+
+impl From<Cancelled> for Error {
+    fn from(Cancelled: Cancelled) -> Self {
+        Error::Cancelled
+    }
+}
+
+/// A record ready to be written to the database.
+struct Row {
+    record: String,
+    expiry: String,
+    expires_at: Option<i64>,
+    /// Whether the record is already expired, in which case it must not be written.
+    expired: bool,
+}
+
+impl Row {
+    fn new<R: Serialize + Expires>(record: &R, now: OffsetDateTime) -> Result<Self, Error> {
+        let expiry = record.expires();
+        let deadline = expiry.deadline(now);
+        Ok(Row {
+            record: serde_json::to_string(record)?,
+            expiry: serde_json::to_string(&expiry)?,
+            expires_at: deadline.map(timestamp),
+            expired: deadline.is_some_and(|deadline| deadline <= now),
+        })
+    }
+}
+
+impl<R> SessionStore<R> for SqliteStore<R>
+where
+    R: Expires + Serialize + DeserializeOwned + Send + Sync,
+{
+    type Error = Error;
+
+    async fn create(&mut self, record: &R) -> Result<Id, Self::Error> {
+        let now = self.clock.now();
+        let row = Row::new(record, now)?;
+        // A record that is already expired is not inserted, as it could never be loaded.
+        if row.expired {
+            return Ok(random_id());
+        }
+        with_connection(self.connection.clone(), move |connection| loop {
+            let id = random_id();
+            // An expired session with the same id may still be in the table.
+            let inserted = connection.execute(
+                "INSERT INTO tower_sesh_sessions (id, record, expiry, expires_at)
+                VALUES (?1, ?2, ?3, ?4)
+                ON CONFLICT (id) DO UPDATE SET
+                    record = excluded.record,
+                    expiry = excluded.expiry,
+                    expires_at = excluded.expires_at
+                WHERE expires_at <= ?5",
+                params![
+                    key(&id),
+                    row.record,
+                    row.expiry,
+                    row.expires_at,
+                    timestamp(now)
+                ],
+            )?;
+            if inserted == 1 {
+                return Ok(id);
+            }
+        })
+        .await
+    }
+
+    async fn save(&mut self, id: &Id, record: &R) -> Result<bool, Self::Error> {
+        let now = self.clock.now();
+        let row = Row::new(record, now)?;
+        let id = *id;
+        with_connection(self.connection.clone(), move |connection| {
+            let updated = if row.expired {
+                connection.execute(
+                    "DELETE FROM tower_sesh_sessions
+                    WHERE id = ?1 AND (expires_at IS NULL OR expires_at > ?2)",
+                    params![key(&id), timestamp(now)],
+                )?
+            } else {
+                connection.execute(
+                    "UPDATE tower_sesh_sessions SET record = ?2, expiry = ?3, expires_at = ?4
+                    WHERE id = ?1 AND (expires_at IS NULL OR expires_at > ?5)",
+                    params![
+                        key(&id),
+                        row.record,
+                        row.expiry,
+                        row.expires_at,
+                        timestamp(now)
+                    ],
+                )?
+            };
+            Ok(updated == 1)
+        })
+        .await
+    }
+
+    async fn save_or_create(&mut self, id: &Id, record: &R) -> Result<(), Self::Error> {
+        let row = Row::new(record, self.clock.now())?;
+        let id = *id;
+        with_connection(self.connection.clone(), move |connection| {
+            if row.expired {
+                connection.execute(
+                    "DELETE FROM tower_sesh_sessions WHERE id = ?1",
+                    params![key(&id)],
+                )?;
+            } else {
+                connection.execute(
+                    "INSERT INTO tower_sesh_sessions (id, record, expiry, expires_at)
+                    VALUES (?1, ?2, ?3, ?4)
+                    ON CONFLICT (id) DO UPDATE SET
+                        record = excluded.record,
+                        expiry = excluded.expiry,
+                        expires_at = excluded.expires_at",
+                    params![key(&id), row.record, row.expiry, row.expires_at],
+                )?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn load(&mut self, id: &Id) -> Result<Option<R>, Self::Error> {
+        let session = self.load_with_expiry_date(id).await?;
+        Ok(session.map(|(record, _)| record))
+    }
+
+    async fn delete(&mut self, id: &Id) -> Result<bool, Self::Error> {
+        let now = self.clock.now();
+        let id = *id;
+        with_connection(self.connection.clone(), move |connection| {
+            let deleted = connection.execute(
+                "DELETE FROM tower_sesh_sessions
+                WHERE id = ?1 AND (expires_at IS NULL OR expires_at > ?2)",
+                params![key(&id), timestamp(now)],
+            )?;
+            Ok(deleted == 1)
+        })
+        .await
+    }
+
+    /// Move the session to a new id, and refresh its expiry.
+    ///
+    /// This takes two statements, in a single transaction so that no other operation on the
+    /// session runs in between: a `SELECT` of the expiry of the session, then an `UPDATE` of its
+    /// id and `expires_at`. The new `expires_at` depends on the [`Expiry`] of the record, which
+    /// is stored as JSON and computed with [`Expiry::deadline`], so it cannot be computed by a
+    /// single `UPDATE`. The `UPDATE` is retried with another id if the new id is taken.
+    async fn cycle_id(&mut self, old_id: &Id) -> Result<Option<Id>, Self::Error> {
+        let now = self.clock.now();
+        let old_id = *old_id;
+        with_connection(self.connection.clone(), move |connection| {
+            let transaction = connection.transaction()?;
+            let Some(expiry) = live_expiry(&transaction, &old_id, now)? else {
+                return Ok(None);
+            };
+            // Cycling the id is an activity, like touching the session.
+            let expires_at = expiry.deadline(now).map(timestamp);
+            let new_id = loop {
+                let new_id = random_id();
+                let updated = transaction.execute(
+                    "UPDATE tower_sesh_sessions SET id = ?2, expires_at = ?3 WHERE id = ?1",
+                    params![key(&old_id), key(&new_id), expires_at],
+                );
+                match updated {
+                    Ok(_) => break new_id,
+                    // Another session, possibly expired, already has the new id.
+                    Err(rusqlite::Error::SqliteFailure(err, _))
+                        if err.code == ErrorCode::ConstraintViolation => {}
+                    Err(err) => return Err(err.into()),
+                }
+            };
+            transaction.commit()?;
+            Ok(Some(new_id))
+        })
+        .await
+    }
+
+    async fn touch(&mut self, id: &Id) -> Result<bool, Self::Error> {
+        let now = self.clock.now();
+        let id = *id;
+        with_connection(self.connection.clone(), move |connection| {
+            let transaction = connection.transaction()?;
+            let Some(expiry) = live_expiry(&transaction, &id, now)? else {
+                return Ok(false);
+            };
+            transaction.execute(
+                "UPDATE tower_sesh_sessions SET expires_at = ?2 WHERE id = ?1",
+                params![key(&id), expiry.deadline(now).map(timestamp)],
+            )?;
+            transaction.commit()?;
+            Ok(true)
+        })
+        .await
+    }
+
+    async fn load_with_expiry_date(
+        &mut self,
+        id: &Id,
+    ) -> Result<Option<(R, ExpiryDate)>, Self::Error> {
+        let now = self.clock.now();
+        let id = *id;
+        let session = with_connection(self.connection.clone(), move |connection| {
+            let session = connection
+                .query_row(
+                    "SELECT record, expires_at FROM tower_sesh_sessions
+                    WHERE id = ?1 AND (expires_at IS NULL OR expires_at > ?2)",
+                    params![key(&id), timestamp(now)],
+                    |row| Ok((row.get::<_, String>(0)?, row.get::<_, Option<i64>>(1)?)),
+                )
+                .optional()?;
+            Ok(session)
+        })
+        .await?;
+        let Some((record, expires_at)) = session else {
+            return Ok(None);
+        };
+        let expiry_date = match expires_at {
+            Some(expires_at) => ExpiryDate::At(date(expires_at)?),
+            None => ExpiryDate::Never,
+        };
+        // The record is deserialized once the connection is released.
+        Ok(Some((serde_json::from_str(&record)?, expiry_date)))
+    }
+}
+
+/// The expiry of the session `id`, if it had not expired at `now`.
+fn live_expiry(
+    connection: &Connection,
+    id: &Id,
+    now: OffsetDateTime,
+) -> Result<Option<Expiry>, Error> {
+    let expiry = connection
+        .query_row(
+            "SELECT expiry FROM tower_sesh_sessions
+            WHERE id = ?1 AND (expires_at IS NULL OR expires_at > ?2)",
+            params![key(id), timestamp(now)],
+            |row| row.get::<_, String>(0),
+        )
+        .optional()?;
+    Ok(expiry
+        .map(|expiry| serde_json::from_str(&expiry))
+        .transpose()?)
+}
+
+/// Create or migrate the schema of the database to the latest version.
+fn migrate(connection: &mut Connection) -> Result<(), Error> {
+    let transaction = connection.transaction()?;
+    let version: usize = transaction.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    if version > MIGRATIONS.len() {
+        return Err(Error::SchemaVersion(version));
+    }
+    for migration in &MIGRATIONS[version..] {
+        transaction.execute_batch(migration)?;
+    }
+    transaction.pragma_update(None, "user_version", MIGRATIONS.len())?;
+    transaction.commit()?;
+    Ok(())
+}
+
+/// Run `f` with the connection, on a blocking thread.
+async fn with_connection<T, F>(connection: Arc<Mutex<Connection>>, f: F) -> Result<T, Error>
+where
+    T: Send + 'static,
+    F: FnOnce(&mut Connection) -> Result<T, Error> + Send + 'static,
+{
+    blocking(move || {
+        // An operation that panicked rolled its transaction back while unwinding, so the
+        // connection is still usable.
+        let mut connection = connection.lock().unwrap_or_else(PoisonError::into_inner);
+        f(&mut connection)
+    })
+    .await
+}
+
+/// Remove the sessions that expired at `now`, returning how many were removed.
+async fn delete_expired(
+    connection: Arc<Mutex<Connection>>,
+    now: OffsetDateTime,
+) -> Result<usize, Error> {
+    with_connection(connection, move |connection| {
+        let deleted = connection.execute(
+            "DELETE FROM tower_sesh_sessions WHERE expires_at <= ?1",
+            params![timestamp(now)],
+        )?;
+        Ok(deleted)
+    })
+    .await
+}
+
+/// The key of the session `id` in the database.
+fn key(id: &Id) -> [u8; 16] {
+    id.0.to_be_bytes()
+}
+
+/// The number of milliseconds between the Unix epoch and `date`, as stored in `expires_at`.
+fn timestamp(date: OffsetDateTime) -> i64 {
+    // Dates supported by `time` are within a few thousand years of the epoch, so this can't
+    // overflow.
+    (date.unix_timestamp_nanos() / 1_000_000) as i64
+}
+
+/// The date `timestamp` milliseconds after the Unix epoch, as stored in `expires_at`.
+fn date(timestamp: i64) -> Result<OffsetDateTime, Error> {
+    OffsetDateTime::from_unix_timestamp_nanos(i128::from(timestamp) * 1_000_000)
+        .map_err(|_| Error::Sqlite(rusqlite::Error::IntegralValueOutOfRange(1, timestamp)))
+}
+
+fn random_id() -> Id {
+    use rand::prelude::*;
+    Id(rand::thread_rng().gen())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn panics_do_not_poison_the_connection() {
+        let store: SqliteStore<()> = SqliteStore::open_in_memory().unwrap();
+        let operation = with_connection(store.connection.clone(), |_| -> Result<(), Error> {
+            panic!("the operation failed")
+        });
+        assert!(tokio::spawn(operation).await.unwrap_err().is_panic());
+        assert!(store.connection.is_poisoned());
+        assert_eq!(store.delete_expired().await.unwrap(), 0);
+    }
+}
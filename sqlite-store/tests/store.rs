@@ -0,0 +1,56 @@
+//! The `SqliteStore`, tested against in-memory databases.
+use rusqlite::Connection;
+use tower_sesh_core::{
+    clock::ManualClock,
+    test_util::{self, record, Record},
+    Expiry, SessionStore,
+};
+use tower_sesh_sqlite_store::{Error, SqliteStore};
+
+fn new_store(clock: ManualClock) -> SqliteStore<Record> {
+    SqliteStore::open_in_memory().unwrap().with_clock(clock)
+}
+
+#[tokio::test]
+async fn session_store() {
+    test_util::check_store(new_store).await;
+}
+
+#[tokio::test]
+async fn delete_expired() {
+    test_util::check_delete_expired(
+        new_store,
+        |store| async move { store.delete_expired().await },
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn migrations() {
+    let path =
+        std::env::temp_dir().join(format!("tower-sesh-sqlite-store-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+
+    let mut store: SqliteStore<Record> = SqliteStore::open(&path).unwrap();
+    let session = record(Expiry::OnSessionEnd);
+    let id = store.create(&session).await.unwrap();
+    drop(store);
+
+    // Opening a migrated database keeps its sessions.
+    let mut store: SqliteStore<Record> = SqliteStore::open(&path).unwrap();
+    assert_eq!(store.load(&id).await.unwrap(), Some(session));
+    drop(store);
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn unknown_schema_version() {
+    let connection = Connection::open_in_memory().unwrap();
+    connection
+        .pragma_update(None, "user_version", 1_000)
+        .unwrap();
+
+    let result: Result<SqliteStore<Record>, _> = SqliteStore::new(connection);
+    assert!(matches!(result, Err(Error::SchemaVersion(1_000))));
+}
@@ -0,0 +1,867 @@
+//! A session store that keeps the whole session record in the cookie.
+//!
+//! With a [`CookieStore`], the server holds no session state at all: the record is serialized,
+//! encrypted with AES-256-GCM, and sent back to the browser in the session cookie. This is useful
+//! for stateless services where running a store is overkill.
+//!
+//! Handlers use the same [`Session`] API as with any other store. The only difference is that the
+//! [`CookieSessionManagerLayer`] must be used instead of the [`SessionManagerLayer`], since the
+//! record has to be read from and written to the cookie by the middleware.
+//!
+//! # Caveats
+//!
+//! - Browsers only keep cookies up to [`MAX_COOKIE_SIZE`] bytes, including the cookie name. Larger
+//!   records can be split across several cookies with [`Config::max_chunks`]. Saving a record that
+//!   does not fit returns a [`CookieStoreError::TooLarge`] error.
+//! - Sessions cannot be revoked by the server. Deleting a session only asks the browser to forget
+//!   the cookie, and a copy of the cookie stays valid until the record expires. Records that
+//!   [never expire][crate::Expiry::OnSessionEnd] stay valid for as long as the keys are in use.
+//!
+//! [`Session`]: crate::Session
+//! [`SessionManagerLayer`]: crate::SessionManagerLayer
+use std::{
+    fmt::{self, Debug, Display},
+    marker::PhantomData,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use http::{request::Parts, Request, Response};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use time::OffsetDateTime;
+use tower_layer::Layer;
+use tower_service::Service;
+use tower_sesh_core::{
+    clock::{Clock, SystemClock},
+    Expires, Id, SessionStore,
+};
+use tracing::{instrument::Instrumented, Instrument};
+
+use crate::{
+    middleware::{Config, ConfigSource, CookieValue, ResponseFuture},
+    protection::{Cipher, KeyRing},
+    transport::{self, Carrier, Transport},
+    warn::{suspicious, WarnLimit},
+    Session,
+};
+
+#[doc(no_inline)]
+pub use crate::middleware::MAX_COOKIE_SIZE;
+
+/// A session store that keeps the session record in the session cookie.
+///
+/// This store is created by the [`CookieSessionManagerLayer`] for every request. It only ever
+/// holds the session of the current request. See the [module documentation][self] for more
+/// information.
+///
+/// # Examples
+///
+/// ```rust
+/// use serde::{Deserialize, Serialize};
+/// use tower_sesh::{CookieStore, Expires, Session};
+///
+/// #[derive(Clone, Serialize, Deserialize)]
+/// struct Cart {
+///     items: Vec<u64>,
+/// }
+///
+/// impl Expires for Cart {}
+///
+/// async fn handler(session: Session<CookieStore<Cart>>) -> String {
+///     match session.load().await {
+///         Ok(Some(cart)) => format!("{} items in the cart", cart.data().items.len()),
+///         Ok(None) => "The cart is empty".to_string(),
+///         Err(_error) => "An error occurred while loading the cart".to_string(),
+///     }
+/// }
+/// ```
+pub struct CookieStore<R> {
+    slot: Arc<Mutex<Option<Encoded>>>,
+    config: Arc<Config>,
+    clock: Arc<dyn Clock>,
+    _record: PhantomData<fn() -> R>,
+}
+
+impl<R> Clone for CookieStore<R> {
+    fn clone(&self) -> Self {
+        Self {
+            slot: Arc::clone(&self.slot),
+            config: Arc::clone(&self.config),
+            clock: Arc::clone(&self.clock),
+            _record: PhantomData,
+        }
+    }
+}
+
+impl<R> Debug for CookieStore<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CookieStore")
+            .field("slot", &self.slot)
+            .field("config", &self.config)
+            .field("clock", &self.clock)
+            .finish()
+    }
+}
+
+/// The error returned by the [`CookieStore`].
+#[derive(Debug)]
+pub enum CookieStoreError {
+    /// The record does not fit in the session cookie once serialized and encrypted.
+    TooLarge {
+        /// The length of the cookie value.
+        size: usize,
+        /// The maximum length of the cookie value, accounting for [`Config::max_chunks`].
+        limit: usize,
+    },
+    /// The record could not be serialized.
+    Serialize(serde_json::Error),
+}
+
+impl Display for CookieStoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CookieStoreError::TooLarge { size, limit } => write!(
+                f,
+                "the session cookie value would be {size} bytes long, which exceeds the limit of \
+                 {limit} bytes"
+            ),
+            CookieStoreError::Serialize(err) => {
+                write!(f, "failed to serialize the session record: {err}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for CookieStoreError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CookieStoreError::TooLarge { .. } => None,
+            CookieStoreError::Serialize(err) => Some(err),
+        }
+    }
+}
+
+/// The serialized session held by a [`CookieStore`].
+#[derive(Debug, Clone)]
+struct Encoded {
+    id: Id,
+    expires_at: Option<i64>,
+    /// The JSON serialization of a [`Payload`].
+    plaintext: Vec<u8>,
+}
+
+impl Encoded {
+    fn is_expired(&self, now: OffsetDateTime) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at <= now.unix_timestamp())
+    }
+}
+
+/// What is encrypted in the cookie.
+#[derive(Serialize, Deserialize)]
+struct Payload<R> {
+    id: Id,
+    /// The expiration date, as a UNIX timestamp.
+    expires_at: Option<i64>,
+    data: R,
+}
+
+/// A [`Payload`] without its data, to read the metadata of a record of unknown type.
+#[derive(Deserialize)]
+struct Header {
+    id: Id,
+    expires_at: Option<i64>,
+}
+
+impl<R> CookieStore<R> {
+    fn new(config: Arc<Config>, clock: Arc<dyn Clock>, encoded: Option<Encoded>) -> Self {
+        Self {
+            slot: Arc::new(Mutex::new(encoded)),
+            config,
+            clock,
+            _record: PhantomData,
+        }
+    }
+}
+
+impl<R> CookieStore<R>
+where
+    R: Expires + Serialize,
+{
+    fn encode(&self, id: Id, record: &R) -> Result<Encoded, CookieStoreError> {
+        let expires_at = record
+            .expires()
+            .deadline(self.clock.now())
+            .map(OffsetDateTime::unix_timestamp);
+        let plaintext = serde_json::to_vec(&Payload {
+            id,
+            expires_at,
+            data: record,
+        })
+        .map_err(CookieStoreError::Serialize)?;
+
+        let size = Cipher::encrypted_len(plaintext.len());
+        let limit = self.config.max_value_len();
+        if size > limit {
+            return Err(CookieStoreError::TooLarge { size, limit });
+        }
+
+        Ok(Encoded {
+            id,
+            expires_at,
+            plaintext,
+        })
+    }
+
+    /// Replace the current session, if it is `id` and has not expired.
+    fn replace(&self, id: &Id, record: &R) -> Result<bool, CookieStoreError> {
+        let mut slot = self.slot.lock().expect("lock should not be poisoned");
+        if !slot
+            .as_ref()
+            .is_some_and(|encoded| encoded.id == *id && !encoded.is_expired(self.clock.now()))
+        {
+            return Ok(false);
+        }
+        *slot = Some(self.encode(*id, record)?);
+        Ok(true)
+    }
+}
+
+impl<R> SessionStore<R> for CookieStore<R>
+where
+    R: Expires + Serialize + DeserializeOwned + Send + Sync,
+{
+    type Error = CookieStoreError;
+
+    async fn create(&mut self, record: &R) -> Result<Id, Self::Error> {
+        let encoded = self.encode(random_id(), record)?;
+        let id = encoded.id;
+        *self.slot.lock().expect("lock should not be poisoned") = Some(encoded);
+        Ok(id)
+    }
+
+    async fn save(&mut self, id: &Id, record: &R) -> Result<bool, Self::Error> {
+        self.replace(id, record)
+    }
+
+    async fn save_or_create(&mut self, id: &Id, record: &R) -> Result<(), Self::Error> {
+        let encoded = self.encode(*id, record)?;
+        *self.slot.lock().expect("lock should not be poisoned") = Some(encoded);
+        Ok(())
+    }
+
+    async fn load(&mut self, id: &Id) -> Result<Option<R>, Self::Error> {
+        let slot = self.slot.lock().expect("lock should not be poisoned");
+        let Some(encoded) = slot
+            .as_ref()
+            .filter(|encoded| encoded.id == *id && !encoded.is_expired(self.clock.now()))
+        else {
+            return Ok(None);
+        };
+
+        Ok(
+            match serde_json::from_slice::<Payload<R>>(&encoded.plaintext) {
+                Ok(payload) => Some(payload.data),
+                Err(err) => {
+                    // The cookie was encrypted by us, so the record type most likely changed.
+                    tracing::warn!(err = %err, "failed to deserialize the session record");
+                    None
+                }
+            },
+        )
+    }
+
+    async fn delete(&mut self, id: &Id) -> Result<bool, Self::Error> {
+        let mut slot = self.slot.lock().expect("lock should not be poisoned");
+        Ok(slot
+            .take_if(|encoded| encoded.id == *id)
+            .is_some_and(|encoded| !encoded.is_expired(self.clock.now())))
+    }
+
+    async fn cycle_id(&mut self, old_id: &Id) -> Result<Option<Id>, Self::Error> {
+        let Some(record) = self.load(old_id).await? else {
+            return Ok(None);
+        };
+        let encoded = self.encode(random_id(), &record)?;
+        let new_id = encoded.id;
+        *self.slot.lock().expect("lock should not be poisoned") = Some(encoded);
+        Ok(Some(new_id))
+    }
+}
+
+fn random_id() -> Id {
+    use rand::prelude::*;
+    let id_val = rand::thread_rng().gen();
+    Id(id_val)
+}
+
+/// Encrypts the session held by a [`CookieStore`] into the cookie value.
+#[derive(Debug, Clone)]
+pub(crate) struct Sealer {
+    slot: Arc<Mutex<Option<Encoded>>>,
+    cipher: Arc<Cipher>,
+}
+
+impl Sealer {
+    /// Produce the cookie value for the session `id`, or `None` if the store does not hold it.
+    pub(crate) fn seal(&self, name: &str, id: Id) -> Option<String> {
+        let slot = self.slot.lock().expect("lock should not be poisoned");
+        slot.as_ref()
+            .filter(|encoded| encoded.id == id)
+            .map(|encoded| self.cipher.encrypt(name, &encoded.plaintext))
+    }
+}
+
+/// A middleware that provides [`Session`] backed by a [`CookieStore`] as a request extension.
+///
+/// The `Tag` distinguishes the sessions of several middlewares that use the same record type. See
+/// [`CookieSessionManagerLayer::with_tag`].
+pub struct CookieSessionManager<R, S, Tag = ()> {
+    inner: S,
+    config: ConfigSource,
+    cipher: Arc<Cipher>,
+    transports: Arc<[Transport]>,
+    warn_limit: Arc<WarnLimit>,
+    clock: Arc<dyn Clock>,
+    _marker: PhantomData<fn() -> (R, Tag)>,
+}
+
+impl<R, S: Clone, Tag> Clone for CookieSessionManager<R, S, Tag> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            config: self.config.clone(),
+            cipher: Arc::clone(&self.cipher),
+            transports: Arc::clone(&self.transports),
+            warn_limit: Arc::clone(&self.warn_limit),
+            clock: Arc::clone(&self.clock),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<R, S: Debug, Tag> Debug for CookieSessionManager<R, S, Tag> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CookieSessionManager")
+            .field("inner", &self.inner)
+            .field("config", &self.config)
+            .field("cipher", &self.cipher)
+            .field("transports", &self.transports)
+            .field("warn_limit", &self.warn_limit)
+            .field("clock", &self.clock)
+            .finish()
+    }
+}
+
+impl<ReqBody, ResBody, S, R, Tag> Service<Request<ReqBody>> for CookieSessionManager<R, S, Tag>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    ResBody: Default,
+    R: Send + Sync + 'static,
+    Tag: 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Instrumented<ResponseFuture<S::Future, ResBody>>;
+
+    #[inline]
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let span = tracing::debug_span!("cookie_session_manager");
+        let _enter = span.enter();
+
+        let (parts, body) = req.into_parts();
+        let config = self.config.resolve(&parts);
+        let mut req = Request::from_parts(parts, body);
+
+        let (value, carrier) =
+            Carrier::extract(&self.transports, req.headers(), &config, &self.warn_limit);
+        let encoded = value.and_then(|value| {
+            let plaintext = self
+                .cipher
+                .decrypt(config.name(), &value)
+                .map_err(|err| {
+                    suspicious!(
+                        self.warn_limit,
+                        err = %err,
+                        "possibly suspicious activity: rejected session cookie"
+                    )
+                })
+                .ok()?;
+            let header = serde_json::from_slice::<Header>(&plaintext)
+                .map_err(
+                    |err| tracing::warn!(err = %err, "failed to deserialize the session record"),
+                )
+                .ok()?;
+            Some(Encoded {
+                id: header.id,
+                expires_at: header.expires_at,
+                plaintext,
+            })
+        });
+        let id = encoded.as_ref().map(|encoded| encoded.id);
+
+        let store = CookieStore::<R>::new(Arc::clone(&config), Arc::clone(&self.clock), encoded);
+        let value = CookieValue::Record(Sealer {
+            slot: Arc::clone(&store.slot),
+            cipher: Arc::clone(&self.cipher),
+        });
+        let updater = Arc::new(Mutex::new(None));
+        let session = Session::<_, Tag> {
+            id,
+            store,
+            updater: Arc::clone(&updater),
+            clock: Arc::clone(&self.clock),
+            tag: PhantomData,
+        };
+        tracing::debug!("adding session to request extensions");
+        req.extensions_mut().insert(session);
+
+        drop(_enter);
+        ResponseFuture {
+            inner: self.inner.call(req),
+            updater,
+            config,
+            value,
+            old_id: id,
+            carrier,
+            clock: Arc::clone(&self.clock),
+            checks: None,
+            checking: None,
+        }
+        .instrument(span)
+    }
+}
+
+/// A layer for providing [`Session`] backed by a [`CookieStore`] as a request extension.
+///
+/// # Examples
+///
+/// ```rust
+/// use serde::{Deserialize, Serialize};
+/// use tower_sesh::{protection::Key, CookieSessionManagerLayer, Expires};
+///
+/// #[derive(Clone, Serialize, Deserialize)]
+/// struct Cart {
+///     items: Vec<u64>,
+/// }
+///
+/// impl Expires for Cart {}
+///
+/// let session_service =
+///     CookieSessionManagerLayer::<Cart>::new(Key::generate(), Default::default())
+///         .with_purpose("cart");
+/// ```
+pub struct CookieSessionManagerLayer<R, Tag = ()> {
+    config: ConfigSource,
+    cipher: Arc<Cipher>,
+    transports: Arc<[Transport]>,
+    warn_limit: Arc<WarnLimit>,
+    clock: Arc<dyn Clock>,
+    _marker: PhantomData<fn() -> (R, Tag)>,
+}
+
+impl<R, Tag> Clone for CookieSessionManagerLayer<R, Tag> {
+    fn clone(&self) -> Self {
+        Self {
+            config: self.config.clone(),
+            cipher: Arc::clone(&self.cipher),
+            transports: Arc::clone(&self.transports),
+            warn_limit: Arc::clone(&self.warn_limit),
+            clock: Arc::clone(&self.clock),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<R, Tag> Debug for CookieSessionManagerLayer<R, Tag> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CookieSessionManagerLayer")
+            .field("config", &self.config)
+            .field("cipher", &self.cipher)
+            .field("transports", &self.transports)
+            .field("warn_limit", &self.warn_limit)
+            .field("clock", &self.clock)
+            .finish()
+    }
+}
+
+impl<R> CookieSessionManagerLayer<R> {
+    /// Create a new [`CookieSessionManagerLayer`].
+    ///
+    /// Records are encrypted with the [current key][KeyRing::current], and cookies are decrypted
+    /// with every key of the ring. Cookies that cannot be decrypted are treated like malformed
+    /// session ids: they are logged, and the session is considered missing.
+    pub fn new(keys: impl Into<KeyRing>, config: Config) -> Self {
+        Self {
+            config: config.into(),
+            cipher: Arc::new(Cipher {
+                keys: keys.into(),
+                purpose: None,
+            }),
+            transports: transport::cookie(),
+            warn_limit: Default::default(),
+            clock: Arc::new(SystemClock),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<R, Tag> CookieSessionManagerLayer<R, Tag> {
+    /// Tag the sessions provided by this layer with the `NewTag` type.
+    ///
+    /// Handlers then extract `Session<CookieStore<R>, NewTag>` instead of
+    /// `Session<CookieStore<R>>`. See [`SessionManagerLayer::with_tag`] for more information.
+    ///
+    /// [`SessionManagerLayer::with_tag`]: crate::SessionManagerLayer::with_tag
+    pub fn with_tag<NewTag>(self) -> CookieSessionManagerLayer<R, NewTag> {
+        CookieSessionManagerLayer {
+            config: self.config,
+            cipher: self.cipher,
+            transports: self.transports,
+            warn_limit: self.warn_limit,
+            clock: self.clock,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Compute the [`Config`] of each request with `config_fn`, instead of using the same
+    /// configuration for every request.
+    ///
+    /// See [`SessionManagerLayer::with_config_fn`] for more information.
+    ///
+    /// [`SessionManagerLayer::with_config_fn`]: crate::SessionManagerLayer::with_config_fn
+    pub fn with_config_fn<F, C>(mut self, config_fn: F) -> Self
+    where
+        F: Fn(&Parts) -> C + Send + Sync + 'static,
+        C: Into<Arc<Config>>,
+    {
+        self.config = ConfigSource::dynamic(config_fn);
+        self
+    }
+
+    /// Carry the session record with the given `transports`, in order of precedence.
+    ///
+    /// See [`SessionManagerLayer::with_transports`] for more information. Header transports
+    /// cannot split the record into chunks, so [`Config::max_chunks`] only applies to the cookie.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `transports` is empty.
+    ///
+    /// [`SessionManagerLayer::with_transports`]: crate::SessionManagerLayer::with_transports
+    pub fn with_transports(mut self, transports: impl IntoIterator<Item = Transport>) -> Self {
+        let transports: Arc<[Transport]> = transports.into_iter().collect();
+        assert!(
+            !transports.is_empty(),
+            "the session should have at least one transport"
+        );
+        self.transports = transports;
+        self
+    }
+
+    /// Log at most `max` warnings about possibly suspicious requests per `period`.
+    ///
+    /// See [`SessionManagerLayer::with_warning_limit`] for more information.
+    ///
+    /// [`SessionManagerLayer::with_warning_limit`]: crate::SessionManagerLayer::with_warning_limit
+    pub fn with_warning_limit(mut self, max: u32, period: std::time::Duration) -> Self {
+        self.warn_limit = Arc::new(WarnLimit::new(max, period));
+        self
+    }
+
+    /// Compute the expiry of the records with `clock`, instead of the [`SystemClock`].
+    ///
+    /// See [`SessionManagerLayer::with_clock`] for more information.
+    ///
+    /// [`SessionManagerLayer::with_clock`]: crate::SessionManagerLayer::with_clock
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Authenticate `purpose` alongside the record, so that a cookie issued for one purpose is
+    /// rejected for any other.
+    pub fn with_purpose(mut self, purpose: &str) -> Self {
+        self.cipher = Arc::new(Cipher {
+            keys: self.cipher.keys.clone(),
+            purpose: Some(purpose.into()),
+        });
+        self
+    }
+}
+
+impl<R, S, Tag> Layer<S> for CookieSessionManagerLayer<R, Tag> {
+    type Service = CookieSessionManager<R, S, Tag>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        CookieSessionManager {
+            inner,
+            config: self.config.clone(),
+            cipher: Arc::clone(&self.cipher),
+            transports: Arc::clone(&self.transports),
+            warn_limit: Arc::clone(&self.warn_limit),
+            clock: Arc::clone(&self.clock),
+            _marker: PhantomData,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+    use axum::body::Body;
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+    use cookie::Cookie;
+    use time::Duration;
+    use tower::{ServiceBuilder, ServiceExt};
+
+    use super::*;
+    use crate::{protection::Key, Expiry};
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    struct Record {
+        foo: i32,
+        padding: String,
+        expiry: Expiry,
+    }
+
+    impl Expires for Record {
+        fn expires(&self) -> Expiry {
+            self.expiry
+        }
+    }
+
+    impl Record {
+        fn new(foo: i32) -> Self {
+            Self {
+                foo,
+                padding: String::new(),
+                expiry: Expiry::OnSessionEnd,
+            }
+        }
+    }
+
+    async fn handler(mut req: Request<Body>) -> anyhow::Result<Response<Body>> {
+        let session = req
+            .extensions_mut()
+            .remove::<Session<CookieStore<Record>>>()
+            .ok_or(anyhow!("Missing session"))?;
+
+        let foo = if let Some(state) = session.clone().load().await? {
+            match req.uri().path() {
+                "/delete" => {
+                    state.delete().await?;
+                    return Ok(Response::new(Body::empty()));
+                }
+                "/cycle" => state.cycle().await?,
+                "/grow" => {
+                    state
+                        .update(|data| data.padding = "a".repeat(MAX_COOKIE_SIZE))
+                        .await?
+                }
+                "/shrink" => state.update(|data| data.padding.clear()).await?,
+                _ => state.update(|data| data.foo += 1).await?,
+            }
+            .ok_or(anyhow!("Session vanished"))?
+            .data()
+            .foo
+        } else if req.uri().path() == "/expired" {
+            let mut record = Record::new(0);
+            record.expiry = Expiry::AtDateTime(OffsetDateTime::now_utc() - Duration::hours(1));
+            session.create(record).await?.data().foo
+        } else {
+            session.create(Record::new(42)).await?.data().foo
+        };
+
+        Ok(Response::new(Body::from(foo.to_string())))
+    }
+
+    fn svc(
+        key: Key,
+    ) -> impl Service<
+        Request<Body>,
+        Response = Response<Body>,
+        Error = anyhow::Error,
+        Future = impl Send,
+    > + Clone {
+        svc_with_config(key, Default::default())
+    }
+
+    fn svc_with_config(
+        key: Key,
+        config: Config,
+    ) -> impl Service<
+        Request<Body>,
+        Response = Response<Body>,
+        Error = anyhow::Error,
+        Future = impl Send,
+    > + Clone {
+        ServiceBuilder::new()
+            .layer(CookieSessionManagerLayer::<Record>::new(key, config))
+            .service_fn(handler)
+    }
+
+    async fn call(
+        svc: impl Service<Request<Body>, Response = Response<Body>, Error = anyhow::Error>,
+        path: &str,
+        cookie: Option<&str>,
+    ) -> anyhow::Result<(String, Option<Cookie<'static>>)> {
+        let cookies = cookie
+            .map(|cookie| vec![Cookie::new("id", cookie.to_owned())])
+            .unwrap_or_default();
+        let (body, cookies) = call_with_cookies(svc, path, &cookies).await?;
+        Ok((body, cookies.into_iter().next()))
+    }
+
+    async fn call_with_cookies(
+        svc: impl Service<Request<Body>, Response = Response<Body>, Error = anyhow::Error>,
+        path: &str,
+        cookies: &[Cookie<'static>],
+    ) -> anyhow::Result<(String, Vec<Cookie<'static>>)> {
+        let mut req = Request::builder().uri(path);
+        if !cookies.is_empty() {
+            let cookies = cookies
+                .iter()
+                .map(|cookie| cookie.stripped().to_string())
+                .collect::<Vec<_>>()
+                .join("; ");
+            req = req.header(http::header::COOKIE, cookies);
+        }
+        let res = svc.oneshot(req.body(Body::empty())?).await?;
+        let cookies = res
+            .headers()
+            .get_all(http::header::SET_COOKIE)
+            .into_iter()
+            .map(|value| Cookie::parse(value.to_str().unwrap().to_owned()).unwrap())
+            .collect();
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX).await?;
+        Ok((String::from_utf8(body.to_vec())?, cookies))
+    }
+
+    /// The cookies a browser would keep after receiving `set_cookies`.
+    fn jar(
+        mut cookies: Vec<Cookie<'static>>,
+        set_cookies: Vec<Cookie<'static>>,
+    ) -> Vec<Cookie<'static>> {
+        for set_cookie in set_cookies {
+            cookies.retain(|cookie| cookie.name() != set_cookie.name());
+            if !set_cookie.value().is_empty() {
+                cookies.push(set_cookie);
+            }
+        }
+        cookies.sort_by(|a, b| a.name().cmp(b.name()));
+        cookies
+    }
+
+    fn names<'a>(cookies: &'a [Cookie<'static>]) -> Vec<&'a str> {
+        cookies.iter().map(Cookie::name).collect()
+    }
+
+    #[tokio::test]
+    async fn round_trip() -> anyhow::Result<()> {
+        let svc = svc(Key::generate());
+
+        let (body, cookie) = call(svc.clone(), "/", None).await?;
+        assert_eq!(body, "42");
+        let cookie = cookie.expect("a session cookie should be set");
+        let ciphertext = URL_SAFE_NO_PAD.decode(cookie.value())?;
+        assert!(!ciphertext.windows(8).any(|window| window == br#""foo":42"#));
+
+        let (body, cookie) = call(svc.clone(), "/", Some(cookie.value())).await?;
+        assert_eq!(body, "43");
+        let cookie = cookie.expect("the session cookie should be updated");
+
+        let (body, cycled) = call(svc.clone(), "/cycle", Some(cookie.value())).await?;
+        assert_eq!(body, "43");
+        let cycled = cycled.expect("the session cookie should be updated");
+        assert_ne!(cookie.value(), cycled.value());
+
+        let (_, deleted) = call(svc, "/delete", Some(cycled.value())).await?;
+        let deleted = deleted.expect("the session cookie should be removed");
+        assert_eq!(deleted.value(), "");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_other_key() -> anyhow::Result<()> {
+        let (_, cookie) = call(svc(Key::generate()), "/", None).await?;
+        let cookie = cookie.expect("a session cookie should be set");
+
+        let (body, _) = call(svc(Key::generate()), "/", Some(cookie.value())).await?;
+        assert_eq!(body, "42");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn expired_record() -> anyhow::Result<()> {
+        let svc = svc(Key::generate());
+
+        let (_, cookie) = call(svc.clone(), "/expired", None).await?;
+        let cookie = cookie.expect("a session cookie should be set");
+
+        let (body, _) = call(svc, "/", Some(cookie.value())).await?;
+        assert_eq!(body, "42");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn too_large() -> anyhow::Result<()> {
+        let svc = svc(Key::generate());
+
+        let (_, cookie) = call(svc.clone(), "/", None).await?;
+        let cookie = cookie.expect("a session cookie should be set");
+
+        let err = call(svc, "/grow", Some(cookie.value()))
+            .await
+            .expect_err("the record should not fit in a cookie");
+        assert!(matches!(
+            err.downcast_ref::<CookieStoreError>(),
+            Some(CookieStoreError::TooLarge { .. })
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn chunked() -> anyhow::Result<()> {
+        let svc = svc_with_config(Key::generate(), Config::builder().max_chunks(4).build()?);
+
+        let (_, set_cookies) = call_with_cookies(svc.clone(), "/", &[]).await?;
+        let cookies = jar(vec![], set_cookies);
+        assert_eq!(names(&cookies), ["id"]);
+
+        let (body, set_cookies) = call_with_cookies(svc.clone(), "/grow", &cookies).await?;
+        assert_eq!(body, "42");
+        assert!(set_cookies
+            .iter()
+            .all(|cookie| cookie.name().len() + cookie.value().len() < MAX_COOKIE_SIZE));
+        let cookies = jar(cookies, set_cookies);
+        assert_eq!(names(&cookies), ["id.0", "id.1"]);
+
+        let (body, set_cookies) = call_with_cookies(svc.clone(), "/", &cookies).await?;
+        assert_eq!(body, "43");
+        let cookies = jar(cookies, set_cookies);
+        assert_eq!(names(&cookies), ["id.0", "id.1"]);
+
+        // A missing chunk is like a missing session.
+        let (body, _) = call_with_cookies(svc.clone(), "/", &cookies[1..]).await?;
+        assert_eq!(body, "42");
+
+        let (body, set_cookies) = call_with_cookies(svc.clone(), "/shrink", &cookies).await?;
+        assert_eq!(body, "43");
+        let cookies = jar(cookies, set_cookies);
+        assert_eq!(names(&cookies), ["id"]);
+
+        let (body, _) = call_with_cookies(svc, "/", &cookies).await?;
+        assert_eq!(body, "44");
+
+        Ok(())
+    }
+}
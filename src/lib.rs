@@ -13,16 +13,35 @@ pub use tower_sesh_core::session_store;
 #[doc(inline)]
 pub use tower_sesh_core::{
     id::Id,
+    clock::{Clock, ManualClock, SystemClock},
     expires::{Expires, Expiry},
     session_store::{CachingSessionStore, SessionStore},
 };
+#[cfg(feature = "memory-store-reaper")]
+#[cfg_attr(docsrs, doc(cfg(feature = "memory-store-reaper")))]
+#[doc(inline)]
+pub use tower_sesh_memory_store::Reaper;
+#[cfg(feature = "memory-store-snapshot")]
+#[cfg_attr(docsrs, doc(cfg(feature = "memory-store-snapshot")))]
+#[doc(inline)]
+pub use tower_sesh_memory_store::SnapshotTask;
 #[cfg(feature = "memory-store")]
 #[cfg_attr(docsrs, doc(cfg(feature = "memory-store")))]
 #[doc(inline)]
-pub use tower_sesh_memory_store::MemoryStore;
+pub use tower_sesh_memory_store::{LruMetrics, LruStore, MemoryStore};
 
+#[cfg(feature = "cookie-store")]
+#[cfg_attr(docsrs, doc(cfg(feature = "cookie-store")))]
+pub use crate::cookie_store::{CookieSessionManagerLayer, CookieStore};
 pub use crate::middleware::{SessionManager, SessionManagerLayer};
 pub use crate::session::{Session, SessionState};
 
+#[cfg(feature = "cookie-store")]
+#[cfg_attr(docsrs, doc(cfg(feature = "cookie-store")))]
+pub mod cookie_store;
 pub mod middleware;
+pub mod protection;
 pub mod session;
+mod throttle;
+pub mod transport;
+mod warn;
@@ -1,64 +1,417 @@
 //! A middleware that provides [`Session`] as a request extension.
 use std::{
+    any::Any,
+    borrow::Cow,
+    fmt::{self, Debug, Display},
     future::Future,
+    marker::PhantomData,
     pin::Pin,
     sync::{Arc, Mutex},
-    task::{Context, Poll},
+    task::{ready, Context, Poll},
 };
 
 use cookie::{Cookie, SameSite};
-use http::{header::COOKIE, Request, Response};
+use http::{
+    header::{Entry, COOKIE, SET_COOKIE},
+    request::Parts,
+    HeaderMap, Request, Response, StatusCode,
+};
 use pin_project_lite::pin_project;
 use time::OffsetDateTime;
 use tower_layer::Layer;
 use tower_service::Service;
-use tower_sesh_core::{expires::Expiry, id::Id};
+use tower_sesh_core::{
+    clock::{Clock, SystemClock},
+    expires::Expiry,
+    id::Id,
+    SessionStore,
+};
 use tracing::{instrument::Instrumented, Instrument};
 
+#[cfg(feature = "private")]
+use crate::protection::Cipher;
+#[cfg(any(feature = "signed", feature = "private"))]
+use crate::protection::KeyRing;
 use crate::{
+    protection::Protection,
     session::{SessionUpdate, Updater},
+    throttle::Throttle,
+    transport::{self, Carrier, Transport},
+    warn::{suspicious, WarnLimit},
     Session,
 };
 
-/// the configuration options for the [`SessionManagerLayer`].
+/// The configuration options for the [`SessionManagerLayer`].
+///
+/// A [`Config`] is built with a [`ConfigBuilder`], which validates the options so that the session
+/// cookie is never silently rejected by browsers. With the `serde` feature, a [`Config`] can also be
+/// deserialized, e.g. from an application configuration file, going through the same validation.
 ///
 /// ## Default
 /// ```
 /// # use tower_sesh::middleware::Config;
-/// # use tower_sesh::Expiry;
 /// # use cookie::SameSite;
-/// let default = Config {
-///    name: "id",
-///    http_only: true,
-///    same_site: SameSite::Strict,
-///    secure: true,
-///    path: "/",
-///    domain: None,
-///    always_set_expiry: None,
-/// };
+/// let default = Config::builder()
+///     .name("id")
+///     .http_only(true)
+///     .same_site(SameSite::Strict)
+///     .secure(true)
+///     .path("/")
+///     .always_set_expiry(None)
+///     .max_chunks(1)
+///     .build()
+///     .unwrap();
 ///
 /// assert_eq!(default, Config::default());
 /// ```
-#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
-pub struct Config<'a> {
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize, serde::Serialize),
+    serde(try_from = "ConfigBuilder", into = "ConfigBuilder")
+)]
+pub struct Config {
+    name: Cow<'static, str>,
+    http_only: bool,
+    same_site: SameSite,
+    secure: bool,
+    path: Cow<'static, str>,
+    domain: Option<Cow<'static, str>>,
+    always_set_expiry: Option<Expiry>,
+    max_chunks: usize,
+    partitioned: bool,
+}
+
+impl Config {
+    /// Create a [`ConfigBuilder`], starting from the [default](Config::default) options.
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::default()
+    }
+
     /// The name of the cookie.
-    pub name: &'a str,
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
     /// Whether the cookie is [HTTP only](https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Set-Cookie#httponly).
-    pub http_only: bool,
+    pub fn http_only(&self) -> bool {
+        self.http_only
+    }
+
     /// The
     /// [SameSite](https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Set-Cookie#samesitesamesite-value)
     /// policy.
-    pub same_site: SameSite,
-    /// Whether the cookie should be
+    pub fn same_site(&self) -> SameSite {
+        self.same_site
+    }
+
+    /// Whether the cookie is
     /// [secure](https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Set-Cookie#secure).
-    pub secure: bool,
+    pub fn secure(&self) -> bool {
+        self.secure
+    }
+
     /// The [path](https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Set-Cookie#pathpath-value)
     /// attribute of the cookie.
-    pub path: &'a str,
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
     /// The
     /// [domain](https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Set-Cookie#domaindomain-value)
     /// attribute of the cookie.
-    pub domain: Option<&'a str>,
+    pub fn domain(&self) -> Option<&str> {
+        self.domain.as_deref()
+    }
+
+    /// The expiry set on unmodified sessions. See [`ConfigBuilder::always_set_expiry`].
+    pub fn always_set_expiry(&self) -> Option<Expiry> {
+        self.always_set_expiry
+    }
+
+    /// The maximum number of cookies the session cookie can be split into. See
+    /// [`ConfigBuilder::max_chunks`].
+    pub fn max_chunks(&self) -> usize {
+        self.max_chunks
+    }
+
+    /// Whether the cookie is
+    /// [partitioned](https://developer.mozilla.org/en-US/docs/Web/Privacy/Privacy_sandbox/Partitioned_cookies).
+    pub fn partitioned(&self) -> bool {
+        self.partitioned
+    }
+
+    /// The prefix of the cookie name, if any. See [`ConfigBuilder::prefix`].
+    pub fn prefix(&self) -> Option<CookiePrefix> {
+        CookiePrefix::of(&self.name)
+    }
+
+    fn build_cookie<'c>(
+        &'c self,
+        name: Cow<'c, str>,
+        value: String,
+        expiry: Expiry,
+        now: OffsetDateTime,
+    ) -> Cookie<'c> {
+        let mut cookie_builder = Cookie::build((name, value))
+            .http_only(self.http_only)
+            .same_site(self.same_site)
+            .secure(self.secure)
+            .partitioned(self.partitioned)
+            .path(&*self.path);
+
+        // Both `Max-Age` and `Expires` are set, for the clients that only support `Expires`.
+        match expiry.deadline(now) {
+            Some(deadline) if deadline > now => {
+                // The deadline was computed when the session was saved, so the remaining time is
+                // rounded to the second rather than truncated.
+                let remaining = (deadline - now).as_seconds_f64().round();
+                let max_age = time::Duration::seconds(remaining as i64).min(MAX_COOKIE_AGE);
+                cookie_builder = cookie_builder.max_age(max_age).expires(now + max_age);
+            }
+            Some(_) => {
+                cookie_builder = cookie_builder
+                    .max_age(time::Duration::ZERO)
+                    .expires(OffsetDateTime::UNIX_EPOCH);
+            }
+            None => {}
+        }
+
+        if let Some(domain) = &self.domain {
+            cookie_builder = cookie_builder.domain(&**domain);
+        }
+
+        cookie_builder.build()
+    }
+
+    /// The maximum length of a value that fits in a single, unchunked cookie.
+    fn unchunked_len(&self) -> usize {
+        MAX_COOKIE_SIZE.saturating_sub(self.name.len() + 1)
+    }
+
+    /// The maximum length of a value that fits in each chunk.
+    fn chunk_len(&self) -> usize {
+        let index_len = self.max_chunks.saturating_sub(1).to_string().len();
+        MAX_COOKIE_SIZE.saturating_sub(self.name.len() + 1 + index_len + 1)
+    }
+
+    /// The maximum length of the session cookie value, chunks included.
+    #[cfg(feature = "cookie-store")]
+    pub(crate) fn max_value_len(&self) -> usize {
+        if self.max_chunks <= 1 {
+            self.unchunked_len()
+        } else {
+            self.unchunked_len().max(self.chunk_len() * self.max_chunks)
+        }
+    }
+
+    fn chunk_name(&self, index: usize) -> Cow<'static, str> {
+        Cow::Owned(format!("{}.{index}", self.name))
+    }
+
+    /// Build the cookies to send for the session, removing what the client `sent` that is now
+    /// stale.
+    ///
+    /// If `value` is `None`, the session cookie is removed. The expiry is computed from `now`.
+    pub(crate) fn build_cookies(
+        &self,
+        value: Option<(String, Expiry)>,
+        sent: Sent,
+        now: OffsetDateTime,
+    ) -> Vec<Cookie<'_>> {
+        let removal = Expiry::AtDateTime(OffsetDateTime::UNIX_EPOCH);
+        let mut cookies = Vec::new();
+
+        let chunks = match value {
+            None => {
+                cookies.push(self.build_cookie(self.name().into(), String::new(), removal, now));
+                0
+            }
+            Some((value, expiry))
+                if self.max_chunks <= 1 || value.len() <= self.unchunked_len() =>
+            {
+                cookies.push(self.build_cookie(self.name().into(), value, expiry, now));
+                0
+            }
+            Some((value, expiry)) => {
+                if sent.unchunked {
+                    cookies.push(self.build_cookie(
+                        self.name().into(),
+                        String::new(),
+                        removal,
+                        now,
+                    ));
+                }
+                let chunks = value.as_bytes().chunks(self.chunk_len());
+                let count = chunks.len();
+                for (index, chunk) in chunks.enumerate() {
+                    let chunk = std::str::from_utf8(chunk)
+                        .expect("session cookie values should be ASCII")
+                        .to_owned();
+                    cookies.push(self.build_cookie(self.chunk_name(index), chunk, expiry, now));
+                }
+                count
+            }
+        };
+
+        for index in chunks..sent.chunks {
+            cookies.push(self.build_cookie(self.chunk_name(index), String::new(), removal, now));
+        }
+
+        cookies
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            name: "id".into(), /* See: https://cheatsheetseries.owasp.org/cheatsheets/Session_Management_Cheat_Sheet.html#session-id-name-fingerprinting */
+            http_only: true,
+            same_site: SameSite::Strict,
+            secure: true,
+            path: "/".into(),
+            domain: None,
+            always_set_expiry: None,
+            max_chunks: 1,
+            partitioned: false,
+        }
+    }
+}
+
+/// A builder for [`Config`].
+///
+/// Every option starts from its [default](Config::default) value.
+///
+/// # Examples
+///
+/// ```
+/// use cookie::SameSite;
+/// use tower_sesh::middleware::{Config, ConfigError};
+///
+/// let domain = std::env::var("COOKIE_DOMAIN").unwrap_or_else(|_| "example.com".to_owned());
+/// let config = Config::builder()
+///     .name("sid")
+///     .same_site(SameSite::Lax)
+///     .domain(domain)
+///     .build()
+///     .unwrap();
+/// assert_eq!(config.name(), "sid");
+///
+/// let err = Config::builder()
+///     .same_site(SameSite::None)
+///     .secure(false)
+///     .build()
+///     .unwrap_err();
+/// assert_eq!(err, ConfigError::InsecureSameSiteNone);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize, serde::Serialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct ConfigBuilder {
+    name: Cow<'static, str>,
+    http_only: bool,
+    #[cfg_attr(feature = "serde", serde(with = "same_site"))]
+    same_site: SameSite,
+    secure: bool,
+    path: Cow<'static, str>,
+    domain: Option<Cow<'static, str>>,
+    always_set_expiry: Option<Expiry>,
+    max_chunks: usize,
+    partitioned: bool,
+    prefix: Option<CookiePrefix>,
+}
+
+impl ConfigBuilder {
+    /// Set the name of the cookie.
+    ///
+    /// The name must be a non-empty
+    /// [token](https://www.rfc-editor.org/rfc/rfc6265#section-4.1.1), i.e. it cannot contain
+    /// whitespace, control characters or any of `()<>@,;:\"/[]?={}`.
+    pub fn name(mut self, name: impl Into<Cow<'static, str>>) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    /// Prepend `prefix` to the name of the cookie, unless the name already starts with it.
+    ///
+    /// Browsers only accept prefixed cookies that respect the invariants of the prefix, so
+    /// [`ConfigBuilder::build`] fails if they are not respected. With the default options, the
+    /// cookie is secure, has the path `/` and no domain, so both prefixes can be used as is.
+    ///
+    /// Names that already start with a prefix are subject to the same checks, even when this
+    /// method is not called.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use tower_sesh::middleware::{Config, ConfigError, CookiePrefix};
+    ///
+    /// let config = Config::builder().prefix(CookiePrefix::Host).build().unwrap();
+    /// assert_eq!(config.name(), "__Host-id");
+    ///
+    /// let err = Config::builder()
+    ///     .prefix(CookiePrefix::Host)
+    ///     .domain("example.com")
+    ///     .build()
+    ///     .unwrap_err();
+    /// assert_eq!(err, ConfigError::HostPrefixDomain);
+    /// ```
+    pub fn prefix(mut self, prefix: CookiePrefix) -> Self {
+        self.prefix = Some(prefix);
+        self
+    }
+
+    /// Set whether the cookie is [HTTP only](https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Set-Cookie#httponly).
+    pub fn http_only(mut self, http_only: bool) -> Self {
+        self.http_only = http_only;
+        self
+    }
+
+    /// Set the
+    /// [SameSite](https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Set-Cookie#samesitesamesite-value)
+    /// policy.
+    ///
+    /// Browsers reject `SameSite=None` cookies that are not [secure](Self::secure).
+    pub fn same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = same_site;
+        self
+    }
+
+    /// Set whether the cookie should be
+    /// [secure](https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Set-Cookie#secure).
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    /// Set the [path](https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Set-Cookie#pathpath-value)
+    /// attribute of the cookie.
+    ///
+    /// The path must start with `/`.
+    pub fn path(mut self, path: impl Into<Cow<'static, str>>) -> Self {
+        self.path = path.into();
+        self
+    }
+
+    /// Set the
+    /// [domain](https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Set-Cookie#domaindomain-value)
+    /// attribute of the cookie.
+    pub fn domain(mut self, domain: impl Into<Cow<'static, str>>) -> Self {
+        self.domain = Some(domain.into());
+        self
+    }
+
+    /// Remove the
+    /// [domain](https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Set-Cookie#domaindomain-value)
+    /// attribute of the cookie, so that it is only sent to the host that set it.
+    pub fn no_domain(mut self) -> Self {
+        self.domain = None;
+        self
+    }
+
     /// If this is set to `None`, the session will only be saved if it is modified. If it is set to
     /// `Some(expiry)`, the session will be saved as usual if it is modified, but it will also be
     /// saved with the provided `expiry` when it is not modified.
@@ -67,60 +420,453 @@ pub struct Config<'a> {
     /// [`Max-Age`](https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Set-Cookie#max-agenumber)
     /// and the
     /// [`Expires`](https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Set-Cookie#expiresdate)
-    /// attributes.
-    pub always_set_expiry: Option<Expiry>,
+    /// attributes. The expiration of the session in the store is not refreshed, unless
+    /// [`SessionManagerLayer::with_touch`] is used.
+    pub fn always_set_expiry(mut self, expiry: Option<Expiry>) -> Self {
+        self.always_set_expiry = expiry;
+        self
+    }
+
+    /// Set the maximum number of cookies the session cookie can be split into.
+    ///
+    /// Browsers only keep cookies up to [`MAX_COOKIE_SIZE`] bytes. When the value of the session
+    /// cookie is larger than that, it is split into chunks named `{name}.0`, `{name}.1`, etc.
+    /// Chunks that are no longer needed are removed from the browser.
+    ///
+    /// This is only useful when the session record is stored in the cookie, with a
+    /// [`CookieStore`][crate::CookieStore]. A value of `1` disables chunking.
+    pub fn max_chunks(mut self, max_chunks: usize) -> Self {
+        self.max_chunks = max_chunks;
+        self
+    }
+
+    /// Set whether the cookie should be
+    /// [partitioned](https://developer.mozilla.org/en-US/docs/Web/Privacy/Privacy_sandbox/Partitioned_cookies)
+    /// (CHIPS).
+    ///
+    /// Partitioned cookies are keyed by the top-level site they are embedded in, which lets
+    /// sessions work in cross-site iframes when third-party cookies are blocked. They must be
+    /// [secure](Self::secure) and use the `SameSite=None` [policy](Self::same_site).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use cookie::SameSite;
+    /// use tower_sesh::middleware::Config;
+    ///
+    /// let config = Config::builder()
+    ///     .same_site(SameSite::None)
+    ///     .partitioned(true)
+    ///     .build()
+    ///     .unwrap();
+    /// assert!(config.partitioned());
+    /// ```
+    pub fn partitioned(mut self, partitioned: bool) -> Self {
+        self.partitioned = partitioned;
+        self
+    }
+
+    /// Validate the options and build the [`Config`].
+    pub fn build(mut self) -> Result<Config, ConfigError> {
+        if let Some(prefix) = self.prefix {
+            if CookiePrefix::of(&self.name) != Some(prefix) {
+                self.name = format!("{}{}", prefix.as_str(), self.name).into();
+            }
+        }
+        if !is_token(&self.name) {
+            return Err(ConfigError::InvalidName(self.name.into_owned()));
+        }
+        if !self.path.starts_with('/') || !is_attribute_value(&self.path) {
+            return Err(ConfigError::InvalidPath(self.path.into_owned()));
+        }
+        if let Some(domain) = &self.domain {
+            if domain.is_empty() || !is_attribute_value(domain) || domain.contains(' ') {
+                return Err(ConfigError::InvalidDomain(domain.clone().into_owned()));
+            }
+        }
+        if self.same_site == SameSite::None && !self.secure {
+            return Err(ConfigError::InsecureSameSiteNone);
+        }
+        if self.max_chunks == 0 {
+            return Err(ConfigError::NoChunks);
+        }
+        if self.partitioned && (!self.secure || self.same_site != SameSite::None) {
+            return Err(ConfigError::InvalidPartitioned);
+        }
+        if let Some(prefix) = CookiePrefix::of(&self.name) {
+            if !self.secure {
+                return Err(ConfigError::InsecurePrefix(prefix));
+            }
+            if prefix == CookiePrefix::Host {
+                if self.domain.is_some() {
+                    return Err(ConfigError::HostPrefixDomain);
+                }
+                if self.path != "/" {
+                    return Err(ConfigError::HostPrefixPath);
+                }
+            }
+        }
+
+        Ok(Config {
+            name: self.name,
+            http_only: self.http_only,
+            same_site: self.same_site,
+            secure: self.secure,
+            path: self.path,
+            domain: self.domain,
+            always_set_expiry: self.always_set_expiry,
+            max_chunks: self.max_chunks,
+            partitioned: self.partitioned,
+        })
+    }
 }
 
-impl<'a> Config<'a> {
-    fn build_cookie(self, session_id: Option<Id>, expiry: Expiry) -> Cookie<'a> {
-        let mut cookie_builder = Cookie::build((
-            self.name,
-            session_id
-                .as_ref()
-                .map(ToString::to_string)
-                .unwrap_or_default(),
-        ))
-        .http_only(self.http_only)
-        .same_site(self.same_site)
-        .secure(self.secure)
-        .path(self.path);
-
-        cookie_builder = match expiry {
-            Expiry::OnInactivity(duration) => cookie_builder.max_age(duration),
-            Expiry::AtDateTime(datetime) => {
-                cookie_builder.max_age(datetime - OffsetDateTime::now_utc())
+impl Default for ConfigBuilder {
+    fn default() -> Self {
+        Config::default().into()
+    }
+}
+
+impl From<Config> for ConfigBuilder {
+    fn from(config: Config) -> Self {
+        Self {
+            name: config.name,
+            http_only: config.http_only,
+            same_site: config.same_site,
+            secure: config.secure,
+            path: config.path,
+            domain: config.domain,
+            always_set_expiry: config.always_set_expiry,
+            max_chunks: config.max_chunks,
+            partitioned: config.partitioned,
+            prefix: None,
+        }
+    }
+}
+
+impl TryFrom<ConfigBuilder> for Config {
+    type Error = ConfigError;
+
+    fn try_from(builder: ConfigBuilder) -> Result<Self, Self::Error> {
+        builder.build()
+    }
+}
+
+/// A [cookie name prefix](https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Set-Cookie#cookie_prefixes).
+///
+/// Browsers only accept prefixed cookies that respect the invariants of their prefix, which
+/// protects them from being overwritten by insecure origins or by other subdomains.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub enum CookiePrefix {
+    /// The `__Host-` prefix. The cookie must be secure, have the path `/` and no domain.
+    Host,
+    /// The `__Secure-` prefix. The cookie must be secure.
+    Secure,
+}
+
+impl CookiePrefix {
+    /// The prefix, as it appears at the start of the cookie name.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CookiePrefix::Host => "__Host-",
+            CookiePrefix::Secure => "__Secure-",
+        }
+    }
+
+    /// The prefix of `name`, if any.
+    ///
+    /// Like browsers, the prefix is matched case-insensitively.
+    fn of(name: &str) -> Option<Self> {
+        [CookiePrefix::Host, CookiePrefix::Secure]
+            .into_iter()
+            .find(|prefix| {
+                name.get(..prefix.as_str().len())
+                    .is_some_and(|start| start.eq_ignore_ascii_case(prefix.as_str()))
+            })
+    }
+}
+
+impl Display for CookiePrefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Whether `name` is a valid cookie name.
+///
+/// See [RFC 6265, section 4.1.1](https://www.rfc-editor.org/rfc/rfc6265#section-4.1.1).
+fn is_token(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_graphic() && !b"()<>@,;:\\\"/[]?={}".contains(&b))
+}
+
+/// Whether `value` can be used as the value of a cookie attribute.
+///
+/// See [RFC 6265, section 4.1.1](https://www.rfc-editor.org/rfc/rfc6265#section-4.1.1).
+fn is_attribute_value(value: &str) -> bool {
+    value.bytes().all(|b| !b.is_ascii_control() && b != b';')
+}
+
+/// The error returned when building an invalid [`Config`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ConfigError {
+    /// The cookie name is not a valid token.
+    InvalidName(String),
+    /// The path does not start with `/`, or contains a `;` or control characters.
+    InvalidPath(String),
+    /// The domain is empty, or contains a `;`, whitespace or control characters.
+    InvalidDomain(String),
+    /// The `SameSite=None` policy was used without the `Secure` attribute, which browsers reject.
+    InsecureSameSiteNone,
+    /// The maximum number of chunks is zero.
+    NoChunks,
+    /// A prefixed cookie is not secure.
+    InsecurePrefix(CookiePrefix),
+    /// A cookie with the `__Host-` prefix has a domain.
+    HostPrefixDomain,
+    /// A cookie with the `__Host-` prefix does not have the path `/`.
+    HostPrefixPath,
+    /// A partitioned cookie is not secure, or does not use the `SameSite=None` policy.
+    InvalidPartitioned,
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::InvalidName(name) => write!(f, "invalid cookie name: {name:?}"),
+            ConfigError::InvalidPath(path) => write!(f, "invalid cookie path: {path:?}"),
+            ConfigError::InvalidDomain(domain) => write!(f, "invalid cookie domain: {domain:?}"),
+            ConfigError::InsecureSameSiteNone => {
+                write!(f, "`SameSite=None` cookies must be secure")
+            }
+            ConfigError::NoChunks => write!(f, "the maximum number of chunks must be at least 1"),
+            ConfigError::InsecurePrefix(prefix) => write!(f, "`{prefix}` cookies must be secure"),
+            ConfigError::HostPrefixDomain => write!(f, "`__Host-` cookies cannot have a domain"),
+            ConfigError::HostPrefixPath => write!(f, "`__Host-` cookies must have the path `/`"),
+            ConfigError::InvalidPartitioned => {
+                write!(
+                    f,
+                    "partitioned cookies must be secure and use `SameSite=None`"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// (De)serialize a [`SameSite`] policy as `"Strict"`, `"Lax"` or `"None"`.
+#[cfg(feature = "serde")]
+mod same_site {
+    use cookie::SameSite;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub(super) fn serialize<S: Serializer>(
+        same_site: &SameSite,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(same_site)
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<SameSite, D::Error> {
+        let value = <std::borrow::Cow<'de, str>>::deserialize(deserializer)?;
+        match &*value {
+            v if v.eq_ignore_ascii_case("strict") => Ok(SameSite::Strict),
+            v if v.eq_ignore_ascii_case("lax") => Ok(SameSite::Lax),
+            v if v.eq_ignore_ascii_case("none") => Ok(SameSite::None),
+            v => Err(D::Error::unknown_variant(v, &["Strict", "Lax", "None"])),
+        }
+    }
+}
+
+/// The maximum size of a cookie, name and value included, that browsers are required to keep.
+///
+/// See [RFC 6265, section 6.1](https://www.rfc-editor.org/rfc/rfc6265#section-6.1).
+pub const MAX_COOKIE_SIZE: usize = 4096;
+
+/// The maximum lifetime of a cookie that browsers keep.
+///
+/// Longer `Max-Age` and `Expires` attributes are capped to this value. See
+/// [RFC 6265bis, section 5.6.1](https://datatracker.ietf.org/doc/html/draft-ietf-httpbis-rfc6265bis#section-5.6.1).
+pub const MAX_COOKIE_AGE: time::Duration = time::Duration::days(400);
+
+/// What the client sent for the session cookie.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct Sent {
+    /// Whether an unchunked session cookie was sent.
+    unchunked: bool,
+    /// The number of chunks that were sent, counting missing chunks before the last one.
+    chunks: usize,
+}
+
+/// The session cookie sent by the client.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct RequestCookie {
+    /// The value of the session cookie, reassembled from its chunks if needed.
+    pub(crate) value: Option<String>,
+    pub(crate) sent: Sent,
+}
+
+impl RequestCookie {
+    /// Find the session cookie in the request headers.
+    ///
+    /// An unchunked cookie takes precedence over chunks. If some chunks are missing, the value is
+    /// `None`.
+    pub(crate) fn parse(headers: &HeaderMap, config: &Config, warn_limit: &WarnLimit) -> Self {
+        let mut unchunked = None;
+        let mut chunks: Vec<Option<&str>> = Vec::new();
+
+        let cookies = headers
+            .get_all(COOKIE)
+            .into_iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(';'))
+            .filter_map(|cookie| Cookie::parse(cookie).ok());
+        for cookie in cookies {
+            let (Some(name), Some(value)) = (cookie.name_raw(), cookie.value_raw()) else {
+                continue;
+            };
+            if name == config.name() {
+                unchunked.get_or_insert(value);
+            } else if let Some(index) = name
+                .strip_prefix(config.name())
+                .and_then(|suffix| suffix.strip_prefix('.'))
+                .and_then(|index| index.parse::<usize>().ok())
+                .filter(|index| *index < config.max_chunks)
+            {
+                if chunks.len() <= index {
+                    chunks.resize(index + 1, None);
+                }
+                chunks[index].get_or_insert(value);
+            }
+        }
+
+        let sent = Sent {
+            unchunked: unchunked.is_some(),
+            chunks: chunks.len(),
+        };
+        let value = match unchunked {
+            Some(value) => Some(value.to_owned()),
+            None if chunks.is_empty() => None,
+            None => {
+                let value = chunks.into_iter().collect::<Option<String>>();
+                if value.is_none() {
+                    suspicious!(
+                        warn_limit,
+                        "possibly suspicious activity: missing session cookie chunks"
+                    );
+                }
+                value
             }
-            Expiry::OnSessionEnd => cookie_builder,
         };
 
-        if let Some(domain) = self.domain {
-            cookie_builder = cookie_builder.domain(domain);
+        Self { value, sent }
+    }
+}
+
+/// Where the [`Config`] of a request comes from.
+#[derive(Clone)]
+pub(crate) enum ConfigSource {
+    /// The same configuration is used for every request.
+    Static(Arc<Config>),
+    /// The configuration is computed from each request.
+    Dynamic(Arc<ConfigFn>),
+}
+
+type ConfigFn = dyn Fn(&Parts) -> Arc<Config> + Send + Sync;
+
+impl ConfigSource {
+    /// Get the configuration for the request with the given `parts`.
+    pub(crate) fn resolve(&self, parts: &Parts) -> Arc<Config> {
+        match self {
+            ConfigSource::Static(config) => Arc::clone(config),
+            ConfigSource::Dynamic(config_fn) => config_fn(parts),
         }
+    }
 
-        cookie_builder.build()
+    /// Wrap `config_fn` so that it can be used for every request.
+    pub(crate) fn dynamic<F, C>(config_fn: F) -> Self
+    where
+        F: Fn(&Parts) -> C + Send + Sync + 'static,
+        C: Into<Arc<Config>>,
+    {
+        ConfigSource::Dynamic(Arc::new(move |parts| config_fn(parts).into()))
     }
 }
 
-impl Default for Config<'static> {
-    fn default() -> Self {
-        Self {
-            name: "id", /* See: https://cheatsheetseries.owasp.org/cheatsheets/Session_Management_Cheat_Sheet.html#session-id-name-fingerprinting */
-            http_only: true,
-            same_site: SameSite::Strict,
-            secure: true,
-            path: "/",
-            domain: None,
-            always_set_expiry: None,
+impl From<Config> for ConfigSource {
+    fn from(config: Config) -> Self {
+        ConfigSource::Static(Arc::new(config))
+    }
+}
+
+impl Debug for ConfigSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigSource::Static(config) => f.debug_tuple("Static").field(config).finish(),
+            ConfigSource::Dynamic(_) => f.debug_tuple("Dynamic").finish_non_exhaustive(),
         }
     }
 }
 
 /// A middleware that provides [`Session`] as a request extension.
-#[derive(Debug, Clone)]
-pub struct SessionManager<Store, S> {
+///
+/// The `Tag` distinguishes the sessions of several middlewares that use the same store type. See
+/// [`SessionManagerLayer::with_tag`].
+pub struct SessionManager<Store, S, Tag = ()> {
     inner: S,
     store: Store,
-    config: Config<'static>,
+    config: ConfigSource,
+    protection: Arc<Protection>,
+    transports: Arc<[Transport]>,
+    invalid: InvalidSessions,
+    touch: Option<Touch>,
+    errors: StoreErrors,
+    warn_limit: Arc<WarnLimit>,
+    clock: Arc<dyn Clock>,
+    tag: PhantomData<fn() -> Tag>,
+}
+
+impl<Store: Clone, S: Clone, Tag> Clone for SessionManager<Store, S, Tag> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            store: self.store.clone(),
+            config: self.config.clone(),
+            protection: Arc::clone(&self.protection),
+            transports: Arc::clone(&self.transports),
+            invalid: self.invalid.clone(),
+            touch: self.touch.clone(),
+            errors: self.errors.clone(),
+            warn_limit: Arc::clone(&self.warn_limit),
+            clock: Arc::clone(&self.clock),
+            tag: PhantomData,
+        }
+    }
+}
+
+impl<Store: Debug, S: Debug, Tag> Debug for SessionManager<Store, S, Tag> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SessionManager")
+            .field("inner", &self.inner)
+            .field("store", &self.store)
+            .field("config", &self.config)
+            .field("protection", &self.protection)
+            .field("transports", &self.transports)
+            .field("invalid", &self.invalid)
+            .field("touch", &self.touch)
+            .field("errors", &self.errors)
+            .field("warn_limit", &self.warn_limit)
+            .field("clock", &self.clock)
+            .field("tag", &std::any::type_name::<Tag>())
+            .finish()
+    }
 }
 
 impl<Store, S> SessionManager<Store, S> {
@@ -134,60 +880,73 @@ impl<Store, S> SessionManager<Store, S> {
     ///
     /// let _ = SessionManager::new(MyService, MemoryStore::<()>::default(), Default::default());
     /// ```
-    pub fn new(inner: S, store: Store, config: Config<'static>) -> Self {
+    pub fn new(inner: S, store: Store, config: Config) -> Self {
         Self {
             inner,
             store,
-            config,
+            config: config.into(),
+            protection: Default::default(),
+            transports: transport::cookie(),
+            invalid: Default::default(),
+            touch: None,
+            errors: Default::default(),
+            warn_limit: Default::default(),
+            clock: Arc::new(SystemClock),
+            tag: PhantomData,
         }
     }
 }
 
-impl<ReqBody, ResBody, S, Store> Service<Request<ReqBody>> for SessionManager<Store, S>
+impl<ReqBody, ResBody, S, Store, Tag> Service<Request<ReqBody>> for SessionManager<Store, S, Tag>
 where
     S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    ResBody: Default,
     Store: Clone + Send + Sync + 'static,
+    Tag: 'static,
 {
     type Response = S::Response;
     type Error = S::Error;
-    type Future = Instrumented<ResponseFuture<S::Future>>;
+    type Future = Instrumented<ResponseFuture<S::Future, ResBody>>;
 
     #[inline]
     fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         self.inner.poll_ready(cx)
     }
 
-    fn call(&mut self, mut req: Request<ReqBody>) -> Self::Future {
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
         let span = tracing::debug_span!("session_manager");
         let _enter = span.enter();
 
-        let session_cookie = req
-            .headers()
-            .get_all(COOKIE)
-            .into_iter()
-            .filter_map(|value| value.to_str().ok())
-            .flat_map(|value| value.split(';'))
-            .filter_map(|cookie| Cookie::parse(cookie).ok())
-            .find(|cookie| cookie.name() == self.config.name);
+        let (parts, body) = req.into_parts();
+        let config = self.config.resolve(&parts);
+        let mut req = Request::from_parts(parts, body);
 
-        let id = session_cookie.and_then(|cookie| {
-            cookie
-                .value()
-                .parse::<Id>()
+        let (value, carrier) =
+            Carrier::extract(&self.transports, req.headers(), &config, &self.warn_limit);
+        let id = value.and_then(|value| {
+            self.protection
+                .open(config.name(), &value)
                 .map_err(|err| {
-                    tracing::warn!(
+                    suspicious!(
+                        self.warn_limit,
                         err = %err,
-                        "possibly suspicious activity: malformed session id"
+                        "possibly suspicious activity: rejected session cookie"
                     )
                 })
                 .ok()
         });
 
-        let updater = Arc::new(Mutex::new(None));
-        let session = Session {
+        let clear = self.invalid.clear && carrier.presented() && id.is_none();
+        if clear {
+            tracing::debug!("removing the invalid session from the client");
+        }
+        let updater = Arc::new(Mutex::new(clear.then_some(SessionUpdate::Delete)));
+        let session = Session::<Store, Tag> {
             id,
             store: self.store.clone(),
             updater: Arc::clone(&updater),
+            clock: Arc::clone(&self.clock),
+            tag: PhantomData,
         };
         tracing::debug!("adding session to request extensions");
         req.extensions_mut().insert(session);
@@ -196,280 +955,2232 @@ where
         ResponseFuture {
             inner: self.inner.call(req),
             updater,
-            config: self.config,
+            config,
+            value: CookieValue::Id(Arc::clone(&self.protection)),
             old_id: id,
+            carrier,
+            clock: Arc::clone(&self.clock),
+            checks: (self.invalid.exists.is_some() || self.touch.is_some()).then(|| Checks {
+                exists: self.invalid.exists.clone(),
+                touch: self.touch.clone(),
+                errors: self.errors.clone(),
+            }),
+            checking: None,
         }
         .instrument(span)
     }
 }
 
-pin_project! {
-    #[derive(Debug, Clone)]
+/// Produces the value of the session cookie.
+#[derive(Debug, Clone)]
+pub(crate) enum CookieValue {
+    /// The value is the session id, protected according to [`Protection`].
+    Id(Arc<Protection>),
+    /// The value is the encrypted session record held by a [`CookieStore`].
+    ///
+    /// [`CookieStore`]: crate::CookieStore
+    #[cfg(feature = "cookie-store")]
+    Record(crate::cookie_store::Sealer),
+}
+
+impl CookieValue {
+    /// Produce the cookie value for the session `id`, or `None` if there is no such session.
+    fn encode(&self, name: &str, id: Id) -> Option<String> {
+        match self {
+            CookieValue::Id(protection) => Some(protection.seal(name, id)),
+            #[cfg(feature = "cookie-store")]
+            CookieValue::Record(sealer) => sealer.seal(name, id),
+        }
+    }
+}
+
+/// What the middleware does when the store fails.
+///
+/// This applies to the store operations that the middleware performs on its own, e.g. for
+/// [`SessionManagerLayer::with_existence_check`], not to the ones of the handler, which get the
+/// errors of the store directly.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ErrorPolicy {
+    /// Log the error and continue as if the operation was not performed.
+    #[default]
+    Log,
+    /// Retry the operation up to the given number of times, then log the error and continue.
+    ///
+    /// With the `tokio` feature, the retries are spaced according to
+    /// [`SessionManagerLayer::with_retry_backoff`]. Otherwise, they are immediate.
+    Retry(u32),
+    /// Fail the request with the response of the handler given to
+    /// [`SessionManagerLayer::with_error_handler`], or with an empty
+    /// `500 Internal Server Error` response.
+    Fail,
+}
+
+/// An error of the store in an operation performed by the middleware.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum StoreError<E> {
+    /// Loading the session to check that it exists failed.
+    Load(E),
+    /// Touching the session to refresh its expiration failed.
+    Touch(E),
+}
+
+impl<E> StoreError<E> {
+    /// The error of the store.
+    pub fn into_inner(self) -> E {
+        match self {
+            StoreError::Load(err) | StoreError::Touch(err) => err,
+        }
+    }
+}
+
+impl<E: Display> Display for StoreError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::Load(err) => write!(f, "failed to load the session: {err}"),
+            StoreError::Touch(err) => write!(f, "failed to touch the session: {err}"),
+        }
+    }
+}
+
+impl<E: std::error::Error + 'static> std::error::Error for StoreError<E> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StoreError::Load(err) | StoreError::Touch(err) => Some(err),
+        }
+    }
+}
+
+/// The error type of the store, as far as a [`SessionManagerLayer`] knows it.
+///
+/// [`SessionManagerLayer::with_existence_check`], [`SessionManagerLayer::with_touch`] and
+/// [`SessionManagerLayer::with_error_handler`] each take the session record type, which
+/// determines the error type of the store. The layer records that error type in its `Err` type
+/// parameter, starting with [`UnknownError`], so that these options only compile if their record
+/// types give the same error type. In particular, an error handler that would never be called
+/// for the errors of the middleware is rejected.
+///
+/// This trait is sealed, and implemented by [`UnknownError`] for every error type, and by
+/// [`KnownError<E>`] for `E`.
+///
+/// # Examples
+///
+/// A store whose records have different error types:
+///
+/// ```rust,compile_fail
+/// use std::{convert::Infallible, io};
+///
+/// use http::Response;
+/// use tower_sesh::{Id, SessionManagerLayer, SessionStore};
+///
+/// #[derive(Clone)]
+/// struct Store;
+///
+/// struct User;
+/// struct Cart;
+///
+/// macro_rules! impl_store {
+///     ($record:ty, $error:ty) => {
+///         impl SessionStore<$record> for Store {
+///             type Error = $error;
+/// #
+/// #           async fn create(&mut self, _: &$record) -> Result<Id, $error> {
+/// #               unimplemented!()
+/// #           }
+/// #           async fn save(&mut self, _: &Id, _: &$record) -> Result<bool, $error> {
+/// #               unimplemented!()
+/// #           }
+/// #           async fn save_or_create(&mut self, _: &Id, _: &$record) -> Result<(), $error> {
+/// #               unimplemented!()
+/// #           }
+/// #           async fn load(&mut self, _: &Id) -> Result<Option<$record>, $error> {
+/// #               unimplemented!()
+/// #           }
+/// #           async fn delete(&mut self, _: &Id) -> Result<bool, $error> {
+/// #               unimplemented!()
+/// #           }
+///             // ...
+///         }
+///     };
+/// }
+/// impl_store!(User, Infallible);
+/// impl_store!(Cart, io::Error);
+///
+/// // The existence check never fails with an `io::Error`, so this handler is rejected.
+/// let layer = SessionManagerLayer::new(Store, Default::default())
+///     .with_existence_check::<User>()
+///     .with_error_handler::<Cart, _>(|_| Response::new(()));
+/// ```
+pub trait StoreErrorType<E>: sealed::Sealed {}
+
+/// The error type of the store of a [`SessionManagerLayer`] that does not use the store on its
+/// own yet. See [`StoreErrorType`].
+#[derive(Debug)]
+pub enum UnknownError {}
+
+/// The error type `E` of the store of a [`SessionManagerLayer`]. See [`StoreErrorType`].
+pub struct KnownError<E>(PhantomData<fn() -> E>);
+
+impl<E> Debug for KnownError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("KnownError")
+            .field(&std::any::type_name::<E>())
+            .finish()
+    }
+}
+
+impl<E> StoreErrorType<E> for UnknownError {}
+impl<E> StoreErrorType<E> for KnownError<E> {}
+
+mod sealed {
+    pub trait Sealed {}
+
+    impl Sealed for super::UnknownError {}
+    impl<E> Sealed for super::KnownError<E> {}
+}
+
+/// Produce the response of a failed request from the error of the store.
+type ErrorHandler<E> = dyn Fn(StoreError<E>) -> Response<()> + Send + Sync;
+
+/// The delay before the first retry of a failed store operation.
+const DEFAULT_RETRY_BACKOFF: std::time::Duration = std::time::Duration::from_millis(10);
+
+/// How the middleware handles the errors of the store.
+#[derive(Clone)]
+pub(crate) struct StoreErrors {
+    policy: ErrorPolicy,
+    /// The delay before the first retry, doubled before each next retry.
+    backoff: std::time::Duration,
+    /// An `Arc<ErrorHandler<E>>` for the error type of the store.
+    ///
+    /// The error type is only known once the record type is, so the handler is downcast when the
+    /// store fails. The [`StoreErrorType`] of the layer guarantees that the downcast succeeds.
+    handler: Option<Arc<dyn Any + Send + Sync>>,
+}
+
+impl Default for StoreErrors {
+    fn default() -> Self {
+        Self {
+            policy: ErrorPolicy::default(),
+            backoff: DEFAULT_RETRY_BACKOFF,
+            handler: None,
+        }
+    }
+}
+
+impl StoreErrors {
+    /// Run `operation` according to the [`ErrorPolicy`].
+    ///
+    /// Returns `Ok(None)` if the operation failed and the request should continue, or the error
+    /// response if the request should fail.
+    async fn run<T, E, F, Fut>(self, mut operation: F) -> Result<Option<T>, Response<()>>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, StoreError<E>>>,
+        E: Debug + 'static,
+    {
+        let mut retries = match self.policy {
+            ErrorPolicy::Retry(retries) => retries,
+            _ => 0,
+        };
+        let mut backoff = self.backoff;
+        loop {
+            match operation().await {
+                Ok(value) => return Ok(Some(value)),
+                Err(err) if retries > 0 => {
+                    tracing::warn!(err = ?err, retries, ?backoff, "session store failed, retrying");
+                    retries -= 1;
+                    back_off(backoff).await;
+                    backoff = backoff.saturating_mul(2);
+                }
+                Err(err) if self.policy == ErrorPolicy::Fail => {
+                    let handler = self.handler.as_ref().map(|handler| {
+                        handler
+                            .downcast_ref::<Arc<ErrorHandler<E>>>()
+                            .expect("the error handler should take the error type of the store")
+                    });
+                    return Err(match handler {
+                        Some(handler) => handler(err),
+                        None => {
+                            tracing::error!(err = ?err, "session store failed");
+                            let mut resp = Response::new(());
+                            *resp.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                            resp
+                        }
+                    });
+                }
+                Err(err) => {
+                    tracing::error!(err = ?err, "session store failed");
+                    return Ok(None);
+                }
+            }
+        }
+    }
+}
+
+/// Wait `delay` before the next retry.
+///
+/// Waiting needs a timer, which is only available with the `tokio` feature. Without it, the
+/// operation is retried immediately.
+async fn back_off(delay: std::time::Duration) {
+    #[cfg(feature = "tokio")]
+    if !delay.is_zero() {
+        tokio::time::sleep(delay).await;
+    }
+    #[cfg(not(feature = "tokio"))]
+    let _ = delay;
+}
+
+impl Debug for StoreErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StoreErrors")
+            .field("policy", &self.policy)
+            .field("backoff", &self.backoff)
+            .field("handler", &self.handler.is_some())
+            .finish()
+    }
+}
+
+/// A boxed future that can be stored in [`ResponseFuture`].
+type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+/// Whether a session exists in the store.
+///
+/// The result is `Ok(None)` if the store failed and the request should continue, or the error
+/// response if it should fail.
+type CheckFuture = BoxFuture<Result<Option<bool>, Response<()>>>;
+
+/// Check whether a session exists in the store, handling its errors with [`StoreErrors`].
+type ExistsFn = dyn Fn(Id, StoreErrors) -> CheckFuture + Send + Sync;
+
+/// What to do with invalid sessions presented by the client.
+#[derive(Clone, Default)]
+pub(crate) struct InvalidSessions {
+    /// Remove malformed and rejected sessions from the client.
+    clear: bool,
+    /// Check that sessions not used by the handler exist, and remove unknown ones.
+    exists: Option<Arc<ExistsFn>>,
+}
+
+impl Debug for InvalidSessions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InvalidSessions")
+            .field("clear", &self.clear)
+            .field("exists", &self.exists.is_some())
+            .finish()
+    }
+}
+
+/// Refresh the expiration of the sessions not modified by the handler.
+#[derive(Clone)]
+pub(crate) struct Touch {
+    /// Touch the session, and return whether it exists.
+    touch: Arc<ExistsFn>,
+    throttle: Option<Arc<Throttle>>,
+}
+
+impl Debug for Touch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Touch")
+            .field("throttle", &self.throttle)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The store operations performed on the sessions not modified by the handler.
+#[derive(Clone)]
+pub(crate) struct Checks {
+    exists: Option<Arc<ExistsFn>>,
+    touch: Option<Touch>,
+    errors: StoreErrors,
+}
+
+impl Checks {
+    /// Start checking the session `id`, or return `None` if there is nothing to do.
+    ///
+    /// Touching the session also checks that it exists, so the existence check is only performed
+    /// when the touch is throttled.
+    fn start(&self, id: Id) -> Option<CheckFuture> {
+        let touch = self.touch.as_ref().filter(|touch| {
+            touch
+                .throttle
+                .as_ref()
+                .is_none_or(|throttle| throttle.allow(id))
+        });
+        match touch {
+            Some(touch) => {
+                tracing::debug!("touching the session");
+                let check = (touch.touch)(id, self.errors.clone());
+                let Some(throttle) = touch.throttle.clone() else {
+                    return Some(check);
+                };
+                // Only the sessions that exist are throttled, so that unknown ids do not fill
+                // the throttle.
+                Some(Box::pin(async move {
+                    let exists = check.await;
+                    if let Ok(Some(true)) = exists {
+                        throttle.record(id);
+                    }
+                    exists
+                }))
+            }
+            None => {
+                let exists = self.exists.as_ref()?;
+                tracing::debug!("checking that the session exists");
+                Some(exists(id, self.errors.clone()))
+            }
+        }
+    }
+}
+
+pin_project! {
     /// The future returned by [`SessionManager`].
-    pub struct ResponseFuture<F> {
+    pub struct ResponseFuture<F, B> {
         #[pin]
-        inner: F,
-        updater: Updater,
-        config: Config<'static>,
-        old_id: Option<Id>,
+        pub(crate) inner: F,
+        pub(crate) updater: Updater,
+        pub(crate) config: Arc<Config>,
+        pub(crate) value: CookieValue,
+        pub(crate) old_id: Option<Id>,
+        pub(crate) carrier: Carrier,
+        pub(crate) clock: Arc<dyn Clock>,
+        pub(crate) checks: Option<Checks>,
+        // The response of the inner service, kept while checking that the session exists.
+        pub(crate) checking: Option<(Response<B>, CheckFuture)>,
+    }
+}
+
+impl<F: Debug, B> Debug for ResponseFuture<F, B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ResponseFuture")
+            .field("inner", &self.inner)
+            .field("updater", &self.updater)
+            .field("config", &self.config)
+            .field("value", &self.value)
+            .field("old_id", &self.old_id)
+            .field("carrier", &self.carrier)
+            .field("clock", &self.clock)
+            .field("checking", &self.checking.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<F, B, Error> Future for ResponseFuture<F, B>
+where
+    F: Future<Output = Result<Response<B>, Error>>,
+    B: Default,
+{
+    type Output = Result<Response<B>, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut self_ = self.project();
+
+        let (mut resp, update) = loop {
+            if let Some((_, check)) = self_.checking {
+                let exists = ready!(check.as_mut().poll(cx));
+                let (resp, _) = self_
+                    .checking
+                    .take()
+                    .expect("the session should be checked");
+                let exists = match exists {
+                    Ok(exists) => exists,
+                    Err(error_resp) => {
+                        let (parts, ()) = error_resp.into_parts();
+                        return Poll::Ready(Ok(Response::from_parts(parts, B::default())));
+                    }
+                };
+                let update = if exists == Some(false) {
+                    tracing::debug!("removing the unknown session from the client");
+                    Some(SessionUpdate::Delete)
+                } else {
+                    refresh(self_.config, *self_.old_id, self_.clock.now())
+                };
+                break (resp, update);
+            }
+
+            let resp = ready!(self_.inner.as_mut().poll(cx))?;
+            let update = *self_
+                .updater
+                .lock()
+                .expect("updater should not be poisoned");
+            let check = match (update, *self_.old_id, self_.checks.as_ref()) {
+                (None, Some(id), Some(checks)) => checks.start(id),
+                _ => None,
+            };
+            match check {
+                Some(check) => *self_.checking = Some((resp, check)),
+                None => {
+                    break (
+                        resp,
+                        update.or_else(|| refresh(self_.config, *self_.old_id, self_.clock.now())),
+                    )
+                }
+            }
+        };
+
+        let now = self_.clock.now();
+        let value = match update {
+            Some(SessionUpdate::Set(_, Expiry::AtDateTime(deadline))) if deadline <= now => {
+                tracing::debug!("the session expired");
+                None
+            }
+            Some(SessionUpdate::Set(id, expiry)) => {
+                tracing::debug!("setting session {id}, expiring: {:?}", expiry);
+                self_
+                    .value
+                    .encode(self_.config.name(), id)
+                    .map(|value| (value, expiry))
+            }
+            Some(SessionUpdate::Delete) => None,
+            None => return Poll::Ready(Ok(resp)),
+        };
+        if value.is_none() {
+            tracing::debug!("deleting session");
+        }
+
+        self_
+            .carrier
+            .respond(resp.headers_mut(), self_.config, value, now);
+
+        Poll::Ready(Ok(resp))
+    }
+}
+
+/// The update that refreshes the expiry of an unmodified session, if
+/// [`ConfigBuilder::always_set_expiry`] is set.
+fn refresh(config: &Config, old_id: Option<Id>, now: OffsetDateTime) -> Option<SessionUpdate> {
+    config
+        .always_set_expiry
+        .and_then(|expiry| old_id.map(|id| SessionUpdate::set(id, expiry, now)))
+}
+
+/// Append `cookies` to the `Set-Cookie` headers.
+///
+/// Cookies set by the inner service are kept, unless they have the same name, path and domain as
+/// one of `cookies`, since the browser would overwrite them anyway.
+pub(crate) fn append_cookies(headers: &mut HeaderMap, cookies: Vec<Cookie<'_>>) {
+    let previous = match headers.entry(SET_COOKIE) {
+        Entry::Occupied(entry) => entry.remove_entry_mult().1.collect(),
+        Entry::Vacant(_) => Vec::new(),
+    };
+    for value in previous {
+        let overwritten = value
+            .to_str()
+            .ok()
+            .and_then(|value| Cookie::parse(value).ok())
+            .is_some_and(|previous| cookies.iter().any(|cookie| same_cookie(&previous, cookie)));
+        if overwritten {
+            tracing::debug!("removing a previous `Set-Cookie` for the session cookie");
+        } else {
+            headers.append(SET_COOKIE, value);
+        }
+    }
+
+    for cookie in cookies {
+        headers.append(
+            SET_COOKIE,
+            cookie
+                .to_string()
+                .try_into()
+                .expect("cookie should be valid"),
+        );
+    }
+}
+
+/// Whether the browser would consider `a` and `b` to be the same cookie.
+///
+/// See [RFC 6265, section 5.3](https://www.rfc-editor.org/rfc/rfc6265#section-5.3), step 11.
+fn same_cookie(a: &Cookie<'_>, b: &Cookie<'_>) -> bool {
+    let same_domain = match (a.domain(), b.domain()) {
+        (Some(a), Some(b)) => a.eq_ignore_ascii_case(b),
+        (a, b) => a == b,
+    };
+    a.name() == b.name() && a.path() == b.path() && same_domain
+}
+
+/// A layer for providing [`Session`] as a request extension.
+///
+/// # Examples
+///
+/// ```rust
+/// use tower_sesh::{MemoryStore, SessionManagerLayer};
+///
+/// let session_store: MemoryStore<()> = MemoryStore::default();
+/// let session_service = SessionManagerLayer::new(session_store, Default::default());
+/// ```
+pub struct SessionManagerLayer<Store, Tag = (), Err = UnknownError> {
+    store: Store,
+    config: ConfigSource,
+    protection: Arc<Protection>,
+    transports: Arc<[Transport]>,
+    invalid: InvalidSessions,
+    touch: Option<Touch>,
+    errors: StoreErrors,
+    warn_limit: Arc<WarnLimit>,
+    clock: Arc<dyn Clock>,
+    tag: PhantomData<fn() -> Tag>,
+    error: PhantomData<fn() -> Err>,
+}
+
+impl<Store: Clone, Tag, Err> Clone for SessionManagerLayer<Store, Tag, Err> {
+    fn clone(&self) -> Self {
+        Self {
+            store: self.store.clone(),
+            config: self.config.clone(),
+            protection: Arc::clone(&self.protection),
+            transports: Arc::clone(&self.transports),
+            invalid: self.invalid.clone(),
+            touch: self.touch.clone(),
+            errors: self.errors.clone(),
+            warn_limit: Arc::clone(&self.warn_limit),
+            clock: Arc::clone(&self.clock),
+            tag: PhantomData,
+            error: PhantomData,
+        }
+    }
+}
+
+impl<Store: Debug, Tag, Err> Debug for SessionManagerLayer<Store, Tag, Err> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SessionManagerLayer")
+            .field("store", &self.store)
+            .field("config", &self.config)
+            .field("protection", &self.protection)
+            .field("transports", &self.transports)
+            .field("invalid", &self.invalid)
+            .field("touch", &self.touch)
+            .field("errors", &self.errors)
+            .field("warn_limit", &self.warn_limit)
+            .field("clock", &self.clock)
+            .field("tag", &std::any::type_name::<Tag>())
+            .finish()
+    }
+}
+
+impl<Store> SessionManagerLayer<Store> {
+    /// Create a new [`SessionManagerLayer`].
+    ///
+    /// The `store` should implement [`SessionStore`], and be cloneable. The `config` holds the
+    /// configuration options for the session cookie.
+    ///
+    /// [`SessionStore`]: tower_sesh_core::SessionStore
+    pub fn new(store: Store, config: Config) -> Self {
+        Self {
+            store,
+            config: config.into(),
+            protection: Default::default(),
+            transports: transport::cookie(),
+            invalid: Default::default(),
+            touch: None,
+            errors: Default::default(),
+            warn_limit: Default::default(),
+            clock: Arc::new(SystemClock),
+            tag: PhantomData,
+            error: PhantomData,
+        }
     }
 }
 
-impl<F, ResBody, Error> Future for ResponseFuture<F>
-where
-    F: Future<Output = Result<Response<ResBody>, Error>>,
-{
-    type Output = Result<Response<ResBody>, Error>;
+impl<Store, Tag, Err> SessionManagerLayer<Store, Tag, Err> {
+    /// Tag the sessions provided by this layer with the `NewTag` type.
+    ///
+    /// Handlers then extract [`Session<Store, NewTag>`](Session) instead of `Session<Store>`.
+    /// This allows several layers with the same store type to be used on the same router, e.g.
+    /// for an authentication session and a separate preferences session. Each layer should use
+    /// a different cookie name.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use axum::{routing::get, Router};
+    /// use tower_sesh::{middleware::Config, MemoryStore, Session, SessionManagerLayer};
+    ///
+    /// struct Auth;
+    /// struct Preferences;
+    ///
+    /// async fn handler(
+    ///     auth: Session<MemoryStore<()>, Auth>,
+    ///     preferences: Session<MemoryStore<()>, Preferences>,
+    /// ) {
+    ///     // ...
+    /// }
+    ///
+    /// let auth_store: MemoryStore<()> = MemoryStore::default();
+    /// let preferences_store: MemoryStore<()> = MemoryStore::default();
+    /// let preferences_config = Config::builder().name("prefs").build().unwrap();
+    ///
+    /// let app: Router = Router::new()
+    ///     .route("/", get(handler))
+    ///     .layer(SessionManagerLayer::new(auth_store, Default::default()).with_tag::<Auth>())
+    ///     .layer(
+    ///         SessionManagerLayer::new(preferences_store, preferences_config)
+    ///             .with_tag::<Preferences>(),
+    ///     );
+    /// ```
+    pub fn with_tag<NewTag>(self) -> SessionManagerLayer<Store, NewTag, Err> {
+        self.retype()
+    }
+
+    /// Change the type parameters of the layer, keeping its options.
+    fn retype<NewTag, NewErr>(self) -> SessionManagerLayer<Store, NewTag, NewErr> {
+        SessionManagerLayer {
+            store: self.store,
+            config: self.config,
+            protection: self.protection,
+            transports: self.transports,
+            invalid: self.invalid,
+            touch: self.touch,
+            errors: self.errors,
+            warn_limit: self.warn_limit,
+            clock: self.clock,
+            tag: PhantomData,
+            error: PhantomData,
+        }
+    }
+
+    /// Compute the [`Config`] of each request with `config_fn`, instead of using the same
+    /// configuration for every request.
+    ///
+    /// The configuration is used both to read the session cookie from the request and to write
+    /// it in the response. This is useful to choose the cookie `Domain`, `Path` or `Secure`
+    /// attributes from the `Host` header or from a request extension, e.g. when serving several
+    /// tenants from the same application. `config_fn` can return a [`Config`] or an
+    /// [`Arc<Config>`] to share configurations between requests.
+    ///
+    /// This replaces the `config` given to [`SessionManagerLayer::new`].
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use http::header::HOST;
+    /// use tower_sesh::{middleware::Config, MemoryStore, SessionManagerLayer};
+    ///
+    /// let session_store: MemoryStore<()> = MemoryStore::default();
+    /// let session_service = SessionManagerLayer::new(session_store, Default::default())
+    ///     .with_config_fn(|parts| {
+    ///         let host = parts.headers.get(HOST).and_then(|host| host.to_str().ok());
+    ///         let builder = Config::builder();
+    ///         match host.and_then(|host| host.strip_suffix(".example.com")) {
+    ///             Some(tenant) => builder.domain(format!("{tenant}.example.com")),
+    ///             None => builder,
+    ///         }
+    ///         .build()
+    ///         .unwrap_or_default()
+    ///     });
+    /// ```
+    pub fn with_config_fn<F, C>(mut self, config_fn: F) -> Self
+    where
+        F: Fn(&Parts) -> C + Send + Sync + 'static,
+        C: Into<Arc<Config>>,
+    {
+        self.config = ConfigSource::dynamic(config_fn);
+        self
+    }
+
+    /// Carry the session with the given `transports`, in order of precedence.
+    ///
+    /// The first transport with which the client presents a session is used for the request, and
+    /// the session is sent back to the client with the same transport. When the client does not
+    /// present a session, new sessions are sent with every transport.
+    ///
+    /// By default, only the session cookie ([`Transport::Cookie`]) is used. Note that combining
+    /// the cookie with a header transport exposes new sessions to scripts that can read the
+    /// response headers, which defeats [`HttpOnly`](ConfigBuilder::http_only) cookies.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `transports` is empty.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use http::HeaderName;
+    /// use tower_sesh::{transport::Transport, MemoryStore, SessionManagerLayer};
+    ///
+    /// let session_store: MemoryStore<()> = MemoryStore::default();
+    /// let session_service = SessionManagerLayer::new(session_store, Default::default())
+    ///     .with_transports([
+    ///         Transport::Bearer {
+    ///             response_header: HeaderName::from_static("x-session-id"),
+    ///         },
+    ///         Transport::Header(HeaderName::from_static("x-session-id")),
+    ///     ]);
+    /// ```
+    pub fn with_transports(mut self, transports: impl IntoIterator<Item = Transport>) -> Self {
+        let transports: Arc<[Transport]> = transports.into_iter().collect();
+        assert!(
+            !transports.is_empty(),
+            "the session should have at least one transport"
+        );
+        self.transports = transports;
+        self
+    }
+
+    /// Remove invalid sessions from the client.
+    ///
+    /// By default, a session that is malformed, or that fails verification with
+    /// [`SessionManagerLayer::with_signed`] or [`SessionManagerLayer::with_private`], is only
+    /// logged and ignored, so the client keeps sending it. With this option, the session is also
+    /// removed from the client, unless the handler creates a new one.
+    pub fn with_clear_invalid(mut self) -> Self {
+        self.invalid.clear = true;
+        self
+    }
+
+    /// Remove sessions that do not exist in the store from the client.
+    ///
+    /// When the handler does not use the session, the middleware loads it from the store after
+    /// the handler has run, and removes it from the client if it does not exist (or expired).
+    /// Sessions used by the handler are removed by [`Session::load`] already. If the store fails,
+    /// the session is kept, or the request fails, according to the
+    /// [`SessionManagerLayer::with_error_policy`].
+    ///
+    /// This implies [`SessionManagerLayer::with_clear_invalid`].
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use tower_sesh::{Expires, MemoryStore, SessionManagerLayer};
+    ///
+    /// #[derive(Clone)]
+    /// struct User;
+    ///
+    /// impl Expires for User {}
+    ///
+    /// let session_store: MemoryStore<User> = MemoryStore::default();
+    /// let session_service = SessionManagerLayer::new(session_store, Default::default())
+    ///     .with_existence_check::<User>();
+    /// ```
+    pub fn with_existence_check<R>(
+        mut self,
+    ) -> SessionManagerLayer<Store, Tag, KnownError<Store::Error>>
+    where
+        R: Send + Sync + 'static,
+        Store: SessionStore<R> + Clone + 'static,
+        Store::Error: Debug + 'static,
+        Err: StoreErrorType<Store::Error>,
+    {
+        let store = self.store.clone();
+        self.invalid = InvalidSessions {
+            clear: true,
+            exists: Some(Arc::new(move |id, errors| {
+                let store = store.clone();
+                Box::pin(errors.run(move || {
+                    let mut store = store.clone();
+                    async move {
+                        store
+                            .load(&id)
+                            .await
+                            .map(|record| record.is_some())
+                            .map_err(StoreError::Load)
+                    }
+                }))
+            })),
+        };
+        self.retype()
+    }
+
+    /// Refresh the expiration of unmodified sessions in the store, for sliding sessions.
+    ///
+    /// [`ConfigBuilder::always_set_expiry`] only refreshes the expiration of the cookie, so the
+    /// cookie can outlive the session in the store. With this option, when the handler does not
+    /// modify the session, the middleware also refreshes it in the store with
+    /// [`SessionStore::touch`] after the handler has run. If `throttle` is set, each session is
+    /// touched at most once per `throttle`.
+    ///
+    /// Sessions that no longer exist are removed from the client, like with
+    /// [`SessionManagerLayer::with_existence_check`]. If the store fails, the error is handled
+    /// according to the [`SessionManagerLayer::with_error_policy`].
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use std::time::Duration;
+    ///
+    /// use tower_sesh::{middleware::Config, Expires, Expiry, MemoryStore, SessionManagerLayer};
+    ///
+    /// #[derive(Clone)]
+    /// struct User;
+    ///
+    /// impl Expires for User {
+    ///     fn expires(&self) -> Expiry {
+    ///         Expiry::OnInactivity(time::Duration::hours(1))
+    ///     }
+    /// }
+    ///
+    /// let config = Config::builder()
+    ///     .always_set_expiry(Some(Expiry::OnInactivity(time::Duration::hours(1))))
+    ///     .build()
+    ///     .unwrap();
+    /// let session_store: MemoryStore<User> = MemoryStore::default();
+    /// let session_service = SessionManagerLayer::new(session_store, config)
+    ///     .with_touch::<User>(Some(Duration::from_secs(5 * 60)));
+    /// ```
+    pub fn with_touch<R>(
+        mut self,
+        throttle: Option<std::time::Duration>,
+    ) -> SessionManagerLayer<Store, Tag, KnownError<Store::Error>>
+    where
+        R: Send + Sync + 'static,
+        Store: SessionStore<R> + Clone + 'static,
+        Store::Error: Debug + 'static,
+        Err: StoreErrorType<Store::Error>,
+    {
+        let store = self.store.clone();
+        self.touch = Some(Touch {
+            touch: Arc::new(move |id, errors| {
+                let store = store.clone();
+                Box::pin(errors.run(move || {
+                    let mut store = store.clone();
+                    async move { store.touch(&id).await.map_err(StoreError::Touch) }
+                }))
+            }),
+            throttle: throttle.map(|period| Arc::new(Throttle::new(period))),
+        });
+        self.retype()
+    }
+
+    /// Handle the errors of the store according to `policy`.
+    ///
+    /// By default, the errors of the store operations performed by the middleware are logged, and
+    /// the request continues as if the operation was not performed. See [`ErrorPolicy`] for the
+    /// other policies.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use tower_sesh::{middleware::ErrorPolicy, Expires, MemoryStore, SessionManagerLayer};
+    ///
+    /// #[derive(Clone)]
+    /// struct User;
+    ///
+    /// impl Expires for User {}
+    ///
+    /// let session_store: MemoryStore<User> = MemoryStore::default();
+    /// let session_service = SessionManagerLayer::new(session_store, Default::default())
+    ///     .with_error_policy(ErrorPolicy::Retry(2))
+    ///     .with_existence_check::<User>();
+    /// ```
+    pub fn with_error_policy(mut self, policy: ErrorPolicy) -> Self {
+        self.errors.policy = policy;
+        self
+    }
+
+    /// Wait `backoff` before retrying a failed store operation, doubling the delay before each
+    /// next retry.
+    ///
+    /// This applies to the [`ErrorPolicy::Retry`] policy. The default backoff is 10 milliseconds,
+    /// and a zero backoff retries immediately. Waiting requires the `tokio` feature and the tokio
+    /// runtime, with its time driver enabled. Without the `tokio` feature, the backoff is ignored
+    /// and the operations are retried immediately.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use std::time::Duration;
+    ///
+    /// use tower_sesh::{middleware::ErrorPolicy, Expires, MemoryStore, SessionManagerLayer};
+    ///
+    /// #[derive(Clone)]
+    /// struct User;
+    ///
+    /// impl Expires for User {}
+    ///
+    /// let session_store: MemoryStore<User> = MemoryStore::default();
+    /// let session_service = SessionManagerLayer::new(session_store, Default::default())
+    ///     .with_error_policy(ErrorPolicy::Retry(3))
+    ///     .with_retry_backoff(Duration::from_millis(50))
+    ///     .with_existence_check::<User>();
+    /// ```
+    pub fn with_retry_backoff(mut self, backoff: std::time::Duration) -> Self {
+        self.errors.backoff = backoff;
+        self
+    }
+
+    /// Fail the request with the response of `handler` when the store fails.
+    ///
+    /// The response replaces the one of the inner service, and its body is left empty. This sets
+    /// the [`ErrorPolicy::Fail`] policy. The `R` type is the session record, which determines the
+    /// error type of the store. It must give the same error type as the records of
+    /// [`SessionManagerLayer::with_existence_check`] and [`SessionManagerLayer::with_touch`], or
+    /// the layer does not compile, see [`StoreErrorType`].
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use http::{Response, StatusCode};
+    /// use tower_sesh::{Expires, MemoryStore, SessionManagerLayer};
+    ///
+    /// #[derive(Clone)]
+    /// struct User;
+    ///
+    /// impl Expires for User {}
+    ///
+    /// let session_store: MemoryStore<User> = MemoryStore::default();
+    /// let session_service = SessionManagerLayer::new(session_store, Default::default())
+    ///     .with_error_handler::<User, _>(|err| {
+    ///         tracing::error!(?err, "session store failed");
+    ///         let mut resp = Response::new(());
+    ///         *resp.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+    ///         resp
+    ///     })
+    ///     .with_existence_check::<User>();
+    /// ```
+    pub fn with_error_handler<R, F>(
+        mut self,
+        handler: F,
+    ) -> SessionManagerLayer<Store, Tag, KnownError<Store::Error>>
+    where
+        R: Send + Sync,
+        Store: SessionStore<R>,
+        Store::Error: 'static,
+        Err: StoreErrorType<Store::Error>,
+        F: Fn(StoreError<Store::Error>) -> Response<()> + Send + Sync + 'static,
+    {
+        let handler: Arc<ErrorHandler<Store::Error>> = Arc::new(handler);
+        self.errors.policy = ErrorPolicy::Fail;
+        self.errors.handler = Some(Arc::new(handler));
+        self.retype()
+    }
+
+    /// Log at most `max` warnings about possibly suspicious requests per `period`.
+    ///
+    /// Requests with malformed or tampered sessions are logged as possibly suspicious activity.
+    /// The warnings are rate limited so that scanners cannot flood the logs, and the number of
+    /// suppressed warnings is logged afterwards. By default, at most 10 warnings are logged per
+    /// second.
+    pub fn with_warning_limit(mut self, max: u32, period: std::time::Duration) -> Self {
+        self.warn_limit = Arc::new(WarnLimit::new(max, period));
+        self
+    }
+
+    /// Compute the expiry of the sessions with `clock`, instead of the [`SystemClock`].
+    ///
+    /// This is mostly useful to test expiration with a [`ManualClock`], which should also be
+    /// given to the store.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use tower_sesh::{ManualClock, MemoryStore, SessionManagerLayer};
+    ///
+    /// let clock = ManualClock::default();
+    /// let session_store: MemoryStore<()> = MemoryStore::with_clock(clock.clone());
+    /// let session_service =
+    ///     SessionManagerLayer::new(session_store, Default::default()).with_clock(clock);
+    /// ```
+    ///
+    /// [`ManualClock`]: tower_sesh_core::clock::ManualClock
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Sign the session cookie with the given keys.
+    ///
+    /// The session id is signed with the [current key][KeyRing::current], and cookies are
+    /// verified against every key of the ring. Cookies that fail verification are treated like
+    /// malformed session ids: they are logged, and the store is never queried for them.
+    ///
+    /// This replaces [`SessionManagerLayer::with_private`] if it was called before.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use tower_sesh::{
+    ///     protection::{Key, KeyRing},
+    ///     MemoryStore, SessionManagerLayer,
+    /// };
+    ///
+    /// let keys = KeyRing::new(Key::generate()).with_previous(Key::generate());
+    /// let session_store: MemoryStore<()> = MemoryStore::default();
+    /// let session_service =
+    ///     SessionManagerLayer::new(session_store, Default::default()).with_signed(keys);
+    /// ```
+    #[cfg(feature = "signed")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "signed")))]
+    pub fn with_signed(mut self, keys: impl Into<KeyRing>) -> Self {
+        self.protection = Arc::new(Protection::Signed(keys.into()));
+        self
+    }
+
+    /// Encrypt the session cookie with the given keys.
+    ///
+    /// The session id is encrypted with AES-256-GCM using the [current key][KeyRing::current],
+    /// and cookies are decrypted with every key of the ring. The cookie name and the optional
+    /// `purpose` are authenticated alongside the id, so a cookie issued for one purpose (or under
+    /// another cookie name) is rejected for any other. Cookies that cannot be decrypted are
+    /// treated like malformed session ids: they are logged, and the store is never queried for
+    /// them.
+    ///
+    /// This replaces [`SessionManagerLayer::with_signed`] if it was called before.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use tower_sesh::{protection::Key, MemoryStore, SessionManagerLayer};
+    ///
+    /// let session_store: MemoryStore<()> = MemoryStore::default();
+    /// let session_service = SessionManagerLayer::new(session_store, Default::default())
+    ///     .with_private(Key::generate(), Some("authentication"));
+    /// ```
+    #[cfg(feature = "private")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "private")))]
+    pub fn with_private(mut self, keys: impl Into<KeyRing>, purpose: Option<&str>) -> Self {
+        self.protection = Arc::new(Protection::Private(Cipher {
+            keys: keys.into(),
+            purpose: purpose.map(Into::into),
+        }));
+        self
+    }
+}
+
+impl<S, Store, Tag, Err> Layer<S> for SessionManagerLayer<Store, Tag, Err>
+where
+    Store: Clone,
+{
+    type Service = SessionManager<Store, S, Tag>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        SessionManager {
+            inner,
+            store: self.store.clone(),
+            config: self.config.clone(),
+            protection: Arc::clone(&self.protection),
+            transports: Arc::clone(&self.transports),
+            invalid: self.invalid.clone(),
+            touch: self.touch.clone(),
+            errors: self.errors.clone(),
+            warn_limit: Arc::clone(&self.warn_limit),
+            clock: Arc::clone(&self.clock),
+            tag: PhantomData,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+    use axum::body::Body;
+    use tower::{ServiceBuilder, ServiceExt};
+    use tower_sesh_core::{clock::ManualClock, Expires};
+    use tower_sesh_memory_store::MemoryStore;
+
+    use super::*;
+
+    #[derive(Debug, Clone)]
+    struct Record {
+        foo: i32,
+    }
+    impl Expires for Record {}
+
+    async fn handler(mut req: Request<Body>) -> anyhow::Result<Response<Body>> {
+        let session = req
+            .extensions_mut()
+            .remove::<Session<MemoryStore<Record>>>()
+            .ok_or(anyhow!("Missing session"))?;
+
+        let session_state = session.clone().load().await?;
+        if let Some(session_state) = session_state {
+            session_state
+                .update(|data| {
+                    data.foo += 1;
+                })
+                .await?;
+        } else {
+            session.create(Record { foo: 42 }).await?;
+        }
+
+        Ok(Response::new(Body::empty()))
+    }
+
+    async fn noop_handler(_: Request<Body>) -> anyhow::Result<Response<Body>> {
+        Ok(Response::new(Body::empty()))
+    }
+
+    #[tokio::test]
+    async fn basic_service_test() -> anyhow::Result<()> {
+        let session_store: MemoryStore<Record> = MemoryStore::default();
+        let session_layer = SessionManagerLayer::new(session_store, Default::default());
+        let svc = ServiceBuilder::new()
+            .layer(session_layer.clone())
+            .service_fn(handler);
+
+        let noop_svc = ServiceBuilder::new()
+            .layer(session_layer)
+            .service_fn(noop_handler);
+
+        let req = Request::builder().body(Body::empty())?;
+        let res = svc.clone().oneshot(req).await?;
+
+        let session = res.headers().get(http::header::SET_COOKIE);
+        assert!(session.is_some());
+
+        let req = Request::builder()
+            .header(http::header::COOKIE, session.unwrap())
+            .body(Body::empty())?;
+        let res = noop_svc.oneshot(req).await?;
+
+        assert!(res.headers().get(http::header::SET_COOKIE).is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn bogus_cookie_test() -> anyhow::Result<()> {
+        let session_store: MemoryStore<Record> = MemoryStore::default();
+        let session_layer = SessionManagerLayer::new(session_store, Default::default());
+        let svc = ServiceBuilder::new()
+            .layer(session_layer)
+            .service_fn(handler);
+
+        let req = Request::builder().body(Body::empty())?;
+        let res = svc.clone().oneshot(req).await?;
+
+        assert!(res.headers().get(http::header::SET_COOKIE).is_some());
+
+        let req = Request::builder()
+            .header(http::header::COOKIE, "id=bogus")
+            .body(Body::empty())?;
+        let res = svc.oneshot(req).await?;
+
+        assert!(res.headers().get(http::header::SET_COOKIE).is_some());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn clear_invalid_test() -> anyhow::Result<()> {
+        let session_store: MemoryStore<Record> = MemoryStore::default();
+        let session_layer =
+            SessionManagerLayer::new(session_store, Default::default()).with_clear_invalid();
+        let noop_svc = ServiceBuilder::new()
+            .layer(session_layer.clone())
+            .service_fn(noop_handler);
+        let svc = ServiceBuilder::new()
+            .layer(session_layer)
+            .service_fn(handler);
+
+        for cookie in ["id=bogus", "id="] {
+            let req = Request::builder()
+                .header(http::header::COOKIE, cookie)
+                .body(Body::empty())?;
+            let res = noop_svc.clone().oneshot(req).await?;
+            let [removal] = &set_cookies(&res)[..] else {
+                panic!("expected a removal cookie");
+            };
+            assert_eq!(removal.name(), "id");
+            assert_eq!(removal.value(), "");
+            assert_eq!(removal.max_age(), Some(time::Duration::ZERO));
+        }
+
+        // A well-formed session is kept, even if it does not exist.
+        let req = Request::builder()
+            .header(http::header::COOKIE, "id=AAAAAAAAAAAAAAAAAAAAAA")
+            .body(Body::empty())?;
+        let res = noop_svc.oneshot(req).await?;
+        assert!(res.headers().get(SET_COOKIE).is_none());
+
+        // The removal is replaced by a new session.
+        let req = Request::builder()
+            .header(http::header::COOKIE, "id=bogus")
+            .body(Body::empty())?;
+        let res = svc.oneshot(req).await?;
+        assert!(cookie_value_matches(&res, |s| !s.starts_with("id=;")));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn existence_check_test() -> anyhow::Result<()> {
+        let session_store: MemoryStore<Record> = MemoryStore::default();
+        let session_layer = SessionManagerLayer::new(session_store, Default::default())
+            .with_existence_check::<Record>();
+        let noop_svc = ServiceBuilder::new()
+            .layer(session_layer.clone())
+            .service_fn(noop_handler);
+        let svc = ServiceBuilder::new()
+            .layer(session_layer)
+            .service_fn(handler);
+
+        let req = Request::builder().body(Body::empty())?;
+        let res = svc.clone().oneshot(req).await?;
+        let [cookie] = &set_cookies(&res)[..] else {
+            panic!("expected a session cookie");
+        };
+
+        // Existing sessions are kept.
+        let req = Request::builder()
+            .header(http::header::COOKIE, cookie.stripped().to_string())
+            .body(Body::empty())?;
+        let res = noop_svc.clone().oneshot(req).await?;
+        assert!(res.headers().get(SET_COOKIE).is_none());
+
+        for cookie in ["id=AAAAAAAAAAAAAAAAAAAAAA", "id=bogus"] {
+            let req = Request::builder()
+                .header(http::header::COOKIE, cookie)
+                .body(Body::empty())?;
+            let res = noop_svc.clone().oneshot(req).await?;
+            let [removal] = &set_cookies(&res)[..] else {
+                panic!("expected a removal cookie");
+            };
+            assert_eq!(removal.value(), "");
+        }
+
+        Ok(())
+    }
+
+    #[derive(Debug)]
+    struct StoreDown;
+
+    /// A store that fails the given number of times.
+    ///
+    /// Loaded sessions never exist, while touched sessions always do.
+    #[derive(Debug, Clone)]
+    struct FlakyStore {
+        failures: Arc<Mutex<u32>>,
+        touches: Arc<Mutex<u32>>,
+    }
+
+    impl FlakyStore {
+        fn new(failures: u32) -> Self {
+            Self {
+                failures: Arc::new(Mutex::new(failures)),
+                touches: Default::default(),
+            }
+        }
+
+        fn fail(&self) -> Result<(), StoreDown> {
+            let mut failures = self.failures.lock().unwrap();
+            if *failures > 0 {
+                *failures -= 1;
+                return Err(StoreDown);
+            }
+            Ok(())
+        }
+    }
+
+    impl SessionStore<Record> for FlakyStore {
+        type Error = StoreDown;
+
+        async fn create(&mut self, _record: &Record) -> Result<Id, Self::Error> {
+            unimplemented!()
+        }
+
+        async fn save(&mut self, _id: &Id, _record: &Record) -> Result<bool, Self::Error> {
+            unimplemented!()
+        }
+
+        async fn save_or_create(&mut self, _id: &Id, _record: &Record) -> Result<(), Self::Error> {
+            unimplemented!()
+        }
+
+        async fn load(&mut self, _id: &Id) -> Result<Option<Record>, Self::Error> {
+            self.fail()?;
+            Ok(None)
+        }
+
+        async fn delete(&mut self, _id: &Id) -> Result<bool, Self::Error> {
+            unimplemented!()
+        }
+
+        async fn touch(&mut self, _id: &Id) -> Result<bool, Self::Error> {
+            self.fail()?;
+            *self.touches.lock().unwrap() += 1;
+            Ok(true)
+        }
+    }
+
+    #[tokio::test]
+    async fn error_policy_test() -> anyhow::Result<()> {
+        async fn call<Err>(
+            layer: SessionManagerLayer<FlakyStore, (), Err>,
+        ) -> anyhow::Result<Response<Body>> {
+            let svc = ServiceBuilder::new().layer(layer).service_fn(noop_handler);
+            let req = Request::builder()
+                .header(http::header::COOKIE, "id=AAAAAAAAAAAAAAAAAAAAAA")
+                .body(Body::empty())?;
+            svc.oneshot(req).await
+        }
+
+        // By default, the error is logged and the session is kept.
+        let layer = SessionManagerLayer::new(FlakyStore::new(1), Default::default())
+            .with_existence_check::<Record>();
+        let res = call(layer).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.headers().get(SET_COOKIE).is_none());
+
+        // Retried operations can succeed.
+        let layer = SessionManagerLayer::new(FlakyStore::new(2), Default::default())
+            .with_existence_check::<Record>()
+            .with_error_policy(ErrorPolicy::Retry(2));
+        let res = call(layer).await?;
+        let [removal] = &set_cookies(&res)[..] else {
+            panic!("expected a removal cookie");
+        };
+        assert_eq!(removal.value(), "");
+
+        let layer = SessionManagerLayer::new(FlakyStore::new(3), Default::default())
+            .with_error_policy(ErrorPolicy::Retry(2))
+            .with_existence_check::<Record>();
+        let res = call(layer).await?;
+        assert!(res.headers().get(SET_COOKIE).is_none());
+
+        // The delay between retries doubles.
+        #[cfg(feature = "tokio")]
+        {
+            let layer = SessionManagerLayer::new(FlakyStore::new(2), Default::default())
+                .with_error_policy(ErrorPolicy::Retry(2))
+                .with_retry_backoff(std::time::Duration::from_millis(20))
+                .with_existence_check::<Record>();
+            let start = std::time::Instant::now();
+            call(layer).await?;
+            assert!(start.elapsed() >= std::time::Duration::from_millis(60));
+        }
+
+        let layer = SessionManagerLayer::new(FlakyStore::new(1), Default::default())
+            .with_error_policy(ErrorPolicy::Fail)
+            .with_existence_check::<Record>();
+        let res = call(layer).await?;
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        let layer = SessionManagerLayer::new(FlakyStore::new(1), Default::default())
+            .with_existence_check::<Record>()
+            .with_error_handler::<Record, _>(|err| {
+                assert!(matches!(err, StoreError::Load(StoreDown)));
+                let mut resp = Response::new(());
+                *resp.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+                resp
+            });
+        let res = call(layer).await?;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(res.headers().get(SET_COOKIE).is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn idle_and_absolute_max_age() {
+        let config = Config::default();
+        let now = OffsetDateTime::now_utc();
+        let max_age = |expiry| {
+            config
+                .build_cookie("id".into(), String::new(), expiry, now)
+                .max_age()
+        };
+
+        let idle =
+            Expiry::OnInactivityUntil(time::Duration::minutes(5), now + time::Duration::days(1));
+        assert_eq!(max_age(idle), Some(time::Duration::minutes(5)));
+
+        let absolute =
+            Expiry::OnInactivityUntil(time::Duration::days(1), now + time::Duration::minutes(5));
+        assert_eq!(max_age(absolute), Some(time::Duration::minutes(5)));
+    }
+
+    #[test]
+    fn cookie_lifetime() {
+        let config = Config::default();
+        let now = OffsetDateTime::now_utc().replace_nanosecond(0).unwrap();
+        let cookie = |expiry| config.build_cookie("id".into(), String::new(), expiry, now);
+
+        let inactivity = cookie(Expiry::OnInactivity(time::Duration::hours(1)));
+        assert_eq!(inactivity.max_age(), Some(time::Duration::hours(1)));
+        assert_eq!(
+            inactivity.expires_datetime(),
+            Some(now + time::Duration::hours(1))
+        );
+
+        let far = cookie(Expiry::AtDateTime(now + time::Duration::days(1000)));
+        assert_eq!(far.max_age(), Some(MAX_COOKIE_AGE));
+        assert_eq!(far.expires_datetime(), Some(now + MAX_COOKIE_AGE));
+
+        let past = cookie(Expiry::AtDateTime(now - time::Duration::hours(1)));
+        assert_eq!(past.max_age(), Some(time::Duration::ZERO));
+        assert_eq!(past.expires_datetime(), Some(OffsetDateTime::UNIX_EPOCH));
+
+        let session_end = cookie(Expiry::OnSessionEnd);
+        assert_eq!(session_end.max_age(), None);
+        assert_eq!(session_end.expires(), None);
+    }
+
+    #[tokio::test]
+    async fn clock_test() -> anyhow::Result<()> {
+        let clock = ManualClock::new(OffsetDateTime::UNIX_EPOCH + time::Duration::days(365));
+        let session_store: MemoryStore<Record> = MemoryStore::with_clock(clock.clone());
+        let config = Config::builder()
+            .always_set_expiry(Some(Expiry::OnInactivity(time::Duration::hours(1))))
+            .build()?;
+        let session_layer = SessionManagerLayer::new(session_store, config)
+            .with_clock(clock.clone())
+            .with_touch::<Record>(None);
+        let svc = ServiceBuilder::new()
+            .layer(session_layer.clone())
+            .service_fn(handler);
+        let noop_svc = ServiceBuilder::new()
+            .layer(session_layer)
+            .service_fn(noop_handler);
+
+        let req = Request::builder().body(Body::empty())?;
+        let res = svc.oneshot(req).await?;
+        let [cookie] = &set_cookies(&res)[..] else {
+            panic!("expected a session cookie");
+        };
+
+        clock.advance(time::Duration::minutes(30));
+        let req = Request::builder()
+            .header(http::header::COOKIE, cookie.stripped().to_string())
+            .body(Body::empty())?;
+        let res = noop_svc.oneshot(req).await?;
+        let [refreshed] = &set_cookies(&res)[..] else {
+            panic!("expected a refreshed session cookie");
+        };
+        assert_eq!(
+            refreshed.expires_datetime(),
+            Some(clock.now() + time::Duration::hours(1))
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn expired_session_test() -> anyhow::Result<()> {
+        let session_store: MemoryStore<Record> = MemoryStore::default();
+        let svc = ServiceBuilder::new()
+            .layer(SessionManagerLayer::new(session_store, Default::default()))
+            .service_fn(|mut req: Request<Body>| async move {
+                let session = req
+                    .extensions_mut()
+                    .remove::<Session<MemoryStore<Record>>>()
+                    .ok_or(anyhow!("Missing session"))?;
+                let expired = OffsetDateTime::now_utc() - time::Duration::hours(1);
+                session
+                    .create_with_expiry(Record { foo: 42 }, Expiry::AtDateTime(expired))
+                    .await?;
+                anyhow::Ok(Response::new(Body::empty()))
+            });
+
+        let req = Request::builder().body(Body::empty())?;
+        let res = svc.oneshot(req).await?;
+        let [removal] = &set_cookies(&res)[..] else {
+            panic!("expected a removal cookie");
+        };
+        assert_eq!(removal.value(), "");
+        assert_eq!(removal.max_age(), Some(time::Duration::ZERO));
+        assert_eq!(removal.expires_datetime(), Some(OffsetDateTime::UNIX_EPOCH));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn touch_test() -> anyhow::Result<()> {
+        let session_store: MemoryStore<Record> = MemoryStore::default();
+        let config = Config::builder()
+            .always_set_expiry(Some(Expiry::OnInactivity(time::Duration::hours(1))))
+            .build()?;
+        let session_layer =
+            SessionManagerLayer::new(session_store, config).with_touch::<Record>(None);
+        let noop_svc = ServiceBuilder::new()
+            .layer(session_layer.clone())
+            .service_fn(noop_handler);
+        let svc = ServiceBuilder::new()
+            .layer(session_layer)
+            .service_fn(handler);
+
+        let req = Request::builder().body(Body::empty())?;
+        let res = svc.oneshot(req).await?;
+        let [cookie] = &set_cookies(&res)[..] else {
+            panic!("expected a session cookie");
+        };
+
+        // Existing sessions are refreshed.
+        let req = Request::builder()
+            .header(http::header::COOKIE, cookie.stripped().to_string())
+            .body(Body::empty())?;
+        let res = noop_svc.clone().oneshot(req).await?;
+        let [refreshed] = &set_cookies(&res)[..] else {
+            panic!("expected a refreshed session cookie");
+        };
+        assert_eq!(refreshed.value(), cookie.value());
+        assert_eq!(refreshed.max_age(), Some(time::Duration::hours(1)));
+
+        // Unknown sessions are removed.
+        let req = Request::builder()
+            .header(http::header::COOKIE, "id=AAAAAAAAAAAAAAAAAAAAAA")
+            .body(Body::empty())?;
+        let res = noop_svc.oneshot(req).await?;
+        let [removal] = &set_cookies(&res)[..] else {
+            panic!("expected a removal cookie");
+        };
+        assert_eq!(removal.value(), "");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn touch_throttle_test() -> anyhow::Result<()> {
+        let store = FlakyStore::new(0);
+        let session_layer = SessionManagerLayer::new(store.clone(), Default::default())
+            .with_touch::<Record>(Some(std::time::Duration::from_secs(3600)));
+        let svc = ServiceBuilder::new()
+            .layer(session_layer)
+            .service_fn(noop_handler);
+
+        for cookie in [
+            "id=AAAAAAAAAAAAAAAAAAAAAA",
+            "id=AAAAAAAAAAAAAAAAAAAAAA",
+            "id=AQAAAAAAAAAAAAAAAAAAAA",
+        ] {
+            let req = Request::builder()
+                .header(http::header::COOKIE, cookie)
+                .body(Body::empty())?;
+            let res = svc.clone().oneshot(req).await?;
+            assert!(res.headers().get(SET_COOKIE).is_none());
+        }
+        assert_eq!(*store.touches.lock().unwrap(), 2);
+
+        // Failed touches do not count.
+        let store = FlakyStore::new(1);
+        let session_layer = SessionManagerLayer::new(store.clone(), Default::default())
+            .with_touch::<Record>(Some(std::time::Duration::from_secs(3600)));
+        let svc = ServiceBuilder::new()
+            .layer(session_layer)
+            .service_fn(noop_handler);
+        for _ in 0..3 {
+            let req = Request::builder()
+                .header(http::header::COOKIE, "id=AAAAAAAAAAAAAAAAAAAAAA")
+                .body(Body::empty())?;
+            svc.clone().oneshot(req).await?;
+        }
+        assert_eq!(*store.touches.lock().unwrap(), 1);
+
+        // Failed touches are handled with the error policy.
+        let session_layer = SessionManagerLayer::new(FlakyStore::new(1), Default::default())
+            .with_error_policy(ErrorPolicy::Fail)
+            .with_touch::<Record>(None);
+        let svc = ServiceBuilder::new()
+            .layer(session_layer)
+            .service_fn(noop_handler);
+        let req = Request::builder()
+            .header(http::header::COOKIE, "id=AAAAAAAAAAAAAAAAAAAAAA")
+            .body(Body::empty())?;
+        let res = svc.oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn no_set_cookie_test() -> anyhow::Result<()> {
+        let session_store: MemoryStore<Record> = MemoryStore::default();
+        let session_layer = SessionManagerLayer::new(session_store, Default::default());
+        let svc = ServiceBuilder::new()
+            .layer(session_layer)
+            .service_fn(noop_handler);
+
+        let req = Request::builder().body(Body::empty())?;
+        let res = svc.oneshot(req).await?;
+
+        assert!(res.headers().get(http::header::SET_COOKIE).is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn custom_config() -> anyhow::Result<()> {
+        let session_store: MemoryStore<Record> = MemoryStore::default();
+
+        let session_config = Config::builder()
+            .name(String::from("my.sid"))
+            .http_only(false)
+            .same_site(SameSite::Lax)
+            .secure(false)
+            .path("/foo/bar")
+            .domain(format!("{}.com", "example"))
+            .always_set_expiry(Some(Expiry::OnInactivity(time::Duration::hours(2))))
+            .build()?;
+        let session_layer = SessionManagerLayer::new(session_store, session_config);
+        let svc = ServiceBuilder::new()
+            .layer(session_layer.clone())
+            .service_fn(handler);
+        let noop_svc = ServiceBuilder::new()
+            .layer(session_layer)
+            .service_fn(noop_handler);
+
+        let req = Request::builder().body(Body::empty())?;
+        let res = svc.oneshot(req).await?;
+
+        assert!(cookie_value_matches(&res, |s| s.contains("my.sid=")));
+        assert!(cookie_value_matches(&res, |s| s.contains("SameSite=Lax")));
+        assert!(cookie_value_matches(&res, |s| !s.contains("Secure")));
+        assert!(cookie_value_matches(&res, |s| s.contains("Path=/foo/bar")));
+        assert!(cookie_value_matches(&res, |s| s.contains("Domain=example.com")));
 
-    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        let self_ = self.project();
-        let mut resp = match self_.inner.poll(cx) {
-            Poll::Ready(r) => r,
-            Poll::Pending => return Poll::Pending,
-        }?;
-
-        let update = self_
-            .updater
-            .lock()
-            .expect("updater should not be poisoned")
-            .or_else(|| {
-                self_
-                    .config
-                    .always_set_expiry
-                    .and_then(|expiry| self_.old_id.map(|id| SessionUpdate::Set(id, expiry)))
-            });
-        match update {
-            Some(SessionUpdate::Delete) => {
-                tracing::debug!("deleting session");
-                let cookie = self_.config.build_cookie(
-                    *self_.old_id,
-                    Expiry::AtDateTime(
-                        // The Year 2000 in UNIX time.
-                        time::OffsetDateTime::from_unix_timestamp(946684800)
-                            .expect("year 2000 should be in range"),
-                    ),
-                );
-                resp.headers_mut().insert(
-                    http::header::SET_COOKIE,
-                    cookie
-                        .to_string()
-                        .try_into()
-                        .expect("cookie should be valid"),
-                );
-            }
-            Some(SessionUpdate::Set(id, expiry)) => {
-                tracing::debug!("setting session {id}, expiring: {:?}", expiry);
-                let cookie = self_.config.build_cookie(Some(id), expiry);
-                resp.headers_mut().insert(
-                    http::header::SET_COOKIE,
-                    cookie
-                        .to_string()
-                        .try_into()
-                        .expect("cookie should be valid"),
-                );
-            }
-            None => {}
-        };
+        let req = Request::builder()
+            .header(
+                http::header::COOKIE,
+                res.headers().get(http::header::SET_COOKIE).unwrap(),
+            )
+            .body(Body::empty())?;
+        let res = noop_svc.oneshot(req).await?;
+        assert!(cookie_has_expected_max_age(&res, 7200));
 
-        Poll::Ready(Ok(resp))
+        Ok(())
     }
-}
-
-/// A layer for providing [`Session`] as a request extension.
-///
-/// # Examples
-///
-/// ```rust
-/// use tower_sesh::{MemoryStore, SessionManagerLayer};
-///
-/// let session_store: MemoryStore<()> = MemoryStore::default();
-/// let session_service = SessionManagerLayer {
-///     store: session_store,
-///     config: Default::default()
-/// };
-/// ```
-#[derive(Debug, Clone)]
-pub struct SessionManagerLayer<Store> {
-    /// The store to use for session data.
-    ///
-    /// This should implement [`tower_sesh_core::SessionStore`], and be cloneable.
-    pub store: Store,
-    /// The configuration options for the session cookie.
-    pub config: Config<'static>,
-}
 
-impl<S, Store> Layer<S> for SessionManagerLayer<Store>
-where
-    Store: Clone,
-{
-    type Service = SessionManager<Store, S>;
+    #[test]
+    fn config_validation() {
+        assert_eq!(Config::builder().build(), Ok(Config::default()));
+        assert!(Config::builder()
+            .same_site(SameSite::None)
+            .secure(true)
+            .build()
+            .is_ok());
 
-    fn layer(&self, inner: S) -> Self::Service {
-        SessionManager {
-            inner,
-            store: self.store.clone(),
-            config: self.config,
+        let invalid = [
+            (
+                Config::builder().name(""),
+                ConfigError::InvalidName("".into()),
+            ),
+            (
+                Config::builder().name("my sid"),
+                ConfigError::InvalidName("my sid".into()),
+            ),
+            (
+                Config::builder().name("sid=1"),
+                ConfigError::InvalidName("sid=1".into()),
+            ),
+            (
+                Config::builder().path("foo"),
+                ConfigError::InvalidPath("foo".into()),
+            ),
+            (
+                Config::builder().path("/; Secure"),
+                ConfigError::InvalidPath("/; Secure".into()),
+            ),
+            (
+                Config::builder().domain(""),
+                ConfigError::InvalidDomain("".into()),
+            ),
+            (
+                Config::builder().domain("example.com; Path=/"),
+                ConfigError::InvalidDomain("example.com; Path=/".into()),
+            ),
+            (
+                Config::builder().same_site(SameSite::None).secure(false),
+                ConfigError::InsecureSameSiteNone,
+            ),
+            (Config::builder().max_chunks(0), ConfigError::NoChunks),
+            (
+                Config::builder().prefix(CookiePrefix::Secure).secure(false),
+                ConfigError::InsecurePrefix(CookiePrefix::Secure),
+            ),
+            (
+                Config::builder().name("__host-id").secure(false),
+                ConfigError::InsecurePrefix(CookiePrefix::Host),
+            ),
+            (
+                Config::builder()
+                    .prefix(CookiePrefix::Host)
+                    .domain("example.com"),
+                ConfigError::HostPrefixDomain,
+            ),
+            (
+                Config::builder().name("__Host-id").path("/app"),
+                ConfigError::HostPrefixPath,
+            ),
+            (
+                Config::builder().partitioned(true),
+                ConfigError::InvalidPartitioned,
+            ),
+            (
+                Config::builder()
+                    .partitioned(true)
+                    .same_site(SameSite::None)
+                    .secure(false),
+                ConfigError::InsecureSameSiteNone,
+            ),
+        ];
+        for (builder, err) in invalid {
+            assert_eq!(builder.build(), Err(err));
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use anyhow::anyhow;
-    use axum::body::Body;
-    use tower::{ServiceBuilder, ServiceExt};
-    use tower_sesh_core::Expires;
-    use tower_sesh_memory_store::MemoryStore;
+    #[tokio::test]
+    async fn cookie_prefixes() -> anyhow::Result<()> {
+        let config = Config::builder().prefix(CookiePrefix::Host).build()?;
+        assert_eq!(config.name(), "__Host-id");
+        assert_eq!(config.prefix(), Some(CookiePrefix::Host));
+        let config = Config::builder()
+            .name("__Host-sid")
+            .prefix(CookiePrefix::Host)
+            .build()?;
+        assert_eq!(config.name(), "__Host-sid");
+        let config = Config::builder()
+            .prefix(CookiePrefix::Secure)
+            .path("/app")
+            .domain("example.com")
+            .build()?;
+        assert_eq!(config.name(), "__Secure-id");
+        assert_eq!(Config::default().prefix(), None);
 
-    use super::*;
+        let session_store: MemoryStore<Record> = MemoryStore::default();
+        let session_config = Config::builder().prefix(CookiePrefix::Host).build()?;
+        let svc = ServiceBuilder::new()
+            .layer(SessionManagerLayer::new(session_store, session_config))
+            .service_fn(handler);
 
-    #[derive(Debug, Clone)]
-    struct Record {
-        foo: i32,
+        let req = Request::builder().body(Body::empty())?;
+        let res = svc.clone().oneshot(req).await?;
+        let [cookie] = &set_cookies(&res)[..] else {
+            panic!("expected a single session cookie");
+        };
+        assert_eq!(cookie.name(), "__Host-id");
+        assert_eq!(cookie.secure(), Some(true));
+        assert_eq!(cookie.path(), Some("/"));
+        assert_eq!(cookie.domain(), None);
+
+        let req = Request::builder()
+            .header(http::header::COOKIE, cookie.stripped().to_string())
+            .body(Body::empty())?;
+        let res = svc.oneshot(req).await?;
+        assert_eq!(set_cookies(&res)[0].value(), cookie.value());
+
+        Ok(())
     }
-    impl Expires for Record {}
 
-    async fn handler(mut req: Request<Body>) -> anyhow::Result<Response<Body>> {
-        let session = req
-            .extensions_mut()
-            .remove::<Session<MemoryStore<Record>>>()
-            .ok_or(anyhow!("Missing session"))?;
+    #[cfg(feature = "serde")]
+    #[test]
+    fn config_deserialize() -> anyhow::Result<()> {
+        let config: Config = serde_json::from_str(
+            r#"{
+                "name": "sid",
+                "same_site": "lax",
+                "domain": "example.com",
+                "always_set_expiry": { "OnInactivity": [3600, 0] }
+            }"#,
+        )?;
+        assert_eq!(
+            config,
+            Config::builder()
+                .name("sid")
+                .same_site(SameSite::Lax)
+                .domain("example.com")
+                .always_set_expiry(Some(Expiry::OnInactivity(time::Duration::hours(1))))
+                .build()?
+        );
+        assert_eq!(
+            serde_json::from_value::<Config>(serde_json::to_value(&config)?)?,
+            config
+        );
 
-        let session_state = session.clone().load().await?;
-        if let Some(session_state) = session_state {
-            session_state
-                .update(|data| {
-                    data.foo += 1;
-                })
-                .await?;
-        } else {
-            session.create(Record { foo: 42 }).await?;
-        }
+        let err = serde_json::from_str::<Config>(r#"{ "same_site": "None", "secure": false }"#)
+            .expect_err("`SameSite=None` without `Secure` should be rejected");
+        assert!(err.to_string().contains("must be secure"));
+        assert!(serde_json::from_str::<Config>(r#"{ "name": "a;b" }"#).is_err());
+        assert!(serde_json::from_str::<Config>(r#"{ "same_site": "Sometimes" }"#).is_err());
+        assert!(serde_json::from_str::<Config>(r#"{ "unknown": true }"#).is_err());
 
-        Ok(Response::new(Body::empty()))
-    }
+        let config: Config = serde_json::from_str(r#"{ "prefix": "Host" }"#)?;
+        assert_eq!(config.name(), "__Host-id");
+        assert!(serde_json::from_str::<Config>(r#"{ "prefix": "Host", "path": "/app" }"#).is_err());
 
-    async fn noop_handler(_: Request<Body>) -> anyhow::Result<Response<Body>> {
-        Ok(Response::new(Body::empty()))
+        Ok(())
     }
 
     #[tokio::test]
-    async fn basic_service_test() -> anyhow::Result<()> {
+    async fn dynamic_config() -> anyhow::Result<()> {
         let session_store: MemoryStore<Record> = MemoryStore::default();
-        let session_layer = SessionManagerLayer {
-            store: session_store,
-            config: Default::default(),
-        };
+        let session_layer = SessionManagerLayer::new(session_store, Default::default())
+            .with_config_fn(|parts| {
+                let host = parts
+                    .headers
+                    .get(http::header::HOST)
+                    .and_then(|host| host.to_str().ok())
+                    .unwrap_or_default()
+                    .to_owned();
+                let tenant = host.split('.').next().unwrap_or_default().to_owned();
+                Config::builder()
+                    .name(format!("{tenant}.sid"))
+                    .secure(host != "localhost")
+                    .domain(host)
+                    .build()
+                    .expect("config should be valid")
+            });
         let svc = ServiceBuilder::new()
-            .layer(session_layer.clone())
+            .layer(session_layer)
             .service_fn(handler);
 
-        let noop_svc = ServiceBuilder::new()
+        let req = Request::builder()
+            .header(http::header::HOST, "a.example.com")
+            .body(Body::empty())?;
+        let res = svc.clone().oneshot(req).await?;
+        let [cookie] = &set_cookies(&res)[..] else {
+            panic!("expected a single session cookie");
+        };
+        assert_eq!(cookie.name(), "a.sid");
+        assert_eq!(cookie.domain(), Some("a.example.com"));
+        assert_eq!(cookie.secure(), Some(true));
+
+        // The cookie is read with the configuration of the request.
+        let req = Request::builder()
+            .header(http::header::HOST, "a.example.com")
+            .header(http::header::COOKIE, cookie.stripped().to_string())
+            .body(Body::empty())?;
+        let res = svc.clone().oneshot(req).await?;
+        assert_eq!(set_cookies(&res)[0].value(), cookie.value());
+
+        let req = Request::builder()
+            .header(http::header::HOST, "localhost")
+            .header(http::header::COOKIE, cookie.stripped().to_string())
+            .body(Body::empty())?;
+        let res = svc.oneshot(req).await?;
+        let [other] = &set_cookies(&res)[..] else {
+            panic!("expected a single session cookie");
+        };
+        assert_eq!(other.name(), "localhost.sid");
+        assert_eq!(other.domain(), Some("localhost"));
+        assert_eq!(other.secure(), None);
+        assert_ne!(other.value(), cookie.value());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tagged_sessions() -> anyhow::Result<()> {
+        use tower_sesh_core::SessionStore;
+
+        struct Auth;
+        struct Preferences;
+
+        async fn tagged_handler(mut req: Request<Body>) -> anyhow::Result<Response<Body>> {
+            let auth = req
+                .extensions_mut()
+                .remove::<Session<MemoryStore<Record>, Auth>>()
+                .ok_or(anyhow!("Missing auth session"))?;
+            let preferences = req
+                .extensions_mut()
+                .remove::<Session<MemoryStore<Record>, Preferences>>()
+                .ok_or(anyhow!("Missing preferences session"))?;
+            assert!(req
+                .extensions()
+                .get::<Session<MemoryStore<Record>>>()
+                .is_none());
+
+            if auth.clone().load().await?.is_none() {
+                auth.create(Record { foo: 1 }).await?;
+            }
+            if let Some(state) = preferences.clone().load().await? {
+                state.update(|record| record.foo += 1).await?;
+            } else {
+                preferences
+                    .create_with_expiry(
+                        Record { foo: 100 },
+                        Expiry::OnInactivity(time::Duration::days(30)),
+                    )
+                    .await?;
+            }
+            Ok(Response::new(Body::empty()))
+        }
+
+        let auth_store: MemoryStore<Record> = MemoryStore::default();
+        let mut preferences_store: MemoryStore<Record> = MemoryStore::default();
+        let svc = ServiceBuilder::new()
+            .layer(SessionManagerLayer::new(auth_store, Default::default()).with_tag::<Auth>())
+            .layer(
+                SessionManagerLayer::new(
+                    preferences_store.clone(),
+                    Config::builder().name("prefs").build()?,
+                )
+                .with_tag::<Preferences>(),
+            )
+            .service_fn(tagged_handler);
+
+        let req = Request::builder().body(Body::empty())?;
+        let res = svc.clone().oneshot(req).await?;
+        let cookies = set_cookies(&res);
+        // The inner layer sets its cookie first.
+        let [preferences, auth] = &cookies[..] else {
+            panic!("expected two session cookies");
+        };
+        assert_eq!(auth.name(), "id");
+        assert_eq!(auth.max_age(), None);
+        assert_eq!(preferences.name(), "prefs");
+        assert_eq!(preferences.max_age(), Some(time::Duration::days(30)));
+        assert_ne!(auth.value(), preferences.value());
+
+        let req = Request::builder()
+            .header(
+                http::header::COOKIE,
+                format!("{}; {}", auth.stripped(), preferences.stripped()),
+            )
+            .body(Body::empty())?;
+        let res = svc.oneshot(req).await?;
+        let cookies = set_cookies(&res);
+        let [updated] = &cookies[..] else {
+            panic!("expected only the preferences cookie");
+        };
+        assert_eq!(updated.name(), "prefs");
+        let id = updated.value().parse::<Id>()?;
+        assert_eq!(preferences_store.load(&id).await?.map(|r| r.foo), Some(101));
+
+        Ok(())
+    }
+
+    #[cfg(feature = "signed")]
+    #[tokio::test]
+    async fn signed_cookie_test() -> anyhow::Result<()> {
+        use tower_sesh_core::SessionStore;
+
+        use crate::protection::Key;
+
+        let mut session_store: MemoryStore<Record> = MemoryStore::default();
+        let session_layer = SessionManagerLayer::new(session_store.clone(), Default::default())
+            .with_signed(Key::generate());
+        let svc = ServiceBuilder::new()
             .layer(session_layer)
-            .service_fn(noop_handler);
+            .service_fn(handler);
 
         let req = Request::builder().body(Body::empty())?;
         let res = svc.clone().oneshot(req).await?;
+        let signed = cookie_value(&res).expect("a session cookie should be set");
+        let id = signed[signed.len() - 22..].parse::<Id>()?;
+        assert_ne!(signed, id.to_string());
 
-        let session = res.headers().get(http::header::SET_COOKIE);
-        assert!(session.is_some());
+        let req = Request::builder()
+            .header(http::header::COOKIE, format!("id={signed}"))
+            .body(Body::empty())?;
+        let res = svc.clone().oneshot(req).await?;
+        assert_eq!(cookie_value(&res), Some(signed));
+        assert_eq!(session_store.load(&id).await?.map(|r| r.foo), Some(43));
 
+        // The raw id is a valid `Id`, but it is not signed.
         let req = Request::builder()
-            .header(http::header::COOKIE, session.unwrap())
+            .header(http::header::COOKIE, format!("id={id}"))
             .body(Body::empty())?;
-        let res = noop_svc.oneshot(req).await?;
+        let res = svc.oneshot(req).await?;
+        assert!(cookie_value(&res).is_some_and(|value| !value.ends_with(&id.to_string())));
+        assert_eq!(session_store.load(&id).await?.map(|r| r.foo), Some(43));
 
-        assert!(res.headers().get(http::header::SET_COOKIE).is_none());
+        Ok(())
+    }
+
+    #[cfg(feature = "signed")]
+    #[tokio::test]
+    async fn signed_cookie_rotation_test() -> anyhow::Result<()> {
+        use crate::protection::{Key, KeyRing};
+
+        let old_key = Key::generate();
+        let session_store: MemoryStore<Record> = MemoryStore::default();
+        let old_svc = ServiceBuilder::new()
+            .layer(
+                SessionManagerLayer::new(session_store.clone(), Default::default())
+                    .with_signed(old_key.clone()),
+            )
+            .service_fn(handler);
+        let new_svc = ServiceBuilder::new()
+            .layer(
+                SessionManagerLayer::new(session_store, Default::default())
+                    .with_signed(KeyRing::new(Key::generate()).with_previous(old_key)),
+            )
+            .service_fn(handler);
+
+        let req = Request::builder().body(Body::empty())?;
+        let res = old_svc.oneshot(req).await?;
+        let old_signed = cookie_value(&res).expect("a session cookie should be set");
+
+        let req = Request::builder()
+            .header(http::header::COOKIE, format!("id={old_signed}"))
+            .body(Body::empty())?;
+        let res = new_svc.oneshot(req).await?;
+        let new_signed = cookie_value(&res).expect("the session should be re-signed");
+
+        assert_ne!(old_signed, new_signed);
+        assert_eq!(old_signed[44..], new_signed[44..]);
 
         Ok(())
     }
 
+    #[cfg(feature = "private")]
     #[tokio::test]
-    async fn bogus_cookie_test() -> anyhow::Result<()> {
+    async fn private_cookie_test() -> anyhow::Result<()> {
+        use crate::protection::Key;
+
+        let key = Key::generate();
         let session_store: MemoryStore<Record> = MemoryStore::default();
-        let session_layer = SessionManagerLayer {
-            store: session_store,
-            config: Default::default(),
-        };
         let svc = ServiceBuilder::new()
-            .layer(session_layer)
+            .layer(
+                SessionManagerLayer::new(session_store.clone(), Default::default())
+                    .with_private(key.clone(), Some("auth")),
+            )
+            .service_fn(handler);
+        let other_purpose_svc = ServiceBuilder::new()
+            .layer(
+                SessionManagerLayer::new(session_store, Default::default())
+                    .with_private(key, Some("prefs")),
+            )
             .service_fn(handler);
 
         let req = Request::builder().body(Body::empty())?;
         let res = svc.clone().oneshot(req).await?;
-
-        assert!(res.headers().get(http::header::SET_COOKIE).is_some());
+        let encrypted = cookie_value(&res).expect("a session cookie should be set");
 
         let req = Request::builder()
-            .header(http::header::COOKIE, "id=bogus")
+            .header(http::header::COOKIE, format!("id={encrypted}"))
             .body(Body::empty())?;
         let res = svc.oneshot(req).await?;
+        let reencrypted = cookie_value(&res).expect("the session should be updated");
+        assert_ne!(encrypted, reencrypted);
 
-        assert!(res.headers().get(http::header::SET_COOKIE).is_some());
+        // The other service decrypts nothing, so the handler creates a brand new session.
+        let req = Request::builder()
+            .header(http::header::COOKIE, format!("id={encrypted}"))
+            .body(Body::empty())?;
+        let res = other_purpose_svc.oneshot(req).await?;
+        assert!(cookie_value(&res).is_some());
 
         Ok(())
     }
 
+    #[cfg(any(feature = "signed", feature = "private"))]
+    fn cookie_value(res: &Response<Body>) -> Option<String> {
+        res.headers()
+            .get(http::header::SET_COOKIE)
+            .and_then(|set_cookie| set_cookie.to_str().ok())
+            .and_then(|set_cookie| Cookie::parse(set_cookie).ok())
+            .map(|cookie| cookie.value().to_owned())
+    }
+
+    async fn cookie_setting_handler(req: Request<Body>) -> anyhow::Result<Response<Body>> {
+        let cookies = req
+            .headers()
+            .get_all("x-set-cookie")
+            .into_iter()
+            .cloned()
+            .collect::<Vec<_>>();
+        let mut res = handler(req).await?;
+        for cookie in cookies {
+            res.headers_mut().append(SET_COOKIE, cookie);
+        }
+        Ok(res)
+    }
+
+    fn set_cookies(res: &Response<Body>) -> Vec<Cookie<'static>> {
+        res.headers()
+            .get_all(SET_COOKIE)
+            .into_iter()
+            .map(|value| Cookie::parse(value.to_str().unwrap().to_owned()).unwrap())
+            .collect()
+    }
+
     #[tokio::test]
-    async fn no_set_cookie_test() -> anyhow::Result<()> {
+    async fn keeps_handler_cookies() -> anyhow::Result<()> {
         let session_store: MemoryStore<Record> = MemoryStore::default();
-        let session_layer = SessionManagerLayer {
-            store: session_store,
-            config: Default::default(),
-        };
+        let session_layer = SessionManagerLayer::new(session_store, Default::default());
         let svc = ServiceBuilder::new()
             .layer(session_layer)
-            .service_fn(noop_handler);
+            .service_fn(cookie_setting_handler);
 
-        let req = Request::builder().body(Body::empty())?;
+        let req = Request::builder()
+            .header("x-set-cookie", "csrf=token; Path=/")
+            .header("x-set-cookie", "locale=fr")
+            .body(Body::empty())?;
         let res = svc.oneshot(req).await?;
 
-        assert!(res.headers().get(http::header::SET_COOKIE).is_none());
+        let cookies = set_cookies(&res);
+        let names = cookies.iter().map(Cookie::name).collect::<Vec<_>>();
+        assert_eq!(names, ["csrf", "locale", "id"]);
 
         Ok(())
     }
 
     #[tokio::test]
-    async fn custom_config() -> anyhow::Result<()> {
-        let session_store: MemoryStore<Record> = MemoryStore::default();
+    async fn keeps_inner_and_outer_layer_cookies() -> anyhow::Result<()> {
+        fn add_cookie(cookie: &'static str) -> impl Fn(Response<Body>) -> Response<Body> + Clone {
+            move |mut res| {
+                res.headers_mut()
+                    .append(SET_COOKIE, http::HeaderValue::from_static(cookie));
+                res
+            }
+        }
 
-        let session_config = Config {
-            name: "my.sid",
-            http_only: false,
-            same_site: SameSite::Lax,
-            secure: false,
-            path: "/foo/bar",
-            domain: Some("example.com"),
-            always_set_expiry: Some(Expiry::OnInactivity(time::Duration::hours(2))),
-        };
-        let session_layer = SessionManagerLayer {
-            store: session_store,
-            config: session_config,
-        };
+        let session_store: MemoryStore<Record> = MemoryStore::default();
+        let session_layer = SessionManagerLayer::new(session_store, Default::default());
         let svc = ServiceBuilder::new()
-            .layer(session_layer.clone())
-            .service_fn(handler);
-        let noop_svc = ServiceBuilder::new()
+            .map_response(add_cookie("outer=1"))
             .layer(session_layer)
-            .service_fn(noop_handler);
+            .map_response(add_cookie("inner=1"))
+            .service_fn(handler);
 
         let req = Request::builder().body(Body::empty())?;
         let res = svc.oneshot(req).await?;
 
-        assert!(cookie_value_matches(&res, |s| s.contains("my.sid=")));
-        assert!(cookie_value_matches(&res, |s| s.contains("SameSite=Lax")));
-        assert!(cookie_value_matches(&res, |s| !s.contains("Secure")));
-        assert!(cookie_value_matches(&res, |s| s.contains("Path=/foo/bar")));
-        assert!(cookie_value_matches(&res, |s| s.contains("Domain=example.com")));
+        let cookies = set_cookies(&res);
+        let names = cookies.iter().map(Cookie::name).collect::<Vec<_>>();
+        assert_eq!(names, ["inner", "id", "outer"]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn replaces_same_cookie() -> anyhow::Result<()> {
+        let session_store: MemoryStore<Record> = MemoryStore::default();
+        let session_layer = SessionManagerLayer::new(session_store, Default::default());
+        let svc = ServiceBuilder::new()
+            .layer(session_layer)
+            .service_fn(cookie_setting_handler);
 
         let req = Request::builder()
+            .header("x-set-cookie", "id=stale; Path=/")
+            .header("x-set-cookie", "id=other-path; Path=/other")
             .header(
-                http::header::COOKIE,
-                res.headers().get(http::header::SET_COOKIE).unwrap(),
+                "x-set-cookie",
+                "id=other-domain; Path=/; Domain=example.com",
             )
             .body(Body::empty())?;
-        let res = noop_svc.oneshot(req).await?;
-        assert!(cookie_has_expected_max_age(&res, 7200));
+        let res = svc.oneshot(req).await?;
+
+        let cookies = set_cookies(&res);
+        let values = cookies.iter().map(Cookie::value).collect::<Vec<_>>();
+        assert_eq!(values.len(), 3);
+        assert_eq!(values[..2], ["other-path", "other-domain"]);
+        assert!(values[2].parse::<Id>().is_ok());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn keeps_handler_cookies_on_delete() -> anyhow::Result<()> {
+        async fn load_handler(mut req: Request<Body>) -> anyhow::Result<Response<Body>> {
+            let session = req
+                .extensions_mut()
+                .remove::<Session<MemoryStore<Record>>>()
+                .ok_or(anyhow!("Missing session"))?;
+            // The session does not exist in the store, so loading it removes the cookie.
+            assert!(session.load().await?.is_none());
+
+            let mut res = Response::new(Body::empty());
+            res.headers_mut()
+                .append(SET_COOKIE, http::HeaderValue::from_static("csrf=token"));
+            Ok(res)
+        }
+
+        let session_store: MemoryStore<Record> = MemoryStore::default();
+        let session_layer = SessionManagerLayer::new(session_store, Default::default());
+        let svc = ServiceBuilder::new()
+            .layer(session_layer)
+            .service_fn(load_handler);
+
+        let req = Request::builder()
+            .header(http::header::COOKIE, format!("id={}", Id(42)))
+            .body(Body::empty())?;
+        let res = svc.oneshot(req).await?;
+
+        let cookies = set_cookies(&res);
+        let names = cookies.iter().map(Cookie::name).collect::<Vec<_>>();
+        assert_eq!(names, ["csrf", "id"]);
+        assert_eq!(cookies[1].value(), "");
 
         Ok(())
     }
@@ -0,0 +1,411 @@
+//! Cryptographic protection of the session cookie.
+//!
+//! By default, the session [`Id`] is written into the cookie as-is. Since a valid [`Id`] is just
+//! 22 base64 characters, any client can forge one and have it looked up in the store. Enabling
+//! signed cookies with [`SessionManagerLayer::with_signed`] makes the middleware reject tampered
+//! or forged cookies before the store is ever reached.
+//!
+//! Signed cookies still expose the session id to the browser. Private cookies, enabled with
+//! [`SessionManagerLayer::with_private`], encrypt the id with AES-256-GCM so that the cookie value
+//! cannot be correlated with server logs or store keys. A fresh nonce is used every time the
+//! cookie is written, so the value changes on every `Set-Cookie`.
+//!
+//! [`SessionManagerLayer::with_signed`]: crate::SessionManagerLayer::with_signed
+//! [`SessionManagerLayer::with_private`]: crate::SessionManagerLayer::with_private
+use std::{
+    fmt::{self, Display},
+    str::FromStr,
+};
+
+#[cfg(feature = "private")]
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    Aes256Gcm, Nonce,
+};
+#[cfg(feature = "private")]
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+#[cfg(any(feature = "signed", feature = "private"))]
+#[cfg_attr(docsrs, doc(cfg(any(feature = "signed", feature = "private"))))]
+#[doc(no_inline)]
+pub use cookie::Key;
+#[cfg(feature = "signed")]
+use cookie::{Cookie, CookieJar};
+use tower_sesh_core::Id;
+
+/// An ordered list of keys used to protect session cookies.
+///
+/// The first key is the _current_ key: it is the only one used to protect new cookies. The
+/// remaining keys are only used to verify cookies that were issued before a key rotation. Once a
+/// cookie protected with an older key is accepted, the next `Set-Cookie` sent for that session
+/// uses the current key.
+///
+/// To rotate keys without logging users out, make the new key the current one, keep the old key
+/// as a previous key, and remove it once every cookie protected with it has expired.
+///
+/// # Examples
+///
+/// ```
+/// use tower_sesh::protection::{Key, KeyRing};
+///
+/// let previous = Key::generate();
+/// let current = Key::generate();
+///
+/// let keys = KeyRing::new(current).with_previous(previous);
+/// assert_eq!(keys.len(), 2);
+/// ```
+#[cfg(any(feature = "signed", feature = "private"))]
+#[cfg_attr(docsrs, doc(cfg(any(feature = "signed", feature = "private"))))]
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyRing(Vec<Key>);
+
+#[cfg(any(feature = "signed", feature = "private"))]
+impl KeyRing {
+    /// Create a new [`KeyRing`] with `current` as the key used to protect new cookies.
+    pub fn new(current: Key) -> Self {
+        Self(vec![current])
+    }
+
+    /// Add a key that is only used to verify cookies issued before a rotation.
+    ///
+    /// Keys are tried in the order they were added, after the current key.
+    pub fn with_previous(mut self, key: Key) -> Self {
+        self.0.push(key);
+        self
+    }
+
+    /// The key used to protect new cookies.
+    pub fn current(&self) -> &Key {
+        &self.0[0]
+    }
+
+    /// The number of keys in the ring, including the current key.
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Iterate over all the keys, starting with the current one.
+    pub fn iter(&self) -> impl Iterator<Item = &Key> {
+        self.0.iter()
+    }
+}
+
+#[cfg(any(feature = "signed", feature = "private"))]
+impl From<Key> for KeyRing {
+    fn from(key: Key) -> Self {
+        Self::new(key)
+    }
+}
+
+/// The reason a session cookie value was rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Rejection {
+    /// The value could not be parsed as an [`Id`].
+    Malformed(<Id as FromStr>::Err),
+    /// The value did not verify against any of the keys.
+    #[cfg(feature = "signed")]
+    Unverified,
+    /// The value could not be decrypted with any of the keys.
+    #[cfg(feature = "private")]
+    Undecryptable,
+}
+
+impl Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::Malformed(err) => write!(f, "malformed session id: {err}"),
+            #[cfg(feature = "signed")]
+            Rejection::Unverified => f.write_str("session cookie failed verification"),
+            #[cfg(feature = "private")]
+            Rejection::Undecryptable => f.write_str("session cookie could not be decrypted"),
+        }
+    }
+}
+
+/// How the session [`Id`] is written into the cookie.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) enum Protection {
+    /// The [`Id`] is written as-is.
+    #[default]
+    Plain,
+    /// The [`Id`] is signed with HMAC-SHA256, using the signed cookie jar of the `cookie` crate.
+    #[cfg(feature = "signed")]
+    Signed(KeyRing),
+    /// The [`Id`] is encrypted, see [`Cipher`].
+    #[cfg(feature = "private")]
+    Private(Cipher),
+}
+
+impl Protection {
+    /// Produce the cookie value for the given session id.
+    #[cfg_attr(
+        not(any(feature = "signed", feature = "private")),
+        allow(unused_variables)
+    )]
+    pub(crate) fn seal(&self, name: &str, id: Id) -> String {
+        match self {
+            Protection::Plain => id.to_string(),
+            #[cfg(feature = "signed")]
+            Protection::Signed(keys) => {
+                let mut jar = CookieJar::new();
+                jar.signed_mut(keys.current())
+                    .add(Cookie::new(name.to_owned(), id.to_string()));
+                jar.get(name)
+                    .expect("cookie was just added to the jar")
+                    .value()
+                    .to_owned()
+            }
+            #[cfg(feature = "private")]
+            Protection::Private(cipher) => cipher.encrypt(name, &id.0.to_le_bytes()),
+        }
+    }
+
+    /// Recover the session id from a cookie value.
+    #[cfg_attr(
+        not(any(feature = "signed", feature = "private")),
+        allow(unused_variables)
+    )]
+    pub(crate) fn open(&self, name: &str, value: &str) -> Result<Id, Rejection> {
+        match self {
+            Protection::Plain => value.parse().map_err(Rejection::Malformed),
+            #[cfg(feature = "signed")]
+            Protection::Signed(keys) => {
+                let jar = CookieJar::new();
+                let cookie = Cookie::new(name.to_owned(), value.to_owned());
+                let verified = keys
+                    .iter()
+                    .find_map(|key| jar.signed(key).verify(cookie.clone()))
+                    .ok_or(Rejection::Unverified)?;
+                verified.value().parse().map_err(Rejection::Malformed)
+            }
+            #[cfg(feature = "private")]
+            Protection::Private(cipher) => {
+                let bytes = cipher
+                    .decrypt(name, value)?
+                    .try_into()
+                    .map_err(|_| Rejection::Undecryptable)?;
+                Ok(Id(u128::from_le_bytes(bytes)))
+            }
+        }
+    }
+}
+
+/// Authenticated encryption of cookie values with AES-256-GCM.
+///
+/// The cookie name and the `purpose` are authenticated as associated data, so a value cannot be
+/// replayed in another context. The encrypted value is the URL-safe base64 encoding of
+/// `nonce | ciphertext | tag`.
+#[cfg(feature = "private")]
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Cipher {
+    pub(crate) keys: KeyRing,
+    pub(crate) purpose: Option<Box<str>>,
+}
+
+#[cfg(feature = "private")]
+impl Cipher {
+    /// The length of an AES-GCM nonce, in bytes.
+    const NONCE_LEN: usize = 12;
+    /// The length of an AES-GCM authentication tag, in bytes.
+    const TAG_LEN: usize = 16;
+
+    /// The length of the value returned by [`Cipher::encrypt`] for a plaintext of `len` bytes.
+    #[cfg(feature = "cookie-store")]
+    pub(crate) fn encrypted_len(len: usize) -> usize {
+        ((Self::NONCE_LEN + len + Self::TAG_LEN) * 4).div_ceil(3)
+    }
+
+    /// Encrypt `plaintext` with the current key.
+    pub(crate) fn encrypt(&self, name: &str, plaintext: &[u8]) -> String {
+        let aead = Aes256Gcm::new_from_slice(self.keys.current().encryption())
+            .expect("encryption key should be 32 bytes");
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: plaintext,
+            aad: &self.associated_data(name),
+        };
+        let sealed = aead
+            .encrypt(&nonce, payload)
+            .expect("encrypting a cookie value should not fail");
+
+        let mut data = Vec::with_capacity(Self::NONCE_LEN + sealed.len());
+        data.extend_from_slice(&nonce);
+        data.extend_from_slice(&sealed);
+        URL_SAFE_NO_PAD.encode(data)
+    }
+
+    /// Decrypt `value`, trying every key of the ring.
+    pub(crate) fn decrypt(&self, name: &str, value: &str) -> Result<Vec<u8>, Rejection> {
+        let data = URL_SAFE_NO_PAD
+            .decode(value)
+            .map_err(|_| Rejection::Undecryptable)?;
+        if data.len() < Self::NONCE_LEN + Self::TAG_LEN {
+            return Err(Rejection::Undecryptable);
+        }
+        let (nonce, sealed) = data.split_at(Self::NONCE_LEN);
+        let aad = self.associated_data(name);
+
+        self.keys
+            .iter()
+            .find_map(|key| {
+                let aead = Aes256Gcm::new_from_slice(key.encryption())
+                    .expect("encryption key should be 32 bytes");
+                let payload = Payload {
+                    msg: sealed,
+                    aad: &aad,
+                };
+                aead.decrypt(Nonce::from_slice(nonce), payload).ok()
+            })
+            .ok_or(Rejection::Undecryptable)
+    }
+
+    /// Build the associated data: `name`, a NUL byte, then a 1 byte and `purpose` if there is
+    /// a purpose.
+    ///
+    /// The NUL byte cannot appear in a cookie name, so `("a", "bc")` and `("ab", "c")` never
+    /// collide, and the 1 byte keeps an empty purpose apart from no purpose at all.
+    fn associated_data(&self, name: &str) -> Vec<u8> {
+        let purpose = self.purpose.as_deref();
+        let mut aad = Vec::with_capacity(name.len() + 2 + purpose.map_or(0, str::len));
+        aad.extend_from_slice(name.as_bytes());
+        aad.push(0);
+        if let Some(purpose) = purpose {
+            aad.push(1);
+            aad.extend_from_slice(purpose.as_bytes());
+        }
+        aad
+    }
+}
+
+#[cfg(all(test, any(feature = "signed", feature = "private")))]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "signed")]
+    #[test]
+    fn signed_round_trip() {
+        let protection = Protection::Signed(KeyRing::new(Key::generate()));
+        let id = Id(42);
+
+        let value = protection.seal("id", id);
+        assert_ne!(value, id.to_string());
+        assert_eq!(protection.open("id", &value), Ok(id));
+    }
+
+    #[cfg(feature = "signed")]
+    #[test]
+    fn signed_rejects_tampering() {
+        let protection = Protection::Signed(KeyRing::new(Key::generate()));
+
+        assert_eq!(
+            protection.open("id", &Id(42).to_string()),
+            Err(Rejection::Unverified)
+        );
+
+        let value = protection.seal("id", Id(42));
+        let forged = format!("{}{}", &value[..value.len() - 22], Id(43));
+        assert_eq!(protection.open("id", &forged), Err(Rejection::Unverified));
+    }
+
+    #[cfg(feature = "signed")]
+    #[test]
+    fn signed_rotation() {
+        let old = Key::generate();
+        let new = Key::generate();
+        let old_protection = Protection::Signed(KeyRing::new(old.clone()));
+        let protection = Protection::Signed(KeyRing::new(new).with_previous(old));
+
+        let old_value = old_protection.seal("id", Id(42));
+        assert_eq!(protection.open("id", &old_value), Ok(Id(42)));
+
+        let new_value = protection.seal("id", Id(42));
+        assert_ne!(old_value, new_value);
+        assert_eq!(
+            old_protection.open("id", &new_value),
+            Err(Rejection::Unverified)
+        );
+    }
+
+    #[cfg(feature = "private")]
+    fn private(keys: KeyRing, purpose: Option<&str>) -> Protection {
+        Protection::Private(Cipher {
+            keys,
+            purpose: purpose.map(Into::into),
+        })
+    }
+
+    #[cfg(feature = "private")]
+    #[test]
+    fn private_round_trip() {
+        let protection = private(KeyRing::new(Key::generate()), None);
+        let id = Id(42);
+
+        let value = protection.seal("id", id);
+        assert!(!value.contains(&id.to_string()));
+        assert_eq!(protection.open("id", &value), Ok(id));
+
+        let other_value = protection.seal("id", id);
+        assert_ne!(value, other_value);
+        assert_eq!(protection.open("id", &other_value), Ok(id));
+    }
+
+    #[cfg(feature = "private")]
+    #[test]
+    fn private_rejects_other_context() {
+        let key = Key::generate();
+        let protection = private(KeyRing::new(key.clone()), Some("auth"));
+        let value = protection.seal("id", Id(42));
+
+        assert_eq!(
+            protection.open("other", &value),
+            Err(Rejection::Undecryptable)
+        );
+        let other_purpose = private(KeyRing::new(key.clone()), Some("prefs"));
+        assert_eq!(
+            other_purpose.open("id", &value),
+            Err(Rejection::Undecryptable)
+        );
+        let no_purpose = private(KeyRing::new(key.clone()), None);
+        assert_eq!(no_purpose.open("id", &value), Err(Rejection::Undecryptable));
+        let empty_purpose = private(KeyRing::new(key), Some(""));
+        assert_eq!(
+            empty_purpose.open("id", &no_purpose.seal("id", Id(42))),
+            Err(Rejection::Undecryptable)
+        );
+        assert_eq!(
+            protection.open("id", &Id(42).to_string()),
+            Err(Rejection::Undecryptable)
+        );
+        assert_eq!(protection.open("id", "!"), Err(Rejection::Undecryptable));
+    }
+
+    #[cfg(feature = "private")]
+    #[test]
+    fn private_rotation() {
+        let old = Key::generate();
+        let old_protection = private(KeyRing::new(old.clone()), None);
+        let protection = private(KeyRing::new(Key::generate()).with_previous(old), None);
+
+        let old_value = old_protection.seal("id", Id(42));
+        assert_eq!(protection.open("id", &old_value), Ok(Id(42)));
+
+        let new_value = protection.seal("id", Id(42));
+        assert_eq!(
+            old_protection.open("id", &new_value),
+            Err(Rejection::Undecryptable)
+        );
+    }
+
+    #[cfg(feature = "cookie-store")]
+    #[test]
+    fn private_encrypted_len() {
+        let cipher = Cipher {
+            keys: KeyRing::new(Key::generate()),
+            purpose: None,
+        };
+        for len in 0..64 {
+            let value = cipher.encrypt("id", &vec![0; len]);
+            assert_eq!(value.len(), Cipher::encrypted_len(len));
+        }
+    }
+}
@@ -3,20 +3,35 @@
 //! The structs provided here have a strict API, but they are designed to be nearly impossible to
 //! misuse. Luckily, they only have a handful of methods, and all of them document how they work.
 use std::{
-    fmt::Debug,
+    fmt::{self, Debug},
+    marker::PhantomData,
     mem::ManuallyDrop,
     sync::{Arc, Mutex},
 };
 // TODO: Remove send + sync bounds on `R` once return type notation is stable.
 
-use tower_sesh_core::{expires::Expires, id::Id, Expiry, SessionStore};
+use time::OffsetDateTime;
+use tower_sesh_core::{expires::Expires, id::Id, Clock, Expiry, SessionStore};
 
 #[derive(Debug, Clone, Copy)]
 pub(crate) enum SessionUpdate {
     Delete,
+    /// Set the session, with its expiry resolved to an [`Expiry::AtDateTime`] when it was saved,
+    /// unless it expires on session end.
     Set(Id, Expiry),
 }
 
+impl SessionUpdate {
+    /// Set the session `id`, saved at `now` with the given `expiry`.
+    pub(crate) fn set(id: Id, expiry: Expiry, now: OffsetDateTime) -> Self {
+        let deadline = expiry.deadline(now);
+        SessionUpdate::Set(
+            id,
+            deadline.map_or(Expiry::OnSessionEnd, Expiry::AtDateTime),
+        )
+    }
+}
+
 pub(crate) type Updater = Arc<Mutex<Option<SessionUpdate>>>;
 
 /// A session that is lazily loaded.
@@ -32,7 +47,7 @@ pub(crate) type Updater = Arc<Mutex<Option<SessionUpdate>>>;
 ///
 /// # Examples
 /// - If you are using `axum`, and you have enabled the `extractor` feature, you can use this
-///     struct as an extractor:
+///   struct as an extractor:
 /// ```rust
 /// use tower_sesh::{Session, MemoryStore};
 ///
@@ -57,16 +72,64 @@ pub(crate) type Updater = Arc<Mutex<Option<SessionUpdate>>>;
 /// ```
 /// Again, the session will not be found if the handler was called without a `SessionManager`
 /// middleware.
-#[derive(Debug, Clone)]
-pub struct Session<Store> {
+///
+/// # Namespaces
+///
+/// Sessions are found in the request extensions by their type, so two [`SessionManagerLayer`]s
+/// with the same store type would provide the same `Session<Store>`. To use several independent
+/// sessions on the same router, give each layer its own `Tag` type with
+/// [`SessionManagerLayer::with_tag`], and extract `Session<Store, Tag>` in handlers:
+/// ```rust
+/// use tower_sesh::{Session, MemoryStore};
+///
+/// struct Auth;
+/// struct Preferences;
+///
+/// async fn handler(
+///     auth: Session<MemoryStore<()>, Auth>,
+///     preferences: Session<MemoryStore<()>, Preferences>,
+/// ) -> String {
+///     unimplemented!()
+/// }
+/// ```
+///
+/// [`SessionManagerLayer`]: crate::SessionManagerLayer
+/// [`SessionManagerLayer::with_tag`]: crate::SessionManagerLayer::with_tag
+pub struct Session<Store, Tag = ()> {
     /// This will be `None` if the handler has not received a session cookie or if the it could
     /// not be parsed.
     pub(crate) id: Option<Id>,
     pub(crate) store: Store,
     pub(crate) updater: Updater,
+    pub(crate) clock: Arc<dyn Clock>,
+    pub(crate) tag: PhantomData<fn() -> Tag>,
+}
+
+impl<Store: Clone, Tag> Clone for Session<Store, Tag> {
+    fn clone(&self) -> Self {
+        Self {
+            id: self.id,
+            store: self.store.clone(),
+            updater: Arc::clone(&self.updater),
+            clock: Arc::clone(&self.clock),
+            tag: PhantomData,
+        }
+    }
 }
 
-impl<Store> Session<Store> {
+impl<Store: Debug, Tag> Debug for Session<Store, Tag> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Session")
+            .field("id", &self.id)
+            .field("store", &self.store)
+            .field("updater", &self.updater)
+            .field("clock", &self.clock)
+            .field("tag", &std::any::type_name::<Tag>())
+            .finish()
+    }
+}
+
+impl<Store, Tag> Session<Store, Tag> {
     /// Try to load the session from the store.
     ///
     /// The return type of this method looks convoluted, so let's break it down:
@@ -117,6 +180,7 @@ impl<Store> Session<Store> {
                     id,
                     data: record,
                     updater: self.updater,
+                    clock: self.clock,
                 })
             } else {
                 self.updater
@@ -187,12 +251,13 @@ impl<Store> Session<Store> {
         self.updater
             .lock()
             .expect("lock should not be poisoned")
-            .replace(SessionUpdate::Set(id, exp));
+            .replace(SessionUpdate::set(id, exp, self.clock.now()));
         Ok(SessionState {
             store: self.store,
             id,
             data,
             updater: self.updater,
+            clock: self.clock,
         })
     }
 }
@@ -234,9 +299,10 @@ mod extractor {
 
     #[async_trait::async_trait]
     #[cfg_attr(docsrs, doc(cfg(feature = "extractor")))]
-    impl<State, Store> FromRequestParts<State> for Session<Store>
+    impl<State, Store, Tag> FromRequestParts<State> for Session<Store, Tag>
     where
         Store: Send + Sync + 'static,
+        Tag: 'static,
     {
         type Rejection = NoMiddleware;
 
@@ -246,7 +312,7 @@ mod extractor {
         ) -> Result<Self, Self::Rejection> {
             let session = parts
                 .extensions
-                .remove::<Session<Store>>()
+                .remove::<Session<Store, Tag>>()
                 .ok_or(NoMiddleware)?;
 
             Ok(session)
@@ -266,6 +332,7 @@ pub struct SessionState<R, Store> {
     id: Id,
     data: R,
     updater: Updater,
+    clock: Arc<dyn Clock>,
 }
 
 impl<R, Store> SessionState<R, Store> {
@@ -340,7 +407,7 @@ where
             self.updater
                 .lock()
                 .expect("lock should not be poisoned")
-                .replace(SessionUpdate::Set(self.id, exp));
+                .replace(SessionUpdate::set(self.id, exp, self.clock.now()));
             Some(self)
         } else {
             self.updater
@@ -370,7 +437,7 @@ where
     /// struct User;
     ///
     /// impl Expires for User {}
-    /// 
+    ///
     /// async fn logout(state: SessionState<User, MemoryStore<User>>) -> Option<String> {
     ///     Some(if state.delete().await.ok()? {
     ///         "User has been logged out".to_string()
@@ -404,12 +471,12 @@ where
     /// # Example
     /// ```
     /// use tower_sesh::{SessionState, MemoryStore, Expires};
-    /// 
+    ///
     /// #[derive(Clone)]
     /// struct User;
     ///
     /// impl Expires for User {}
-    /// 
+    ///
     /// async fn cycle(state: SessionState<User, MemoryStore<User>>) -> Option<String> {
     ///     Some(if let Some(new_state) = state.cycle().await.ok()? {
     ///         "Session has been cycled".to_string()
@@ -438,7 +505,7 @@ where
             self.updater
                 .lock()
                 .expect("lock should not be poisoned")
-                .replace(SessionUpdate::Set(new_id, exp));
+                .replace(SessionUpdate::set(new_id, exp, self.clock.now()));
             self.id = new_id;
             return Ok(Some(self));
         }
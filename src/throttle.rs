@@ -0,0 +1,84 @@
+//! Throttling of the store operations performed for each session.
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use tower_sesh_core::Id;
+
+/// Limits an operation to once per `period` for each session.
+#[derive(Debug)]
+pub(crate) struct Throttle {
+    period: Duration,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    /// When the sessions were last pruned.
+    pruned: Instant,
+    /// When the operation was last performed for each session.
+    last: HashMap<Id, Instant>,
+}
+
+impl Throttle {
+    pub(crate) fn new(period: Duration) -> Self {
+        Self {
+            period,
+            state: Mutex::new(State {
+                pruned: Instant::now(),
+                last: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Whether the operation can be performed for the session `id` now.
+    ///
+    /// Sessions whose operation is older than the period are forgotten once per period, so that
+    /// the memory used is bounded by the number of sessions recorded during a period.
+    pub(crate) fn allow(&self, id: Id) -> bool {
+        let mut state = self.state.lock().expect("lock should not be poisoned");
+        let now = Instant::now();
+        if now.duration_since(state.pruned) >= self.period {
+            state
+                .last
+                .retain(|_, last| now.duration_since(*last) < self.period);
+            state.pruned = now;
+        }
+        state
+            .last
+            .get(&id)
+            .is_none_or(|last| now.duration_since(*last) >= self.period)
+    }
+
+    /// Record that the operation was performed for the session `id`.
+    ///
+    /// This is only called once the operation succeeded, so that the ids of unknown sessions,
+    /// e.g. forged by clients, are not kept.
+    pub(crate) fn record(&self, id: Id) {
+        let mut state = self.state.lock().expect("lock should not be poisoned");
+        state.last.insert(id, Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn throttles_each_session() {
+        let throttle = Throttle::new(Duration::from_millis(50));
+        assert!(throttle.allow(Id(1)));
+        throttle.record(Id(1));
+        assert!(!throttle.allow(Id(1)));
+        assert!(throttle.allow(Id(2)));
+        assert!(throttle.allow(Id(2)));
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(throttle.allow(Id(1)));
+        throttle.record(Id(1));
+        assert!(!throttle.allow(Id(1)));
+        assert_eq!(throttle.state.lock().unwrap().last.len(), 1);
+    }
+}
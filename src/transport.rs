@@ -0,0 +1,355 @@
+//! How the session is carried between the client and the server.
+//!
+//! By default, the session is carried in a cookie. Clients that don't handle cookies, like mobile
+//! applications, can carry it in a request header instead, and receive it in a response header.
+//! Several transports can be used at the same time with
+//! [`SessionManagerLayer::with_transports`].
+//!
+//! [`SessionManagerLayer::with_transports`]: crate::SessionManagerLayer::with_transports
+use std::sync::Arc;
+
+use http::{header::AUTHORIZATION, HeaderMap, HeaderName, HeaderValue};
+use time::OffsetDateTime;
+use tower_sesh_core::Expiry;
+
+use crate::{
+    middleware::{append_cookies, Config, RequestCookie, Sent},
+    warn::WarnLimit,
+};
+
+/// A way to carry the session between the client and the server.
+///
+/// The value that is carried is the same for every transport: it is the session id, protected
+/// with the [`protection`](crate::protection) of the [`SessionManagerLayer`], or the session
+/// record for a [`CookieStore`](crate::CookieStore).
+///
+/// [`SessionManagerLayer`]: crate::SessionManagerLayer
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Transport {
+    /// The session cookie, as configured by the [`Config`].
+    Cookie,
+    /// A header, e.g. `X-Session-Id`, used in both requests and responses.
+    ///
+    /// When the session is deleted, the header is sent back with an empty value. The expiry of
+    /// the session is not sent to the client.
+    Header(HeaderName),
+    /// The bearer token of the `Authorization` request header.
+    ///
+    /// Since `Authorization` is a request header, the session is sent back to the client in the
+    /// `response_header`, like with [`Transport::Header`].
+    Bearer {
+        /// The response header in which the session is sent to the client.
+        response_header: HeaderName,
+    },
+}
+
+impl Transport {
+    /// Find the value presented by the client with this transport.
+    fn extract(
+        &self,
+        headers: &HeaderMap,
+        config: &Config,
+        warn_limit: &WarnLimit,
+    ) -> Option<(String, Sent)> {
+        match self {
+            Transport::Cookie => {
+                let RequestCookie { value, sent } =
+                    RequestCookie::parse(headers, config, warn_limit);
+                (sent != Sent::default()).then(|| (value.unwrap_or_default(), sent))
+            }
+            Transport::Header(name) => headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| (value.trim().to_owned(), Sent::default())),
+            Transport::Bearer { .. } => headers
+                .get(AUTHORIZATION)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| {
+                    let (scheme, token) = value.trim().split_once(' ')?;
+                    scheme.eq_ignore_ascii_case("bearer").then(|| token.trim())
+                })
+                .map(|token| (token.to_owned(), Sent::default())),
+        }
+    }
+
+    /// Send `value` to the client with this transport, or remove the session if it is `None`.
+    fn respond(
+        &self,
+        headers: &mut HeaderMap,
+        config: &Config,
+        value: Option<(String, Expiry)>,
+        sent: Sent,
+        now: OffsetDateTime,
+    ) {
+        match self {
+            Transport::Cookie => append_cookies(headers, config.build_cookies(value, sent, now)),
+            Transport::Header(name)
+            | Transport::Bearer {
+                response_header: name,
+            } => {
+                let value = value.map(|(value, _)| value).unwrap_or_default();
+                headers.insert(
+                    name.clone(),
+                    HeaderValue::try_from(value).expect("session values should be valid headers"),
+                );
+            }
+        }
+    }
+}
+
+/// The transports of a session, and what the client presented with them.
+#[derive(Debug, Clone)]
+pub(crate) struct Carrier {
+    transports: Arc<[Transport]>,
+    /// The index of the transport the client used, if any.
+    used: Option<usize>,
+    /// What the client sent for the session cookie, if it used the cookie transport.
+    sent: Sent,
+}
+
+impl Carrier {
+    /// Find the value presented by the client.
+    ///
+    /// Transports are tried in order, and the first one that presents a value is used, even if
+    /// its value turns out to be invalid.
+    pub(crate) fn extract(
+        transports: &Arc<[Transport]>,
+        headers: &HeaderMap,
+        config: &Config,
+        warn_limit: &WarnLimit,
+    ) -> (Option<String>, Self) {
+        let presented = transports
+            .iter()
+            .enumerate()
+            .find_map(|(index, transport)| {
+                transport
+                    .extract(headers, config, warn_limit)
+                    .map(|(value, sent)| (index, value, sent))
+            });
+        let (used, value, sent) = match presented {
+            Some((index, value, sent)) => {
+                tracing::debug!(transport = ?transports[index], "found session value");
+                let value = (!value.is_empty()).then_some(value);
+                (Some(index), value, sent)
+            }
+            None => (None, None, Sent::default()),
+        };
+
+        let carrier = Self {
+            transports: Arc::clone(transports),
+            used,
+            sent,
+        };
+        (value, carrier)
+    }
+
+    /// Whether the client presented a session, valid or not.
+    pub(crate) fn presented(&self) -> bool {
+        self.used.is_some()
+    }
+
+    /// Send `value` to the client, or remove the session if it is `None`.
+    ///
+    /// The transport the client used is used to respond. If the client did not present a session,
+    /// every transport is used since it is not known which one the client supports.
+    pub(crate) fn respond(
+        &self,
+        headers: &mut HeaderMap,
+        config: &Config,
+        value: Option<(String, Expiry)>,
+        now: OffsetDateTime,
+    ) {
+        match self.used {
+            Some(index) => self.transports[index].respond(headers, config, value, self.sent, now),
+            None => {
+                for transport in self.transports.iter() {
+                    transport.respond(headers, config, value.clone(), self.sent, now);
+                }
+            }
+        }
+    }
+}
+
+/// The default transports, with only the session cookie.
+pub(crate) fn cookie() -> Arc<[Transport]> {
+    Arc::new([Transport::Cookie])
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+    use axum::body::Body;
+    use http::{
+        header::{COOKIE, SET_COOKIE},
+        Request, Response,
+    };
+    use tower::{Service, ServiceBuilder, ServiceExt};
+    use tower_sesh_core::Expires;
+    use tower_sesh_memory_store::MemoryStore;
+
+    use super::*;
+    use crate::{Session, SessionManagerLayer};
+
+    #[derive(Debug, Clone)]
+    struct Record;
+    impl Expires for Record {}
+
+    const SESSION_ID: HeaderName = HeaderName::from_static("x-session-id");
+
+    /// Create a session on `/create`, delete it on `/delete`, and only load it otherwise.
+    async fn handler(mut req: Request<Body>) -> anyhow::Result<Response<Body>> {
+        let session = req
+            .extensions_mut()
+            .remove::<Session<MemoryStore<Record>>>()
+            .ok_or(anyhow!("Missing session"))?;
+
+        let state = session.clone().load().await?;
+        match (req.uri().path(), state) {
+            ("/create", _) => {
+                session.create(Record).await?;
+            }
+            ("/delete", Some(state)) => {
+                state.delete().await?;
+            }
+            _ => {}
+        }
+        Ok(Response::new(Body::empty()))
+    }
+
+    fn svc(
+        transports: impl IntoIterator<Item = Transport>,
+    ) -> impl Service<Request<Body>, Response = Response<Body>, Error = anyhow::Error> + Clone {
+        let session_store: MemoryStore<Record> = MemoryStore::default();
+        ServiceBuilder::new()
+            .layer(
+                SessionManagerLayer::new(session_store, Default::default())
+                    .with_transports(transports),
+            )
+            .service_fn(handler)
+    }
+
+    fn header<'a>(res: &'a Response<Body>, name: &HeaderName) -> Option<&'a str> {
+        res.headers()
+            .get(name)
+            .map(|value| value.to_str().expect("header should be a string"))
+    }
+
+    #[tokio::test]
+    async fn header_transport() -> anyhow::Result<()> {
+        let svc = svc([Transport::Header(SESSION_ID)]);
+
+        let req = Request::builder().uri("/create").body(Body::empty())?;
+        let res = svc.clone().oneshot(req).await?;
+        assert!(res.headers().get(SET_COOKIE).is_none());
+        let id = header(&res, &SESSION_ID)
+            .expect("the session should be sent in the header")
+            .to_owned();
+        assert!(!id.is_empty());
+
+        let req = Request::builder()
+            .uri("/delete")
+            .header(SESSION_ID, &id)
+            .body(Body::empty())?;
+        let res = svc.clone().oneshot(req).await?;
+        assert_eq!(header(&res, &SESSION_ID), Some(""));
+        assert!(res.headers().get(SET_COOKIE).is_none());
+
+        // Unknown sessions are removed from the client as well.
+        let req = Request::builder()
+            .uri("/")
+            .header(SESSION_ID, &id)
+            .body(Body::empty())?;
+        let res = svc.clone().oneshot(req).await?;
+        assert_eq!(header(&res, &SESSION_ID), Some(""));
+
+        // Nothing is sent when the session is not modified.
+        let req = Request::builder().uri("/").body(Body::empty())?;
+        let res = svc.oneshot(req).await?;
+        assert_eq!(header(&res, &SESSION_ID), None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn bearer_transport() -> anyhow::Result<()> {
+        let svc = svc([Transport::Bearer {
+            response_header: SESSION_ID,
+        }]);
+
+        let req = Request::builder().uri("/create").body(Body::empty())?;
+        let res = svc.clone().oneshot(req).await?;
+        let id = header(&res, &SESSION_ID)
+            .expect("the session should be sent in the response header")
+            .to_owned();
+
+        let req = Request::builder()
+            .uri("/delete")
+            .header(AUTHORIZATION, format!("bearer  {id}"))
+            .body(Body::empty())?;
+        let res = svc.clone().oneshot(req).await?;
+        assert_eq!(header(&res, &SESSION_ID), Some(""));
+
+        // Other schemes are ignored.
+        let req = Request::builder()
+            .uri("/delete")
+            .header(AUTHORIZATION, format!("Basic {id}"))
+            .body(Body::empty())?;
+        let res = svc.oneshot(req).await?;
+        assert_eq!(header(&res, &SESSION_ID), None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn precedence() -> anyhow::Result<()> {
+        let svc = svc([Transport::Header(SESSION_ID), Transport::Cookie]);
+
+        // Without a session, new sessions are sent with every transport.
+        let req = Request::builder().uri("/create").body(Body::empty())?;
+        let res = svc.clone().oneshot(req).await?;
+        let id = header(&res, &SESSION_ID)
+            .expect("the session should be sent in the header")
+            .to_owned();
+        let cookie = header(&res, &SET_COOKIE).expect("the session cookie should be set");
+        assert!(cookie.starts_with(&format!("id={id};")));
+
+        // The header takes precedence over the cookie, and is the only one used to respond.
+        let req = Request::builder()
+            .uri("/delete")
+            .header(SESSION_ID, &id)
+            .header(COOKIE, "id=AAAAAAAAAAAAAAAAAAAAAA")
+            .body(Body::empty())?;
+        let res = svc.clone().oneshot(req).await?;
+        assert_eq!(header(&res, &SESSION_ID), Some(""));
+        assert!(res.headers().get(SET_COOKIE).is_none());
+
+        // The header takes precedence even if its session is invalid.
+        let req = Request::builder()
+            .uri("/create")
+            .header(SESSION_ID, "bogus")
+            .header(COOKIE, format!("id={id}"))
+            .body(Body::empty())?;
+        let res = svc.clone().oneshot(req).await?;
+        assert!(header(&res, &SESSION_ID).is_some_and(|new_id| new_id != id));
+        assert!(res.headers().get(SET_COOKIE).is_none());
+
+        // The cookie is used when the header is missing.
+        let req = Request::builder()
+            .uri("/create")
+            .header(COOKIE, format!("id={id}"))
+            .body(Body::empty())?;
+        let res = svc.oneshot(req).await?;
+        assert!(res.headers().get(SET_COOKIE).is_some());
+        assert_eq!(header(&res, &SESSION_ID), None);
+
+        Ok(())
+    }
+
+    #[test]
+    #[should_panic = "at least one transport"]
+    fn no_transports() {
+        let session_store: MemoryStore<Record> = MemoryStore::default();
+        let _ = SessionManagerLayer::new(session_store, Default::default()).with_transports([]);
+    }
+}
@@ -0,0 +1,89 @@
+//! Rate limiting of the warnings logged for suspicious requests.
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Limits how many warnings about suspicious requests are logged, so that scanners sending bogus
+/// sessions cannot flood the logs.
+///
+/// At most `max` warnings are logged per `period`. The number of suppressed warnings is logged
+/// when the next period starts.
+#[derive(Debug)]
+pub(crate) struct WarnLimit {
+    max: u32,
+    period: Duration,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    start: Instant,
+    count: u32,
+}
+
+impl WarnLimit {
+    pub(crate) fn new(max: u32, period: Duration) -> Self {
+        Self {
+            max,
+            period,
+            state: Mutex::new(State {
+                start: Instant::now(),
+                count: 0,
+            }),
+        }
+    }
+
+    /// Whether a warning can be logged now.
+    pub(crate) fn allow(&self) -> bool {
+        let mut state = self.state.lock().expect("lock should not be poisoned");
+        let now = Instant::now();
+        if now.duration_since(state.start) >= self.period {
+            let suppressed = state.count.saturating_sub(self.max);
+            if suppressed > 0 {
+                tracing::warn!(
+                    suppressed,
+                    "suppressed warnings about possibly suspicious activity"
+                );
+            }
+            *state = State {
+                start: now,
+                count: 0,
+            };
+        }
+        state.count = state.count.saturating_add(1);
+        state.count <= self.max
+    }
+}
+
+impl Default for WarnLimit {
+    /// Log at most 10 warnings per second.
+    fn default() -> Self {
+        Self::new(10, Duration::from_secs(1))
+    }
+}
+
+/// Log a warning about possibly suspicious activity, unless the [`WarnLimit`] is exceeded.
+macro_rules! suspicious {
+    ($limit:expr, $($arg:tt)+) => {
+        if $limit.allow() {
+            tracing::warn!($($arg)+);
+        }
+    };
+}
+pub(crate) use suspicious;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_warnings() {
+        let limit = WarnLimit::new(3, Duration::from_millis(50));
+        let allowed = (0..10).filter(|_| limit.allow()).count();
+        assert_eq!(allowed, 3);
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(limit.allow());
+    }
+}
@@ -0,0 +1,92 @@
+use axum::{body::Body, routing::get, Router};
+use cookie::{Cookie, SameSite};
+use http::{header, Request, Response};
+use tower::ServiceExt;
+use tower_sesh::{middleware::Config, Expires, MemoryStore, Session, SessionManagerLayer};
+
+#[derive(Debug, Clone)]
+struct Record;
+
+impl Expires for Record {}
+
+type Store = MemoryStore<Record>;
+
+fn app(config: Config) -> Router {
+    Router::new()
+        .route(
+            "/create",
+            get(|session: Session<Store>| async move {
+                session.create(Record).await.unwrap();
+            }),
+        )
+        .route(
+            "/delete",
+            get(|session: Session<Store>| async move {
+                session
+                    .load()
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .delete()
+                    .await
+                    .unwrap();
+            }),
+        )
+        .layer(SessionManagerLayer::new(Store::default(), config))
+}
+
+async fn get_with_cookie(app: Router, uri: &str, cookie: Option<&Cookie<'_>>) -> Response<Body> {
+    let mut req = Request::builder().uri(uri);
+    if let Some(cookie) = cookie {
+        req = req.header(header::COOKIE, cookie.stripped().to_string());
+    }
+    app.oneshot(req.body(Body::empty()).unwrap()).await.unwrap()
+}
+
+fn session_cookie(res: &Response<Body>) -> (String, Cookie<'static>) {
+    let mut set_cookies = res.headers().get_all(header::SET_COOKIE).into_iter();
+    let header = set_cookies
+        .next()
+        .expect("a session cookie should be set")
+        .to_str()
+        .unwrap()
+        .to_owned();
+    assert!(set_cookies.next().is_none());
+    let cookie = Cookie::parse(header.clone()).unwrap();
+    (header, cookie)
+}
+
+fn partitioned_config() -> Config {
+    Config::builder()
+        .same_site(SameSite::None)
+        .partitioned(true)
+        .build()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn partitioned_cookie() {
+    let app = app(partitioned_config());
+
+    let res = get_with_cookie(app.clone(), "/create", None).await;
+    let (header, cookie) = session_cookie(&res);
+    assert!(header.contains("; Partitioned"));
+    assert!(header.contains("; Secure"));
+    assert!(header.contains("; SameSite=None"));
+    assert_eq!(cookie.partitioned(), Some(true));
+
+    // The removal cookie must also be partitioned, or the browser would not match it.
+    let res = get_with_cookie(app, "/delete", Some(&cookie)).await;
+    let (header, removal) = session_cookie(&res);
+    assert_eq!(removal.name(), cookie.name());
+    assert_eq!(removal.value(), "");
+    assert!(header.contains("; Partitioned"));
+}
+
+#[tokio::test]
+async fn not_partitioned_by_default() {
+    let res = get_with_cookie(app(Config::default()), "/create", None).await;
+    let (header, cookie) = session_cookie(&res);
+    assert!(!header.contains("Partitioned"));
+    assert_eq!(cookie.partitioned(), None);
+}
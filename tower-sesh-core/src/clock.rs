@@ -0,0 +1,75 @@
+use std::{
+    fmt::Debug,
+    sync::{Arc, Mutex},
+};
+
+use time::{Duration, OffsetDateTime};
+
+/// A source of the current date and time, used to compute the expiration of sessions.
+///
+/// The [`SystemClock`] is used by default. A [`ManualClock`] can be used instead to test
+/// expiration without waiting.
+pub trait Clock: Debug + Send + Sync {
+    /// Returns the current date and time.
+    fn now(&self) -> OffsetDateTime;
+}
+
+/// The clock of the system, in UTC.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> OffsetDateTime {
+        OffsetDateTime::now_utc()
+    }
+}
+
+/// A clock that only moves when it is told to.
+///
+/// Clones share the same time, so a clone can be given to a store or a middleware, and advanced
+/// from a test.
+///
+/// # Examples
+///
+/// ```rust
+/// use time::{Duration, OffsetDateTime};
+/// use tower_sesh_core::clock::{Clock, ManualClock};
+///
+/// let clock = ManualClock::new(OffsetDateTime::UNIX_EPOCH);
+/// let handle = clock.clone();
+///
+/// handle.advance(Duration::hours(1));
+/// assert_eq!(clock.now(), OffsetDateTime::UNIX_EPOCH + Duration::hours(1));
+/// ```
+#[derive(Debug, Clone)]
+pub struct ManualClock(Arc<Mutex<OffsetDateTime>>);
+
+impl ManualClock {
+    /// Create a clock stopped at `now`.
+    pub fn new(now: OffsetDateTime) -> Self {
+        Self(Arc::new(Mutex::new(now)))
+    }
+
+    /// Move the clock forward by `duration`, or backward if it is negative.
+    pub fn advance(&self, duration: Duration) {
+        *self.0.lock().expect("lock should not be poisoned") += duration;
+    }
+
+    /// Set the clock to `now`.
+    pub fn set(&self, now: OffsetDateTime) {
+        *self.0.lock().expect("lock should not be poisoned") = now;
+    }
+}
+
+impl Default for ManualClock {
+    /// A clock stopped at the current time of the system.
+    fn default() -> Self {
+        Self::new(OffsetDateTime::now_utc())
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> OffsetDateTime {
+        *self.0.lock().expect("lock should not be poisoned")
+    }
+}
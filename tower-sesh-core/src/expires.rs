@@ -1,4 +1,7 @@
+use std::sync::Arc;
+
 use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
 
 /// Trait for types that can expire.
 ///
@@ -60,6 +63,13 @@ pub trait Expires {
     }
 }
 
+/// Shared records expire like the records they point to.
+impl<T: Expires + ?Sized> Expires for Arc<T> {
+    fn expires(&self) -> Expiry {
+        (**self).expires()
+    }
+}
+
 /// Session expiry configuration.
 ///
 /// # Examples
@@ -77,8 +87,15 @@ pub trait Expires {
 /// // Will be expired at the given timestamp.
 /// let expired_at = OffsetDateTime::now_utc().saturating_add(Duration::weeks(2));
 /// let expiry = Expiry::AtDateTime(expired_at);
+///
+/// // Will be expired in five minutes from last active, and at the latest in two weeks.
+/// let expiry = Expiry::OnInactivityUntil(Duration::minutes(5), expired_at);
 /// ```
+///
+/// More ways to expire may be added in minor releases, so matches on `Expiry` need a wildcard arm.
+/// [`Expiry::deadline`] gives the expiration of any of them.
 #[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[non_exhaustive]
 pub enum Expiry {
     /// __Browser:__ Expire on [current session end][current-session-end], as defined by the
     /// browser.
@@ -93,16 +110,50 @@ pub enum Expiry {
     /// Reading a session is not considered activity for expiration purposes. Expiration
     /// is computed from the last time the session was modified. That is, when
     /// the session is created ([`SessionStore::create`]), when it is saved
-    /// ([`SessionStore::save`]/[`SessionStore::save_or_create`]), and when its [`Id`] is cycled
-    /// ([`SessionStore::cycle_id`]).
+    /// ([`SessionStore::save`]/[`SessionStore::save_or_create`]), when its [`Id`] is cycled
+    /// ([`SessionStore::cycle_id`]), and when it is touched ([`SessionStore::touch`]).
     ///
     /// [`Id`]: crate::Id
     /// [`SessionStore::create`]: crate::SessionStore::create
     /// [`SessionStore::save`]: crate::SessionStore::save
     /// [`SessionStore::save_or_create`]: crate::SessionStore::save_or_create
     /// [`SessionStore::cycle_id`]: crate::SessionStore::cycle_id
+    /// [`SessionStore::touch`]: crate::SessionStore::touch
     OnInactivity(time::Duration),
 
     /// Expire at a specific date and time.
     AtDateTime(time::OffsetDateTime),
+
+    /// Expire on inactivity, but no later than a specific date and time.
+    ///
+    /// This combines an idle timeout with an absolute lifetime, as recommended by
+    /// [OWASP](https://cheatsheetseries.owasp.org/cheatsheets/Session_Management_Cheat_Sheet.html#session-expiration).
+    /// The session expires like with [`Expiry::OnInactivity`], or at the given date and time,
+    /// whichever comes first. The date and time is usually computed once, when the session is
+    /// created, and kept in the session record.
+    OnInactivityUntil(time::Duration, time::OffsetDateTime),
+}
+
+impl Expiry {
+    /// The date and time at which a session modified at `now` expires, or `None` if it expires
+    /// on [session end][Expiry::OnSessionEnd].
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use time::{Duration, OffsetDateTime};
+    /// use tower_sesh_core::Expiry;
+    ///
+    /// let now = OffsetDateTime::now_utc();
+    /// let expiry = Expiry::OnInactivityUntil(Duration::hours(1), now + Duration::minutes(5));
+    /// assert_eq!(expiry.deadline(now), Some(now + Duration::minutes(5)));
+    /// ```
+    pub fn deadline(&self, now: OffsetDateTime) -> Option<OffsetDateTime> {
+        match *self {
+            Expiry::OnSessionEnd => None,
+            Expiry::OnInactivity(duration) => Some(now + duration),
+            Expiry::AtDateTime(date_time) => Some(date_time),
+            Expiry::OnInactivityUntil(duration, date_time) => Some(date_time.min(now + duration)),
+        }
+    }
 }
@@ -6,6 +6,7 @@
 pub use self::session_store::SessionStore;
 pub use self::id::Id;
 pub use self::expires::{Expires, Expiry};
+pub use self::clock::Clock;
 
 /// A trait for session storage and retrieval.
 pub mod session_store;
@@ -13,3 +14,11 @@ pub mod session_store;
 pub mod expires;
 /// Session IDs.
 pub mod id;
+/// Clocks used to compute session expiration.
+pub mod clock;
+/// Periodic tasks of session stores.
+#[cfg(feature = "task")]
+pub mod task;
+/// Tests shared by the session stores.
+#[cfg(feature = "test-util")]
+pub mod test_util;
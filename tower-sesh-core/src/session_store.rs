@@ -26,6 +26,7 @@ use std::{fmt::Debug, future::Future};
 use either::Either::{self, Left, Right};
 use futures_util::future::try_join;
 use futures_util::TryFutureExt;
+use time::OffsetDateTime;
 
 use crate::id::Id;
 
@@ -169,6 +170,100 @@ pub trait SessionStore<R: Send + Sync>: Send + Sync {
             }
         }
     }
+
+    /// Refresh the expiration of a session record, as if it was saved without modifications.
+    ///
+    /// This is used by sliding sessions, whose [`Expiry::OnInactivity`] is renewed by every
+    /// request, even if the session is only read.
+    ///
+    /// # Implementations
+    ///
+    /// In the successful path, implementations _must_ return `bool` indicating whether the
+    /// session existed and thus was refreshed, or if it did not exist (or was expired).
+    ///
+    /// If the implementation handles expiration, it _should_ update the expiration time on the
+    /// session record, like [`SessionStore::save`] does.
+    ///
+    /// ### Note
+    ///
+    /// The default implementation uses one `load` and one `save` operation. It is recommended to
+    /// implement it more efficiently whenever possible, since it can be called for every request.
+    ///
+    /// [`Expiry::OnInactivity`]: crate::Expiry::OnInactivity
+    fn touch(&mut self, id: &Id) -> impl Future<Output = Result<bool, Self::Error>> + Send {
+        async move {
+            match self.load(id).await? {
+                Some(record) => self.save(id, &record).await,
+                None => Ok(false),
+            }
+        }
+    }
+
+    /// Loads an existing session record like [`SessionStore::load`], along with the date and
+    /// time at which the store expires it.
+    ///
+    /// This is used by [`CachingSessionStore`], so that its cache does not keep a session for
+    /// longer than its store.
+    ///
+    /// # Implementations
+    ///
+    /// Implementations that handle expiration _should_ return the expiry date they keep for the
+    /// session, i.e. the date at which it expires unless it is saved or touched before.
+    ///
+    /// ### Note
+    ///
+    /// The default implementation uses one `load` operation, and returns
+    /// [`ExpiryDate::Unknown`]. [`CachingSessionStore`] then caches the session as if it was
+    /// saved when loaded, so the cache may keep it for longer than the store.
+    fn load_with_expiry_date(
+        &mut self,
+        id: &Id,
+    ) -> impl Future<Output = Result<Option<(R, ExpiryDate)>, Self::Error>> + Send {
+        async move {
+            let record = self.load(id).await?;
+            Ok(record.map(|record| (record, ExpiryDate::Unknown)))
+        }
+    }
+
+    /// Save the provided session record like [`SessionStore::save_or_create`], but expire it no
+    /// later than `expiry_date`.
+    ///
+    /// This is used by [`CachingSessionStore`] to cache a session loaded from its store, with
+    /// the expiry date of the store.
+    ///
+    /// # Implementations
+    ///
+    /// In the successful path, implementations _must_ return `bool` indicating whether the
+    /// record was saved. Implementations _must not_ keep the record after `expiry_date`.
+    ///
+    /// If the implementation handles expiration, it _should_ expire the record at the earliest of
+    /// `expiry_date` and the expiration time it would set in [`SessionStore::save_or_create`].
+    ///
+    /// ### Note
+    ///
+    /// The default implementation does not save the record and returns `Ok(false)`, since the
+    /// store could keep it for longer.
+    fn save_or_create_until(
+        &mut self,
+        id: &Id,
+        record: &R,
+        expiry_date: OffsetDateTime,
+    ) -> impl Future<Output = Result<bool, Self::Error>> + Send {
+        let _ = (id, record, expiry_date);
+        async { Ok(false) }
+    }
+}
+
+/// The date and time at which a store expires a session, as returned by
+/// [`SessionStore::load_with_expiry_date`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ExpiryDate {
+    /// The session expires at this date and time, unless it is saved or touched before.
+    At(OffsetDateTime),
+    /// The session never expires in the store.
+    Never,
+    /// The store does not tell when the session expires.
+    Unknown,
 }
 
 /// Provides a layered caching mechanism with a cache as the frontend and a
@@ -177,6 +272,13 @@ pub trait SessionStore<R: Send + Sync>: Send + Sync {
 /// By using a cache, the cost of reads can be greatly reduced as once cached,
 /// reads need only interact with the frontend, forgoing the cost of retrieving
 /// the session record from the backend.
+///
+/// A session loaded from the backend is cached until the expiry date reported by
+/// [`SessionStore::load_with_expiry_date`], with [`SessionStore::save_or_create_until`], so that
+/// the cache does not serve it after the backend expired it. This requires a cache implementing
+/// `save_or_create_until`, like the memory stores; the others do not cache such sessions. When
+/// the backend does not report expiry dates, the session is cached with
+/// [`SessionStore::save_or_create`], and expires in the cache according to its record.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct CachingSessionStore<Cache, Store> {
     cache: Cache,
@@ -230,16 +332,30 @@ where
         match self.cache.load(id).await {
             Ok(Some(session_record)) => Ok(Some(session_record)),
             Ok(None) => {
-                let session_record = self.store.load(id).await.map_err(Right)?;
+                let Some((session_record, expiry_date)) =
+                    self.store.load_with_expiry_date(id).await.map_err(Right)?
+                else {
+                    return Ok(None);
+                };
 
-                if let Some(ref session_record) = session_record {
-                    self.cache
-                        .save(id, session_record)
-                        .await
-                        .map_err(Either::Left)?;
+                // The cache must not serve the session once the store has expired it, so the
+                // session is only cached until the expiry date of the store, when it is known.
+                match expiry_date {
+                    ExpiryDate::At(expiry_date) => {
+                        self.cache
+                            .save_or_create_until(id, &session_record, expiry_date)
+                            .await
+                            .map_err(Either::Left)?;
+                    }
+                    ExpiryDate::Never | ExpiryDate::Unknown => {
+                        self.cache
+                            .save_or_create(id, &session_record)
+                            .await
+                            .map_err(Either::Left)?;
+                    }
                 }
 
-                Ok(session_record)
+                Ok(Some(session_record))
             }
             Err(err) => Err(Left(err)),
         }
@@ -262,4 +378,17 @@ where
             .await
             .map(|(_, new_id)| new_id)
     }
+
+    async fn touch(&mut self, id: &Id) -> Result<bool, Self::Error> {
+        let store_touch_fut = self.store.touch(id).map_err(Right);
+        let cache_touch_fut = self.cache.touch(id).map_err(Left);
+
+        let (exists_cache, exists_store) = try_join(cache_touch_fut, store_touch_fut).await?;
+
+        if !exists_store && exists_cache {
+            self.cache.delete(id).await.map_err(Left)?;
+        }
+
+        Ok(exists_store)
+    }
 }
@@ -0,0 +1,278 @@
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::future::Future;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use tokio::{sync::oneshot, task::JoinHandle};
+
+/// A task running periodically on behalf of a session store, e.g. to remove expired sessions.
+///
+/// The task works on a target shared with the store, e.g. its connection, which it only holds
+/// weakly between runs: the task stops once every clone of the store has been dropped. It also
+/// stops when [`shutdown`](PeriodicTask::shutdown) is called, or when the task handle is dropped,
+/// without waiting for it.
+///
+/// Stores wrap this type in their own task type, so that it can be documented along with the
+/// store. Stores removing their expired sessions periodically can use [`CleanupTask`] instead.
+#[derive(Debug)]
+pub struct PeriodicTask<T = ()> {
+    shutdown: oneshot::Sender<()>,
+    task: JoinHandle<Option<T>>,
+}
+
+impl PeriodicTask {
+    /// Spawn a task on the current tokio runtime that calls `tick` with `target` every
+    /// `interval`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside of a tokio runtime, or if `interval` is zero.
+    pub fn spawn<W, F, Fut>(target: &Arc<W>, interval: Duration, mut tick: F) -> Self
+    where
+        W: ?Sized + Send + Sync + 'static,
+        F: FnMut(Arc<W>) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send,
+    {
+        Self::start(target, interval, false, move |target, _| tick(target))
+    }
+}
+
+impl<T: Send + 'static> PeriodicTask<T> {
+    /// Spawn a task on the current tokio runtime that calls `tick` with `target` every
+    /// `interval`, and a last time when the task is shut down.
+    ///
+    /// The second argument of `tick` tells whether the call is the last one, whose output is
+    /// returned by [`shutdown`](PeriodicTask::shutdown). The outputs of the other calls are
+    /// dropped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside of a tokio runtime, or if `interval` is zero.
+    pub fn spawn_with_last_tick<W, F, Fut>(target: &Arc<W>, interval: Duration, tick: F) -> Self
+    where
+        W: ?Sized + Send + Sync + 'static,
+        F: FnMut(Arc<W>, bool) -> Fut + Send + 'static,
+        Fut: Future<Output = T> + Send,
+    {
+        Self::start(target, interval, true, tick)
+    }
+
+    fn start<W, F, Fut>(target: &Arc<W>, interval: Duration, last_tick: bool, mut tick: F) -> Self
+    where
+        W: ?Sized + Send + Sync + 'static,
+        F: FnMut(Arc<W>, bool) -> Fut + Send + 'static,
+        Fut: Future<Output = T> + Send,
+    {
+        assert!(
+            !interval.is_zero(),
+            "the interval of a periodic task must not be zero"
+        );
+        let target = Arc::downgrade(target);
+        let (shutdown, mut stopped) = oneshot::channel();
+        let task = tokio::spawn(async move {
+            loop {
+                // The shutdown is requested when the timeout does not elapse, or when the task
+                // handle is dropped.
+                let last = tokio::time::timeout(interval, &mut stopped).await.is_ok();
+                if last && !last_tick {
+                    return None;
+                }
+                let target = Weak::upgrade(&target)?;
+                let output = tick(target, last).await;
+                if last {
+                    return Some(output);
+                }
+            }
+        });
+        PeriodicTask { shutdown, task }
+    }
+
+    /// Stop the task, waiting for a run in progress to finish.
+    ///
+    /// Returns the output of the last call of the task, for a task spawned with
+    /// [`spawn_with_last_tick`](PeriodicTask::spawn_with_last_tick). Returns `None` if there was
+    /// no last call, e.g. because the store was dropped, or the runtime is shutting down.
+    ///
+    /// # Panics
+    ///
+    /// Panics with the panic of the task, if the task panicked.
+    pub async fn shutdown(self) -> Option<T> {
+        let _ = self.shutdown.send(());
+        match self.task.await {
+            Ok(output) => output,
+            Err(err) if err.is_panic() => std::panic::resume_unwind(err.into_panic()),
+            Err(_) => None,
+        }
+    }
+
+    /// Whether the task has stopped.
+    pub fn is_finished(&self) -> bool {
+        self.task.is_finished()
+    }
+}
+
+/// A task removing the expired sessions of a session store periodically.
+///
+/// Created by the stores, e.g. with a `spawn_cleanup` method. Dropping the task handle stops the
+/// task without waiting for it.
+#[derive(Debug)]
+pub struct CleanupTask(PeriodicTask);
+
+impl CleanupTask {
+    /// Spawn a task on the current tokio runtime that calls `delete_expired` with `target` every
+    /// `interval`.
+    ///
+    /// `delete_expired` returns how many sessions were removed. Failures are logged, and the
+    /// task keeps running. Like a [`PeriodicTask`], the task stops once every clone of the store
+    /// has been dropped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside of a tokio runtime, or if `interval` is zero.
+    pub fn spawn<W, F, Fut, E>(target: &Arc<W>, interval: Duration, mut delete_expired: F) -> Self
+    where
+        W: ?Sized + Send + Sync + 'static,
+        F: FnMut(Arc<W>) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<usize, E>> + Send,
+        E: Display,
+    {
+        CleanupTask(PeriodicTask::spawn(target, interval, move |target| {
+            let deleted = delete_expired(target);
+            async move {
+                match deleted.await {
+                    Ok(deleted) => tracing::debug!(deleted, "deleted expired sessions"),
+                    Err(err) => tracing::error!(err = %err, "failed to delete expired sessions"),
+                }
+            }
+        }))
+    }
+
+    /// Stop the task, waiting for a cleanup in progress to finish.
+    pub async fn shutdown(self) {
+        self.0.shutdown().await;
+    }
+
+    /// Whether the task has stopped.
+    pub fn is_finished(&self) -> bool {
+        self.0.is_finished()
+    }
+}
+
+/// The error of an operation run with [`blocking`] that was cancelled before it ran, because
+/// the runtime is shutting down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Cancelled;
+
+impl Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("the session operation was cancelled")
+    }
+}
+
+impl Error for Cancelled {}
+
+/// Run `f` on tokio's blocking threads, e.g. for a store using a synchronous database.
+///
+/// # Panics
+///
+/// Panics if called outside of a tokio runtime, and with the panic of `f`, if `f` panicked.
+pub async fn blocking<T, E, F>(f: F) -> Result<T, E>
+where
+    T: Send + 'static,
+    E: From<Cancelled> + Send + 'static,
+    F: FnOnce() -> Result<T, E> + Send + 'static,
+{
+    match tokio::task::spawn_blocking(f).await {
+        Ok(result) => result,
+        Err(err) if err.is_panic() => std::panic::resume_unwind(err.into_panic()),
+        Err(_) => Err(Cancelled.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    const INTERVAL: Duration = Duration::from_secs(60);
+
+    /// Let the paused time of the runtime advance by `intervals` times the [`INTERVAL`], and by
+    /// half an interval more, so that the ticks due in the meantime have run.
+    async fn wait(intervals: u32) {
+        tokio::time::sleep(INTERVAL * intervals + INTERVAL / 2).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn periodic_task() {
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let task = PeriodicTask::spawn(&ticks, INTERVAL, |ticks| async move {
+            ticks.fetch_add(1, Ordering::Relaxed);
+        });
+
+        wait(3).await;
+        assert_eq!(ticks.load(Ordering::Relaxed), 3);
+        task.shutdown().await;
+        // No last tick.
+        assert_eq!(ticks.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn periodic_task_stops_with_its_target() {
+        let target = Arc::new(());
+        let task = PeriodicTask::spawn(&target, INTERVAL, |_| async {});
+
+        wait(1).await;
+        assert!(!task.is_finished());
+        drop(target);
+        wait(1).await;
+        assert!(task.is_finished());
+        assert_eq!(task.shutdown().await, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn last_tick() {
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let task = PeriodicTask::spawn_with_last_tick(&ticks, INTERVAL, |ticks, last| async move {
+            (ticks.fetch_add(1, Ordering::Relaxed) + 1, last)
+        });
+
+        wait(2).await;
+        assert_eq!(task.shutdown().await, Some((3, true)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cleanup_task() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let task = CleanupTask::spawn(&calls, INTERVAL, |calls| async move {
+            match calls.fetch_add(1, Ordering::Relaxed) {
+                0 => Err("the store failed"),
+                _ => Ok(1),
+            }
+        });
+
+        // The task keeps running after a failure.
+        wait(2).await;
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+        assert!(!task.is_finished());
+        task.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn blocking_operations() {
+        #[derive(Debug, PartialEq)]
+        struct Error;
+
+        impl From<Cancelled> for Error {
+            fn from(Cancelled: Cancelled) -> Self {
+                panic!("the operation should not be cancelled")
+            }
+        }
+
+        assert_eq!(blocking(|| Ok::<_, Error>(1)).await, Ok(1));
+        assert_eq!(blocking(|| Err::<(), _>(Error)).await, Err(Error));
+        let panicked = tokio::spawn(blocking(|| -> Result<(), Error> { panic!("failed") }));
+        assert!(panicked.await.unwrap_err().is_panic());
+    }
+}
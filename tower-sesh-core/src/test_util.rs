@@ -0,0 +1,289 @@
+use std::fmt::Debug;
+use std::future::Future;
+
+use serde::{Deserialize, Serialize};
+use time::{Duration, OffsetDateTime};
+
+use crate::{clock::ManualClock, session_store::ExpiryDate, Expires, Expiry, Id, SessionStore};
+
+/// The date and time at which the clocks of the stores start.
+pub const START: OffsetDateTime = OffsetDateTime::UNIX_EPOCH;
+
+/// The record stored by the tests.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    /// The expiry of the session.
+    pub expiry: Expiry,
+    /// A value changed by the tests, to check that the records are saved.
+    pub value: u32,
+}
+
+impl Expires for Record {
+    fn expires(&self) -> Expiry {
+        self.expiry
+    }
+}
+
+/// A record with the given `expiry`.
+pub fn record(expiry: Expiry) -> Record {
+    Record { expiry, value: 0 }
+}
+
+/// Check that the stores made by `new_store` fulfill the contract of [`SessionStore`], and
+/// handle the expiry of the sessions.
+///
+/// `new_store` is called with a [`ManualClock`] set to [`START`], and must return a new empty
+/// store using this clock. Tests of the features specific to a store, e.g. removing the expired
+/// sessions, are left to the store.
+///
+/// # Panics
+///
+/// Panics if the store does not fulfill the contract, or returns an error.
+///
+/// # Examples
+///
+/// ```rust
+/// use tower_sesh::MemoryStore;
+///
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// tower_sesh_core::test_util::check_store(MemoryStore::with_clock).await;
+/// # }
+/// ```
+pub async fn check_store<S, F>(mut new_store: F)
+where
+    S: SessionStore<Record>,
+    S::Error: Debug,
+    F: FnMut(ManualClock) -> S,
+{
+    let mut store = || {
+        let clock = ManualClock::new(START);
+        (new_store(clock.clone()), clock)
+    };
+    round_trip(store()).await;
+    on_inactivity(store()).await;
+    at_date_time(store()).await;
+    on_inactivity_until(store()).await;
+    cycled_sessions_are_refreshed(store()).await;
+    for expiry in expiring() {
+        expired_sessions_are_invisible(store(), expiry).await;
+    }
+    expired_records_are_not_stored(store()).await;
+    expiry_dates(store()).await;
+}
+
+/// Check that `delete_expired` removes the expired sessions of the store made by `new_store`,
+/// and returns how many were removed.
+///
+/// `new_store` is called like in [`check_store`]. `delete_expired` is called with a clone of
+/// the store, and usually calls a method of the store removing the expired sessions, which is
+/// also what the cleanup tasks of the stores run.
+///
+/// # Panics
+///
+/// Panics if the expired sessions are not removed, or if the store returns an error.
+pub async fn check_delete_expired<S, F, D, Fut, E>(new_store: F, mut delete_expired: D)
+where
+    S: SessionStore<Record> + Clone,
+    S::Error: Debug,
+    F: FnOnce(ManualClock) -> S,
+    D: FnMut(S) -> Fut,
+    Fut: Future<Output = Result<usize, E>>,
+    E: Debug,
+{
+    let clock = ManualClock::new(START);
+    let mut store = new_store(clock.clone());
+    for minutes in [1, 2, 3] {
+        store
+            .create(&record(Expiry::OnInactivity(Duration::minutes(minutes))))
+            .await
+            .unwrap();
+    }
+    let forever = store.create(&record(Expiry::OnSessionEnd)).await.unwrap();
+    assert_eq!(delete_expired(store.clone()).await.unwrap(), 0);
+    // An expired record is never stored, so there is nothing to delete.
+    store
+        .create(&record(Expiry::AtDateTime(START - Duration::minutes(1))))
+        .await
+        .unwrap();
+
+    clock.advance(Duration::minutes(2));
+    assert_eq!(delete_expired(store.clone()).await.unwrap(), 2);
+    clock.advance(Duration::days(1));
+    assert_eq!(delete_expired(store.clone()).await.unwrap(), 1);
+    assert!(store.load(&forever).await.unwrap().is_some());
+}
+
+/// Every variant that eventually expires, each expiring 10 minutes after [`START`] unless
+/// renewed.
+pub fn expiring() -> [Expiry; 3] {
+    [
+        Expiry::OnInactivity(Duration::minutes(10)),
+        Expiry::AtDateTime(START + Duration::minutes(10)),
+        Expiry::OnInactivityUntil(Duration::minutes(10), START + Duration::hours(1)),
+    ]
+}
+
+async fn round_trip<S>((mut store, _): (S, ManualClock))
+where
+    S: SessionStore<Record>,
+    S::Error: Debug,
+{
+    let mut session = record(Expiry::OnSessionEnd);
+
+    let id = store.create(&session).await.unwrap();
+    assert_eq!(store.load(&id).await.unwrap(), Some(session.clone()));
+
+    session.value = 1;
+    assert!(store.save(&id, &session).await.unwrap());
+    assert_eq!(store.load(&id).await.unwrap(), Some(session.clone()));
+
+    let new_id = store.cycle_id(&id).await.unwrap().unwrap();
+    assert_ne!(id, new_id);
+    assert_eq!(store.load(&id).await.unwrap(), None);
+    assert_eq!(store.load(&new_id).await.unwrap(), Some(session.clone()));
+
+    assert!(store.delete(&new_id).await.unwrap());
+    assert!(!store.delete(&new_id).await.unwrap());
+    assert_eq!(store.load(&new_id).await.unwrap(), None);
+    assert!(!store.save(&new_id, &session).await.unwrap());
+    assert_eq!(store.cycle_id(&new_id).await.unwrap(), None);
+
+    store.save_or_create(&new_id, &session).await.unwrap();
+    assert_eq!(store.load(&new_id).await.unwrap(), Some(session));
+}
+
+async fn on_inactivity<S>((mut store, clock): (S, ManualClock))
+where
+    S: SessionStore<Record>,
+    S::Error: Debug,
+{
+    let expiry = Expiry::OnInactivity(Duration::minutes(10));
+    let id = store.create(&record(expiry)).await.unwrap();
+
+    clock.advance(Duration::minutes(9));
+    assert!(store.save(&id, &record(expiry)).await.unwrap());
+    clock.advance(Duration::minutes(9));
+    assert!(store.touch(&id).await.unwrap());
+    clock.advance(Duration::minutes(9));
+    // Loading the session is not an activity.
+    assert!(store.load(&id).await.unwrap().is_some());
+    clock.advance(Duration::minutes(1));
+    assert!(store.load(&id).await.unwrap().is_none());
+}
+
+async fn at_date_time<S>((mut store, clock): (S, ManualClock))
+where
+    S: SessionStore<Record>,
+    S::Error: Debug,
+{
+    let expiry = Expiry::AtDateTime(START + Duration::minutes(10));
+    let id = store.create(&record(expiry)).await.unwrap();
+
+    clock.advance(Duration::minutes(5));
+    assert!(store.touch(&id).await.unwrap());
+    clock.advance(Duration::minutes(5));
+    assert!(store.load(&id).await.unwrap().is_none());
+}
+
+async fn on_inactivity_until<S>((mut store, clock): (S, ManualClock))
+where
+    S: SessionStore<Record>,
+    S::Error: Debug,
+{
+    let expiry = Expiry::OnInactivityUntil(Duration::minutes(10), START + Duration::minutes(25));
+    let id = store.create(&record(expiry)).await.unwrap();
+
+    for _ in 0..3 {
+        clock.advance(Duration::minutes(8));
+        assert!(store.touch(&id).await.unwrap());
+    }
+    assert!(store.load(&id).await.unwrap().is_some());
+    clock.advance(Duration::minutes(1));
+    assert!(store.load(&id).await.unwrap().is_none());
+}
+
+async fn cycled_sessions_are_refreshed<S>((mut store, clock): (S, ManualClock))
+where
+    S: SessionStore<Record>,
+    S::Error: Debug,
+{
+    let session = record(Expiry::OnInactivity(Duration::minutes(10)));
+    let id = store.create(&session).await.unwrap();
+
+    clock.advance(Duration::minutes(5));
+    let id = store.cycle_id(&id).await.unwrap().unwrap();
+    clock.advance(Duration::minutes(9));
+    assert_eq!(store.load(&id).await.unwrap(), Some(session));
+    clock.advance(Duration::minutes(1));
+    assert_eq!(store.load(&id).await.unwrap(), None);
+}
+
+async fn expired_sessions_are_invisible<S>((mut store, clock): (S, ManualClock), expiry: Expiry)
+where
+    S: SessionStore<Record>,
+    S::Error: Debug,
+{
+    let id = store.create(&record(expiry)).await.unwrap();
+    clock.advance(Duration::minutes(10));
+
+    assert_eq!(store.load(&id).await.unwrap(), None, "{expiry:?}");
+    assert!(
+        !store.save(&id, &record(expiry)).await.unwrap(),
+        "{expiry:?}"
+    );
+    assert!(!store.touch(&id).await.unwrap(), "{expiry:?}");
+    assert_eq!(store.cycle_id(&id).await.unwrap(), None, "{expiry:?}");
+    assert!(!store.delete(&id).await.unwrap(), "{expiry:?}");
+}
+
+async fn expired_records_are_not_stored<S>((mut store, _): (S, ManualClock))
+where
+    S: SessionStore<Record>,
+    S::Error: Debug,
+{
+    let past = record(Expiry::AtDateTime(START - Duration::minutes(1)));
+
+    let id = store.create(&past).await.unwrap();
+    assert_eq!(store.load(&id).await.unwrap(), None);
+
+    // Saving an expired record ends the session.
+    let id = store.create(&record(Expiry::OnSessionEnd)).await.unwrap();
+    assert!(store.save(&id, &past).await.unwrap());
+    assert!(!store.save(&id, &past).await.unwrap());
+    assert_eq!(store.load(&id).await.unwrap(), None);
+
+    store.save_or_create(&id, &past).await.unwrap();
+    assert_eq!(store.load(&id).await.unwrap(), None);
+}
+
+async fn expiry_dates<S>((mut store, clock): (S, ManualClock))
+where
+    S: SessionStore<Record>,
+    S::Error: Debug,
+{
+    let idle = record(Expiry::OnInactivity(Duration::minutes(10)));
+    let id = store.create(&idle).await.unwrap();
+    clock.advance(Duration::minutes(5));
+    assert_eq!(
+        store.load_with_expiry_date(&id).await.unwrap(),
+        Some((idle, ExpiryDate::At(START + Duration::minutes(10))))
+    );
+    assert!(store.touch(&id).await.unwrap());
+    assert_eq!(
+        store
+            .load_with_expiry_date(&id)
+            .await
+            .unwrap()
+            .map(|(_, expiry_date)| expiry_date),
+        Some(ExpiryDate::At(START + Duration::minutes(15)))
+    );
+
+    let forever = record(Expiry::OnSessionEnd);
+    let id = store.create(&forever).await.unwrap();
+    assert_eq!(
+        store.load_with_expiry_date(&id).await.unwrap(),
+        Some((forever, ExpiryDate::Never))
+    );
+    assert_eq!(store.load_with_expiry_date(&Id(0)).await.unwrap(), None);
+}